@@ -0,0 +1,314 @@
+//! Reading, counting and rewriting the `categories.csv` file produced by
+//! the `categorize` run.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+use anyhow::Result;
+use csv::StringRecord;
+use itertools::Itertools;
+use load_data::split_incomplete_line;
+
+/// Column holding the category. The domain is always column 0.
+const CATEGORY: usize = 1;
+/// Column holding the address family (`ipv4`, `ipv6`, `dual-stack`). Older runs didn't write it.
+const ADDRESS_FAMILY: usize = 2;
+/// The optional columns that say what they are, as `lang=de`. Any of them
+/// can be missing, so they're found by label rather than by position.
+const LABELS: &[&str] = &["lang=", "image=", "favicon=", "from=", "dup=", "agreement="];
+
+/// One `domain,category,...` row. Any extra columns are kept as-is so that
+/// rewriting the file doesn't lose them.
+pub struct Row {
+    record: StringRecord,
+}
+
+impl Row {
+    pub fn domain(&self) -> &str {
+        self.record.get(0).unwrap_or_default().trim()
+    }
+
+    pub fn category(&self) -> &str {
+        self.record.get(CATEGORY).unwrap_or_default().trim()
+    }
+
+    pub fn address_family(&self) -> &str {
+        self.record.get(ADDRESS_FAMILY).map(str::trim).unwrap_or("unknown")
+    }
+
+    /// The `keywords` column from `--store-keywords`, if the row has one
+    /// (`domain,category,family[,status,fetch_ms][,labeled..][,keywords]`).
+    /// It's always last, and apart from `--record-fetch`'s pair of numbers
+    /// straight after the address family, the only column without a label.
+    pub fn keywords(&self) -> Option<&str> {
+        let fetch = |i| self.record.get(i).is_some_and(|field: &str| field.trim().bytes().all(|b| b.is_ascii_digit()));
+        let skip = if fetch(ADDRESS_FAMILY + 1) && fetch(ADDRESS_FAMILY + 2) { 2 } else { 0 };
+        let unlabeled: Vec<&str> = self.optional().filter(|field| !LABELS.iter().any(|label| field.starts_with(label))).collect();
+        match unlabeled.len().checked_sub(skip) {
+            Some(1) => unlabeled.last().copied(),
+            _ => None,
+        }
+    }
+
+    /// The `lang=` column from `--store-language`, if the row has one. An
+    /// empty one (the page didn't say) is `unknown`.
+    pub fn language(&self) -> Option<&str> {
+        let language = self.labeled("lang=")?;
+        Some(if language.is_empty() { "unknown" } else { language })
+    }
+
+    /// The columns after the address family.
+    fn optional(&self) -> impl Iterator<Item = &str> {
+        self.record.iter().skip(ADDRESS_FAMILY + 1)
+    }
+
+    /// The value of the column labeled `label`, wherever it is in the row.
+    fn labeled(&self, label: &str) -> Option<&str> {
+        self.optional().find_map(|field| field.strip_prefix(label))
+    }
+
+    pub fn set_category(&mut self, category: &str) {
+        self.record = self.record
+            .iter()
+            .enumerate()
+            .map(|(i, field)| if i == CATEGORY { category } else { field })
+            .collect();
+    }
+}
+
+/// Read categorized rows from any reader. Rows without a category are skipped.
+pub fn parse_categories(reader: impl std::io::Read) -> Result<Vec<Row>> {
+    Ok(stream_rows(reader).collect())
+}
+
+/// The rows in `reader`, one at a time, so a file doesn't have to fit in memory.
+fn stream_rows(reader: impl std::io::Read) -> impl Iterator<Item = Row> {
+    csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true) // Older runs wrote raw LLM output, which may have extra commas
+        .from_reader(reader)
+        .into_records()
+        .flatten() // Keep only Ok records
+        .filter(|r| r.len() > CATEGORY) // Need at least a domain and a category
+        .map(|record| Row { record })
+}
+
+/// Load `categories.csv` (or another file in the same format). A last line
+/// without a newline is a row cut short by a crash, so it's skipped with a warning.
+pub fn read_categories(path: &Path) -> Result<Vec<Row>> {
+    let text = std::fs::read_to_string(path)?;
+    let (complete, incomplete) = split_incomplete_line(&text);
+    if let Some(line) = incomplete {
+        tracing::warn!("Skipping the incomplete last line of {}: {line:?}", path.display());
+    }
+    parse_categories(complete.as_bytes())
+}
+
+/// Like [`count_categories`] on [`read_categories`], but reading the file a
+/// row at a time: only the counts are kept, so it works on files too big to
+/// load. An incomplete last line is skipped in the same way.
+pub fn stream_category_counts(path: &Path) -> Result<Vec<(String, usize)>> {
+    let mut file = File::open(path)?;
+    let complete = complete_len(&mut file)?;
+    if complete < file.metadata()?.len() {
+        tracing::warn!("Skipping the incomplete last line of {}", path.display());
+    }
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for row in stream_rows(BufReader::new(file.take(complete))) {
+        match counts.get_mut(row.category()) {
+            Some(count) => *count += 1,
+            None => {
+                counts.insert(row.category().to_string(), 1);
+            }
+        }
+    }
+    Ok(counts
+        .into_iter()
+        .sorted_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0))) // Same order as count_by
+        .collect())
+}
+
+/// How many bytes of `file` are complete lines: up to and including its last
+/// newline. Reads backwards from the end, and leaves `file` at the start.
+fn complete_len(file: &mut File) -> Result<u64> {
+    const CHUNK: u64 = 64 * 1024;
+    let mut end = file.metadata()?.len();
+    let mut buffer = vec![0; CHUNK as usize];
+    let complete = loop {
+        if end == 0 {
+            break 0;
+        }
+        let start = end.saturating_sub(CHUNK);
+        let chunk = &mut buffer[..(end - start) as usize];
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(chunk)?;
+        if let Some(i) = chunk.iter().rposition(|&b| b == b'\n') {
+            break start + i as u64 + 1;
+        }
+        end = start;
+    };
+    file.rewind()?;
+    Ok(complete)
+}
+
+/// Rewrite a categories file. The rows are written to a temporary file first,
+/// and then renamed over the original - so a crash can't leave half a file.
+pub fn write_categories(path: &Path, rows: &[Row]) -> Result<()> {
+    let tmp = path.with_extension("csv.tmp");
+    let mut writer = csv::WriterBuilder::new()
+        .flexible(true)
+        .from_path(&tmp)?;
+    for row in rows {
+        writer.write_record(&row.record)?;
+    }
+    writer.flush()?;
+    std::fs::rename(tmp, path)?;
+    Ok(())
+}
+
+/// Count how many times each key occurs, most common first (ties broken by key).
+pub fn count_by<K: Ord + Clone>(keys: impl IntoIterator<Item = K>) -> Vec<(K, usize)> {
+    keys.into_iter()
+        .sorted() // Sort so that dedup can count
+        .dedup_with_count() // (count, key)
+        .map(|(count, key)| (key, count))
+        .sorted_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0))) // Count descending, then by key
+        .collect()
+}
+
+/// Count the `(group, key)` pairs, keeping the `top` most common keys in each
+/// group, as `([group, key], count)`. Groups are in alphabetical order.
+pub fn top_per_group(pairs: impl IntoIterator<Item = (String, String)>, top: usize) -> Vec<(Vec<String>, usize)> {
+    count_by(pairs)
+        .into_iter()
+        .into_group_map_by(|((group, _), _)| group.clone())
+        .into_iter()
+        .sorted_by(|a, b| a.0.cmp(&b.0))
+        .flat_map(|(_, counts)| counts.into_iter().take(top))
+        .map(|((group, key), count)| (vec![group, key], count))
+        .collect()
+}
+
+/// Count the domains in each category, most popular first.
+pub fn count_categories(rows: &[Row]) -> Vec<(String, usize)> {
+    count_by(rows.iter().map(|r| r.category().to_string()))
+}
+
+/// Count the domains in each (category, address family) pair.
+pub fn count_address_families(rows: &[Row]) -> Vec<(Vec<String>, usize)> {
+    count_by(rows.iter().map(|r| vec![r.category().to_string(), r.address_family().to_string()]))
+}
+
+/// Count the domains in each (category, language) pair. Rows written without
+/// `--store-language` are left out.
+pub fn count_languages(rows: &[Row]) -> Vec<(Vec<String>, usize)> {
+    count_by(rows.iter().filter_map(|r| Some(vec![r.category().to_string(), r.language()?.to_string()])))
+}
+
+/// Write grouped counts as CSV: one column per key field, followed by `count`.
+pub fn write_counts<K: AsRef<[String]>>(path: &Path, columns: &[&str], counts: &[(K, usize)]) -> Result<()> {
+    let mut writer = csv::Writer::from_path(path)?;
+    writer.write_record(columns.iter().chain(&["count"]))?;
+    for (key, count) in counts {
+        let count = count.to_string();
+        writer.write_record(key.as_ref().iter().map(String::as_str).chain([count.as_str()]))?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    pub(crate) fn rows(csv: &str) -> Vec<Row> {
+        parse_categories(csv.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn test_count_categories() {
+        let rows = rows("a.com,News\nb.com,Gaming\nc.com,News\nd.com\n");
+        assert_eq!(
+            count_categories(&rows),
+            vec![("News".to_string(), 2), ("Gaming".to_string(), 1)]
+        );
+    }
+
+    #[test]
+    fn test_truncated_last_line_is_skipped() {
+        let path = std::env::temp_dir().join(format!("analyze-truncated-{}.csv", std::process::id()));
+        std::fs::write(&path, "a.com,News,ipv4\nb.com,Gaming,ipv4\nc.com,Ret").unwrap();
+        let rows = read_categories(&path).unwrap();
+        assert_eq!(rows.iter().map(Row::domain).collect::<Vec<_>>(), vec!["a.com", "b.com"]);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_streamed_counts_match() {
+        let path = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/categories.csv"));
+        let counts = stream_category_counts(path).unwrap();
+        assert_eq!(counts, count_categories(&read_categories(path).unwrap()));
+        assert_eq!(counts[0], ("News".to_string(), 3));
+        assert_eq!(counts.iter().map(|(_, count)| count).sum::<usize>(), 7);
+    }
+
+    #[test]
+    fn test_count_address_families() {
+        let rows = rows("a.com,News,ipv4\nb.com,News,dual-stack\nc.com,News,ipv4\nd.com,Gaming\n");
+        let key = |c: &str, f: &str| vec![c.to_string(), f.to_string()];
+        assert_eq!(
+            count_address_families(&rows),
+            vec![(key("News", "ipv4"), 2), (key("Gaming", "unknown"), 1), (key("News", "dual-stack"), 1)]
+        );
+    }
+
+    #[test]
+    fn test_count_languages() {
+        let rows = rows(concat!(
+            "a.de,News,ipv4,lang=de\n",
+            "b.de,News,ipv4,200,35,lang=de,nachrichten wetter\n",
+            "c.com,News,ipv4,lang=en\n",
+            "d.jp,Gaming,ipv4,lang=\n",
+            "old.com,Gaming,ipv4\n",
+        ));
+        let key = |c: &str, l: &str| vec![c.to_string(), l.to_string()];
+        assert_eq!(
+            count_languages(&rows),
+            vec![(key("News", "de"), 2), (key("Gaming", "unknown"), 1), (key("News", "en"), 1)]
+        );
+        // The keywords are still found after the language
+        assert_eq!(rows[1].keywords(), Some("nachrichten wetter"));
+        assert_eq!(rows[0].keywords(), None);
+    }
+
+    #[test]
+    fn test_name_only_rows_are_marked() {
+        let rows = rows("cloudbank.io,Banking/Finance,unknown,from=name,\nb.com,News,ipv4,lang=en,from=name,news\n");
+        assert!(rows.iter().all(|row| row.labeled("from=") == Some("name")));
+        assert_eq!(rows[0].keywords(), Some(""));
+        assert_eq!(rows[1].keywords(), Some("news"));
+    }
+
+    #[test]
+    fn test_labeled_columns_are_found_wherever_they_are() {
+        let rows = rows(concat!(
+            // Fetch columns, then labels that aren't the language
+            "a.com,News,ipv4,200,35,dup=b.com,agreement=2/3,news weather\n",
+            "b.com,News,ipv4,200,35,lang=de,agreement=2/3\n",
+            // No fetch columns, and the images before the provenance
+            "c.com,Gaming,ipv6,image=https://c.com/og.png,favicon=,from=name,games\n",
+            "d.com,Gaming,ipv6,\"image=https://d.com/og.png?w=1,h=1\",favicon=,dup=c.com\n",
+            "e.com,News,ipv4,dup=a.com,news\n",
+            "f.com,\"Arts, Culture\",ipv4,lang=fr,culture\n",
+        ));
+        let keywords: Vec<_> = rows.iter().map(Row::keywords).collect();
+        assert_eq!(keywords, vec![Some("news weather"), None, Some("games"), None, Some("news"), Some("culture")]);
+        let languages: Vec<_> = rows.iter().map(Row::language).collect();
+        assert_eq!(languages, vec![None, Some("de"), None, None, None, Some("fr")]);
+        assert_eq!(rows[3].labeled("dup="), Some("c.com"));
+        // A quoted category is one column
+        assert_eq!(rows[5].category(), "Arts, Culture");
+        assert_eq!(rows[5].address_family(), "ipv4");
+    }
+}
@@ -0,0 +1,126 @@
+//! Why domains failed, from the `failures.txt` file written by `categorize`.
+
+use std::path::Path;
+use anyhow::Result;
+use itertools::Itertools;
+use crate::categories::count_by;
+
+/// Suffixes under which domains are registered a level further down, so
+/// `shop.example.co.uk` groups as `example.co.uk` rather than `co.uk`. Not
+/// the whole public suffix list, just the ones common enough to matter.
+const MULTI_LABEL_SUFFIXES: &[&str] = &[
+    "co.uk", "org.uk", "ac.uk", "gov.uk", "me.uk", "ltd.uk", "plc.uk",
+    "com.au", "net.au", "org.au", "edu.au", "gov.au",
+    "co.nz", "org.nz", "co.jp", "ne.jp", "or.jp", "co.kr", "or.kr",
+    "com.br", "net.br", "org.br", "com.cn", "net.cn", "org.cn",
+    "com.mx", "com.ar", "com.tr", "com.tw", "com.hk", "com.sg", "com.my",
+    "co.in", "net.in", "org.in", "co.za", "org.za", "co.il", "com.ua",
+];
+
+/// Read a failures file (`domain,reason`, with a header) as `(domain, reason)`.
+/// Files from before reasons were recorded only have the domain; those count
+/// as `unknown`.
+pub fn parse_failures(reader: impl std::io::Read) -> Result<Vec<(String, String)>> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(reader);
+    let failures = reader
+        .records()
+        .flatten() // Keep only Ok records
+        .filter(|r| r.get(0) != Some("domain")) // Skip the header
+        .map(|r| (
+            r.get(0).unwrap_or_default().trim().to_string(),
+            r.get(1).map(str::trim).unwrap_or("unknown").to_string(),
+        ))
+        .collect();
+    Ok(failures)
+}
+
+/// Just the reason column of a failures file.
+pub fn parse_failure_reasons(reader: impl std::io::Read) -> Result<Vec<String>> {
+    Ok(parse_failures(reader)?.into_iter().map(|(_, reason)| reason).collect())
+}
+
+/// The registrable domain (eTLD+1): the effective TLD plus the label before
+/// it. `example.com` for `www.example.com`, `example.co.uk` for
+/// `shop.example.co.uk`. A bare suffix is returned as it is.
+pub fn etld_plus_one(domain: &str) -> String {
+    let domain = domain.trim_end_matches('.').to_lowercase();
+    let labels: Vec<&str> = domain.rsplit('.').take(3).collect();
+    let suffix = match labels.as_slice() {
+        [tld, second, ..] if MULTI_LABEL_SUFFIXES.contains(&format!("{second}.{tld}").as_str()) => 2,
+        _ => 1,
+    };
+    match labels.get(..=suffix) {
+        Some(registrable) => registrable.iter().rev().join("."),
+        None => domain,
+    }
+}
+
+/// Count the failures for each reason, most common first.
+pub fn count_failure_reasons(path: &Path) -> Result<Vec<(String, usize)>> {
+    let reasons = parse_failure_reasons(std::fs::File::open(path)?)?;
+    Ok(count_by(reasons))
+}
+
+/// Count the failures for each eTLD+1 and reason, as `([etld, reason],
+/// count)`, most common first. A registrable domain that fails far more than
+/// the rest (many subdomains of one host, say) points at its infrastructure
+/// rather than the sites.
+pub fn count_failures_by_etld(path: &Path) -> Result<Vec<([String; 2], usize)>> {
+    let failures = parse_failures(std::fs::File::open(path)?)?;
+    Ok(count_by(failures.into_iter().map(|(domain, reason)| [etld_plus_one(&domain), reason])))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_failure_reason_counts() {
+        let csv = "domain,reason\na.com,nxdomain\nb.com,timeout\nc.com,nxdomain\nd.com,categorize\ne.com,nxdomain\nold.com\n";
+        let reasons = parse_failure_reasons(csv.as_bytes()).unwrap();
+        let key = |reason: &str, count| (reason.to_string(), count);
+        assert_eq!(
+            count_by(reasons),
+            vec![key("nxdomain", 3), key("categorize", 1), key("timeout", 1), key("unknown", 1)]
+        );
+    }
+
+    #[test]
+    fn test_failures_grouped_by_etld() {
+        let path = std::env::temp_dir().join(format!("failures-etld-test-{}.txt", std::process::id()));
+        let csv = concat!(
+            "domain,reason\n",
+            // Two subdomains of one registrable domain...
+            "a.hosting.com,nxdomain\n",
+            "b.hosting.com,nxdomain\n",
+            // ...but another .com is its own group
+            "other.com,nxdomain\n",
+            "shop.c.co.uk,timeout\n",
+            "www.C.co.uk,timeout\n",
+            "d.co.uk,timeout\n",
+        );
+        std::fs::write(&path, csv).unwrap();
+        let counts = count_failures_by_etld(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        let key = |etld: &str, reason: &str, count| ([etld.to_string(), reason.to_string()], count);
+        assert_eq!(counts, vec![
+            key("c.co.uk", "timeout", 2),
+            key("hosting.com", "nxdomain", 2),
+            key("d.co.uk", "timeout", 1),
+            key("other.com", "nxdomain", 1),
+        ]);
+    }
+
+    #[test]
+    fn test_etld_plus_one() {
+        assert_eq!(etld_plus_one("www.example.com"), "example.com");
+        assert_eq!(etld_plus_one("example.com."), "example.com");
+        assert_eq!(etld_plus_one("a.b.shop.example.co.uk"), "example.co.uk");
+        assert_eq!(etld_plus_one("localhost"), "localhost");
+        assert_eq!(etld_plus_one("co.uk"), "co.uk");
+    }
+}
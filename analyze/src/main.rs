@@ -0,0 +1,165 @@
+//! Analyzes the results of a `categorize` run.
+//!
+//! With no subcommand, counts the domains in each category and writes them to
+//! `category-counts.csv`.
+
+mod categories;
+mod failures;
+mod orgs;
+mod remap;
+mod terms;
+
+use std::path::{Path, PathBuf};
+use anyhow::Result;
+use clap::{Parser, Subcommand};
+use categories::{count_address_families, count_categories, count_languages, read_categories, stream_category_counts, write_categories, write_counts};
+use failures::{count_failure_reasons, count_failures_by_etld};
+use load_data::load_asn_names;
+use orgs::top_orgs;
+use remap::Remap;
+use terms::top_terms;
+
+/// Files bigger than this are counted a row at a time, rather than loaded.
+const STREAM_ABOVE_BYTES: u64 = 256 * 1024 * 1024;
+
+#[derive(Parser)]
+struct Cli {
+    /// The categorized domains, as written by `categorize`
+    #[arg(long, default_value = "categories.csv")]
+    input: PathBuf,
+
+    /// Where to write the per-category counts
+    #[arg(long, default_value = "category-counts.csv")]
+    counts: PathBuf,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Count domains per category (the default)
+    Count,
+    /// Rewrite the categories using an `old,new` remap file, then re-count
+    Remap {
+        remap_file: PathBuf,
+    },
+    /// Break each category down by address family (IPv4-only, IPv6-only, dual-stack)
+    Families {
+        #[arg(long, default_value = "address-families.csv")]
+        output: PathBuf,
+    },
+    /// Break each category down by page language. Needs a run with `--store-language`.
+    Languages {
+        #[arg(long, default_value = "category-languages.csv")]
+        output: PathBuf,
+    },
+    /// The organizations (ASN names) with the most domains in each category
+    Orgs {
+        #[arg(long, default_value = "top-orgs.csv")]
+        output: PathBuf,
+        /// How many organizations to keep per category
+        #[arg(long, default_value_t = 5)]
+        top: usize,
+    },
+    /// The most common keywords in each category. Needs a run with `--store-keywords`.
+    Terms {
+        #[arg(long, default_value = "top-terms.csv")]
+        output: PathBuf,
+        /// How many terms to keep per category
+        #[arg(long, default_value_t = 10)]
+        top: usize,
+    },
+    /// Count failed domains by reason (nxdomain, timeout, ...)
+    Failures {
+        /// The failures file written by `categorize`
+        #[arg(long, default_value = "failures.txt")]
+        failures: PathBuf,
+        #[arg(long, default_value = "failure-reasons.csv")]
+        output: PathBuf,
+    },
+    /// Count failed domains by eTLD+1 (registrable domain) and reason, to spot
+    /// failures that cluster on one host or registrar
+    FailuresByEtld {
+        /// The failures file written by `categorize`
+        #[arg(long, default_value = "failures.txt")]
+        failures: PathBuf,
+        #[arg(long, default_value = "failures-by-etld.csv")]
+        output: PathBuf,
+    },
+}
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+    // Warnings (like a cut-short last row) go to stderr, away from the counts
+    tracing_subscriber::fmt().with_writer(std::io::stderr).init();
+
+    // These don't need the categories file
+    if let Some(Command::Failures { failures, output }) = &cli.command {
+        let counts = count_failure_reasons(failures)?;
+        for (reason, count) in counts.iter() {
+            println!("{reason}: {count}");
+        }
+        let counts: Vec<_> = counts.into_iter().map(|(reason, count)| ([reason], count)).collect();
+        write_counts(output, &["reason"], &counts)?;
+        return Ok(());
+    }
+    if let Some(Command::FailuresByEtld { failures, output }) = &cli.command {
+        let counts = count_failures_by_etld(failures)?;
+        write_counts(output, &["etld", "reason"], &counts)?;
+        println!("Wrote {} eTLD/reason groups to {}", counts.len(), output.display());
+        return Ok(());
+    }
+
+    // Counting can be done a row at a time, which a big file needs
+    if matches!(cli.command, None | Some(Command::Count)) && std::fs::metadata(&cli.input)?.len() > STREAM_ABOVE_BYTES {
+        print_and_write_counts(&cli.counts, stream_category_counts(&cli.input)?)?;
+        return Ok(());
+    }
+
+    let mut rows = read_categories(&cli.input)?;
+
+    match &cli.command {
+        Some(Command::Remap { remap_file }) => {
+            let remap = Remap::load(remap_file)?;
+            let changed = remap.apply(&mut rows);
+            write_categories(&cli.input, &rows)?;
+            println!("Relabeled {changed} domains in {}", cli.input.display());
+        }
+        Some(Command::Families { output }) => {
+            let counts = count_address_families(&rows);
+            write_counts(output, &["category", "address_family"], &counts)?;
+            println!("Wrote {} category/address family groups to {}", counts.len(), output.display());
+            return Ok(());
+        }
+        Some(Command::Languages { output }) => {
+            let counts = count_languages(&rows);
+            write_counts(output, &["category", "language"], &counts)?;
+            println!("Wrote {} category/language groups to {}", counts.len(), output.display());
+            return Ok(());
+        }
+        Some(Command::Orgs { output, top }) => {
+            let counts = top_orgs(&rows, &load_asn_names()?, *top);
+            write_counts(output, &["category", "org"], &counts)?;
+            println!("Wrote {} top organizations to {}", counts.len(), output.display());
+            return Ok(());
+        }
+        Some(Command::Terms { output, top }) => {
+            let counts = top_terms(&rows, *top);
+            write_counts(output, &["category", "term"], &counts)?;
+            println!("Wrote {} top terms to {}", counts.len(), output.display());
+            return Ok(());
+        }
+        Some(Command::Count) | Some(Command::Failures { .. }) | Some(Command::FailuresByEtld { .. }) | None => {}
+    }
+
+    print_and_write_counts(&cli.counts, count_categories(&rows))
+}
+
+fn print_and_write_counts(path: &Path, counts: Vec<(String, usize)>) -> Result<()> {
+    for (category, count) in counts.iter() {
+        println!("{category}: {count}");
+    }
+    let counts: Vec<_> = counts.into_iter().map(|(category, count)| ([category], count)).collect();
+    write_counts(path, &["category"], &counts)
+}
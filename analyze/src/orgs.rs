@@ -0,0 +1,43 @@
+//! Which organizations (ASN names) dominate each category - e.g. whether
+//! "Cloud" is mostly AWS and Google.
+
+use std::collections::HashMap;
+use crate::categories::{top_per_group, Row};
+
+/// The `top` most common organizations in each category, as
+/// `([category, org], count)`. `names` maps domains to organizations; domains
+/// not in it count as `unknown`.
+pub fn top_orgs(rows: &[Row], names: &HashMap<String, String>, top: usize) -> Vec<(Vec<String>, usize)> {
+    let pairs = rows.iter().map(|row| {
+        let org = names.get(&row.domain().to_lowercase()).map(String::as_str).unwrap_or("unknown");
+        (row.category().to_string(), org.to_string())
+    });
+    top_per_group(pairs, top)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::categories::tests::rows;
+
+    #[test]
+    fn test_top_orgs_per_category() {
+        let names: HashMap<String, String> = [
+            ("aws.example", "Amazon.com, Inc."),
+            ("s3.example", "Amazon.com, Inc."),
+            ("gcp.example", "Google LLC"),
+            ("games.example", "Valve Corporation"),
+        ]
+        .into_iter()
+        .map(|(domain, org)| (domain.to_string(), org.to_string()))
+        .collect();
+        let rows = rows("aws.example,Cloud\nS3.example,Cloud\ngcp.example,Cloud\ngames.example,Gaming\nnew.example,Gaming\n");
+
+        let key = |c: &str, o: &str| vec![c.to_string(), o.to_string()];
+        assert_eq!(top_orgs(&rows, &names, 1), vec![
+            (key("Cloud", "Amazon.com, Inc."), 2),
+            (key("Gaming", "Valve Corporation"), 1),
+        ]);
+        assert_eq!(top_orgs(&rows, &names, 5).len(), 4);
+    }
+}
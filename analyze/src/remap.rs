@@ -0,0 +1,76 @@
+//! Merging categories after the fact. A remap file is a list of `old,new`
+//! pairs; every domain in category `old` is moved to `new`.
+
+use std::collections::HashMap;
+use std::path::Path;
+use anyhow::Result;
+use crate::categories::Row;
+
+/// Category remapping, keyed by the lowercase old category.
+pub struct Remap(HashMap<String, String>);
+
+impl Remap {
+    /// Parse `old,new` pairs. Blank lines and lines starting with `#` are ignored.
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut map = HashMap::new();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((old, new)) = line.split_once(',') else {
+                anyhow::bail!("Remap line {} should be `old,new`: {line}", n + 1);
+            };
+            map.insert(old.trim().to_lowercase(), new.trim().to_string());
+        }
+        Ok(Self(map))
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    /// The replacement for a category, if there is one. Matching ignores case,
+    /// since the LLM isn't consistent about it.
+    pub fn get(&self, category: &str) -> Option<&str> {
+        self.0.get(&category.trim().to_lowercase()).map(|s| s.as_str())
+    }
+
+    /// Relabel the rows in place, returning how many were changed.
+    pub fn apply(&self, rows: &mut [Row]) -> usize {
+        let mut changed = 0;
+        for row in rows.iter_mut() {
+            if let Some(new) = self.get(row.category()) {
+                let new = new.to_string();
+                row.set_category(&new);
+                changed += 1;
+            }
+        }
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::categories::count_categories;
+    use crate::categories::tests::rows;
+
+    #[test]
+    fn test_remap_relabels_and_recounts() {
+        let mut rows = rows("a.com,News\nb.com,Media/Entertainment\nc.com,news\nd.com,Gaming\n");
+        let remap = Remap::parse("# Merge news into media\nNews,Media/Entertainment\n").unwrap();
+
+        assert_eq!(remap.apply(&mut rows), 2);
+        assert_eq!(rows[0].category(), "Media/Entertainment");
+        assert_eq!(
+            count_categories(&rows),
+            vec![("Media/Entertainment".to_string(), 3), ("Gaming".to_string(), 1)]
+        );
+    }
+
+    #[test]
+    fn test_remap_rejects_bad_lines() {
+        assert!(Remap::parse("News Media\n").is_err());
+    }
+}
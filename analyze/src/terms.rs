@@ -0,0 +1,42 @@
+//! The keywords that come up most within each category, from the `keywords`
+//! column written by `categorize --store-keywords`.
+
+use crate::categories::{top_per_group, Row};
+
+/// The `top` most common terms in each category, as `([category, term], count)`.
+/// Categories are in alphabetical order, terms most common first. Rows without
+/// keywords are ignored.
+pub fn top_terms(rows: &[Row], top: usize) -> Vec<(Vec<String>, usize)> {
+    let terms = rows.iter().flat_map(|row| {
+        let category = row.category();
+        row.keywords()
+            .unwrap_or_default()
+            .split(|c: char| c.is_whitespace() || c == ',')
+            .filter(|term| !term.is_empty())
+            .map(move |term| (category.to_string(), term.to_lowercase()))
+    });
+    top_per_group(terms, top)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::categories::tests::rows;
+
+    #[test]
+    fn test_top_terms_per_category() {
+        let rows = rows(concat!(
+            "bakery.com,Food/Beverage,ipv4,bread cakes bread\n",
+            "cafe.com,Food/Beverage,ipv4,\"coffee, cakes bread\"\n",
+            "steam.com,Gaming,ipv4,200,35,games Store games\n",
+            "old.com,Gaming,ipv4,200,35\n",
+        ));
+        let key = |c: &str, t: &str| vec![c.to_string(), t.to_string()];
+        assert_eq!(top_terms(&rows, 2), vec![
+            (key("Food/Beverage", "bread"), 3),
+            (key("Food/Beverage", "cakes"), 2),
+            (key("Gaming", "games"), 2),
+            (key("Gaming", "store"), 1),
+        ]);
+    }
+}
@@ -0,0 +1,74 @@
+//! How long keyword extraction takes, to catch changes to the selectors,
+//! tokenizing or ranking that make it slower. Run with
+//! `cargo bench -p categorize`, optionally with a name to run just the
+//! benches containing it (`cargo bench -p categorize -- large`).
+//!
+//! A plain timing loop, so it needs no extra dependencies: each bench runs a
+//! few times and the median and fastest times are printed.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+use categorize::scraping::{extract_keywords, website_text, ScrapeConfig};
+
+const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures");
+
+/// Run `f` `runs` times (after one to warm up) and print how long it took.
+fn bench(filter: Option<&str>, name: &str, runs: usize, mut f: impl FnMut()) {
+    if filter.is_some_and(|filter| !name.contains(filter)) {
+        return;
+    }
+    f();
+    let mut times: Vec<Duration> = (0..runs)
+        .map(|_| {
+            let start = Instant::now();
+            f();
+            start.elapsed()
+        })
+        .collect();
+    times.sort();
+    println!("{name:<28} median {:>10.2?}   fastest {:>10.2?}   ({runs} runs)", times[runs / 2], times[0]);
+}
+
+/// A page of about `size` bytes: the chrome fixture's header, sidebar and
+/// footer around its article repeated with different words, like a long
+/// listing or archive page.
+fn large_page(size: usize) -> String {
+    let page = std::fs::read_to_string(format!("{FIXTURES}/chrome.example.html")).unwrap();
+    let (head, rest) = page.split_once("<article>").unwrap();
+    let (article, tail) = rest.split_once("</article>").unwrap();
+    let mut html = head.to_string();
+    let mut i = 0;
+    while html.len() < size {
+        html.push_str(&format!("<article>{article}<p>Batch{} recipe{} loaf{}</p></article>\n", i % 97, i % 389, i));
+        i += 1;
+    }
+    html.push_str(tail);
+    html
+}
+
+fn main() {
+    let filter = std::env::args().skip(1).find(|arg| !arg.starts_with('-'));
+    let filter = filter.as_deref();
+    let config = ScrapeConfig::default();
+
+    let small = std::fs::read_to_string(format!("{FIXTURES}/chrome.example.html")).unwrap();
+    bench(filter, "extract_keywords/small", 200, || {
+        black_box(extract_keywords(black_box(&small), &config));
+    });
+
+    let large = large_page(4 * 1024 * 1024);
+    bench(filter, "extract_keywords/large", 5, || {
+        black_box(extract_keywords(black_box(&large), &config));
+    });
+
+    // The whole of website_text, from a fixture instead of the network
+    let dir = std::env::temp_dir().join(format!("extraction-bench-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("large.example.html"), &large).unwrap();
+    let fixtures = ScrapeConfig::builder().fixtures(&dir).build().unwrap();
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+    bench(filter, "website_text/large", 5, || {
+        black_box(runtime.block_on(website_text("large.example", &fixtures)).unwrap());
+    });
+    std::fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,191 @@
+//! Categorizing a whole organization (an ASN) at once, from a sample of its
+//! domains, instead of one domain at a time.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use anyhow::Result;
+use futures::future::join_all;
+use itertools::Itertools;
+use tokio::sync::mpsc::Sender;
+use tokio::task::JoinHandle;
+use load_data::Asn;
+use crate::llm::{Categorizer, Completion};
+use crate::sample_order;
+use crate::scraping::{website_text, DnsCache, ScrapeConfig};
+use crate::success_fail::{append_to_file, csv_field};
+
+/// The category for an ASN.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AsnCategory {
+    pub asn: String,
+    pub name: String,
+    pub category: String,
+    /// The domains whose pages it was chosen from
+    pub domains: Vec<String>,
+}
+
+/// Merge several domains' keywords, ranking words by how many of the domains
+/// use them (then by how high they ranked), so one site's favourite words
+/// don't crowd out what they have in common.
+fn combine_keywords<'a>(keywords: impl IntoIterator<Item = &'a str>, max_words: usize) -> String {
+    let mut words: HashMap<&str, (usize, usize)> = HashMap::new();
+    for list in keywords {
+        for (position, word) in list.split_whitespace().unique().enumerate() {
+            let (count, best) = words.entry(word).or_insert((0, position));
+            *count += 1;
+            *best = (*best).min(position);
+        }
+    }
+    words
+        .into_iter()
+        .sorted_by(|a, b| b.1.0.cmp(&a.1.0).then(a.1.1.cmp(&b.1.1)).then(a.0.cmp(b.0)))
+        .map(|(word, _)| word)
+        .take(max_words)
+        .join(" ")
+}
+
+/// Scrape `sample` of the ASN's domains, picked at random with `seed`, and
+/// ask the LLM for one category for the organization, from all of their
+/// keywords together. Domains that don't resolve, fail or are parked are left
+/// out; if that's all of them, it's an error.
+pub async fn categorize_asn<L: Completion>(
+    asn: &Asn,
+    sample: usize,
+    seed: u64,
+    dns: &DnsCache,
+    scrape: &ScrapeConfig,
+    categorizer: &Categorizer<L>,
+) -> Result<AsnCategory> {
+    // In name order, so the prompt doesn't depend on how they were picked
+    let picked = sample_order(asn.domains.iter().cloned(), sample, seed, None).into_iter().sorted().collect_vec();
+    let pages = picked.iter().map(|domain| async move {
+        if dns.resolve(domain).await.is_empty() {
+            return None;
+        }
+        match website_text(domain, scrape).await {
+            // A parking page says nothing about who owns the domain
+            Ok(page) if page.parked.is_none() => Some((domain.clone(), page.keywords)),
+            Ok(_) => None,
+            Err(e) => {
+                tracing::debug!(domain, asn = %asn.asn, "Skipping: {e}");
+                None
+            }
+        }
+    });
+    let pages: Vec<(String, String)> = join_all(pages).await.into_iter().flatten().collect();
+    anyhow::ensure!(!pages.is_empty(), "None of the domains for {} could be scraped", asn.asn);
+
+    let keywords = combine_keywords(pages.iter().map(|(_, keywords)| keywords.as_str()), scrape.max_words);
+    let domains: Vec<String> = pages.into_iter().map(|(domain, _)| domain).collect();
+    let subject = format!("{} ({}), which runs {}", asn.name, asn.asn, domains.join(", "));
+    let result = categorizer.categorize_domain(&subject, &keywords).await?;
+    Ok(AsnCategory { asn: asn.asn.clone(), name: asn.name.clone(), category: result.category, domains })
+}
+
+/// Write `asn,name,category` rows to `filename` as they're sent, so a run
+/// doesn't hold them all until the end. The file is replaced, starting with
+/// a header.
+pub async fn asn_categories(filename: PathBuf, capacity: usize) -> Result<(Sender<AsnCategory>, JoinHandle<()>)> {
+    tokio::fs::write(&filename, "asn,name,category\n").await?;
+    let (tx, mut rx) = tokio::sync::mpsc::channel::<AsnCategory>(capacity.max(1));
+    let writer = tokio::spawn(async move {
+        while let Some(result) = rx.recv().await {
+            let line = [&result.asn, &result.name, &result.category].map(|field| csv_field(field)).join(",");
+            if let Err(e) = append_to_file(&filename, &line).await {
+                tracing::error!("Failed to write to file: {}", e);
+            }
+        }
+    });
+    Ok((tx, writer))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use crate::test_support::MockLlm;
+
+    #[tokio::test]
+    async fn test_asn_gets_one_category_from_its_domains() {
+        let dns = DnsCache::default();
+        for domain in ["bakery.example", "games.example", "parked.example"] {
+            dns.insert(domain, vec!["192.0.2.1".parse().unwrap()]);
+        }
+        let scrape = ScrapeConfig::builder()
+            .fixtures(concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures"))
+            .build()
+            .unwrap();
+        let categorizer = Categorizer::new(MockLlm::new(["Retail"]));
+        let asn = Asn {
+            asn: "AS64500".to_string(),
+            name: "Example Holdings".to_string(),
+            domains: ["bakery.example", "games.example", "nothing-here.invalid", "parked.example"].map(String::from).to_vec(),
+        };
+
+        let result = categorize_asn(&asn, 10, 42, &dns, &scrape, &categorizer).await.unwrap();
+        assert_eq!(result.category, "Retail");
+        assert_eq!(result.domains, vec!["bakery.example", "games.example"]);
+
+        // One question for the whole organization, with both sites' words
+        let prompts = categorizer.llm.prompts.lock().unwrap().clone();
+        assert_eq!(prompts.len(), 1);
+        assert!(prompts[0].contains("Example Holdings (AS64500), which runs bakery.example, games.example"), "{}", prompts[0]);
+        assert!(prompts[0].contains("bread") && prompts[0].contains("games"), "{}", prompts[0]);
+    }
+
+    #[tokio::test]
+    async fn test_sample_is_picked_at_random() {
+        let domains: Vec<String> = (0..20).map(|i| format!("{i}.example")).collect();
+        let asn = Asn { asn: "AS64500".to_string(), name: "Example Holdings".to_string(), domains };
+        let categorizer = Categorizer::new(MockLlm::new(["Retail"]));
+        let scrape = ScrapeConfig::default();
+        // Nothing resolves; the lookups show which domains were sampled
+        let picked = |seed| {
+            let (asn, scrape, categorizer) = (&asn, &scrape, &categorizer);
+            async move {
+                let lookups = Arc::new(Mutex::new(Vec::new()));
+                let dns = DnsCache::with_resolver({
+                    let lookups = lookups.clone();
+                    move |domain| {
+                        lookups.lock().unwrap().push(domain);
+                        async { Vec::new() }
+                    }
+                });
+                categorize_asn(asn, 3, seed, &dns, scrape, categorizer).await.unwrap_err();
+                let lookups = lookups.lock().unwrap().iter().sorted().cloned().collect_vec();
+                lookups
+            }
+        };
+        let first = picked(1).await;
+        assert_eq!(first.len(), 3);
+        assert_eq!(first, picked(1).await);
+        assert_ne!(first, picked(2).await);
+        // Not just the first few
+        assert_ne!(first, ["0.example", "1.example", "2.example"]);
+    }
+
+    #[tokio::test]
+    async fn test_rows_are_written_as_they_arrive() {
+        let path = std::env::temp_dir().join(format!("asn-categories-test-{}.csv", std::process::id()));
+        let (tx, writer) = asn_categories(path.clone(), 4).await.unwrap();
+        let result = |asn: &str, name: &str| AsnCategory {
+            asn: asn.to_string(),
+            name: name.to_string(),
+            category: "Retail".to_string(),
+            domains: Vec::new(),
+        };
+        tx.send(result("AS64500", "Example Holdings, Inc.")).await.unwrap();
+        tx.send(result("AS64501", "Other Ltd")).await.unwrap();
+        crate::success_fail::close(tx, writer).await;
+
+        let written = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(written, "asn,name,category\nAS64500,\"Example Holdings, Inc.\",Retail\nAS64501,Other Ltd,Retail\n");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_shared_words_rank_first() {
+        let combined = combine_keywords(["bread cakes hosting", "hosting servers", "hosting cloud bread"], 3);
+        assert_eq!(combined, "hosting bread cakes");
+    }
+}
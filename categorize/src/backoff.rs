@@ -0,0 +1,80 @@
+//! Waiting between retries. The wait doubles with each retry, and jitter
+//! makes it random, so that when the LLM (or a site) comes back after an
+//! outage, everything that failed meanwhile doesn't retry at the same moment.
+
+use std::time::Duration;
+use rand::Rng;
+
+/// How much of the wait is random.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Jitter {
+    /// Exactly the backoff, every time
+    None,
+    /// Anywhere from nothing to the backoff: the most spread out
+    #[default]
+    Full,
+    /// At least half the backoff, plus a random amount up to the other half
+    Equal,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Backoff {
+    /// The backoff before the first retry
+    pub base: Duration,
+    /// The most it can get to, however many retries
+    pub max: Duration,
+    pub jitter: Jitter,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self { base: Duration::from_millis(500), max: Duration::from_secs(30), jitter: Jitter::Full }
+    }
+}
+
+impl Backoff {
+    /// How long to wait before retry number `retry` (counting from 1).
+    pub fn delay(&self, retry: u32, rng: &mut impl Rng) -> Duration {
+        let doublings = retry.saturating_sub(1).min(31);
+        let backoff = self.base.saturating_mul(1 << doublings).min(self.max);
+        match self.jitter {
+            Jitter::None => backoff,
+            Jitter::Full => backoff.mul_f64(rng.gen_range(0.0..=1.0)),
+            Jitter::Equal => backoff / 2 + (backoff / 2).mul_f64(rng.gen_range(0.0..=1.0)),
+        }
+    }
+
+    pub async fn wait(&self, retry: u32) {
+        let delay = self.delay(retry, &mut rand::thread_rng());
+        tokio::time::sleep(delay).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_jitter_stays_in_bounds() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(7);
+        let backoff = |jitter| Backoff { base: Duration::from_millis(100), max: Duration::from_secs(1), jitter };
+        let millis = |jitter, retry, rng: &mut rand::rngs::StdRng| -> Vec<u128> {
+            (0..50).map(|_| backoff(jitter).delay(retry, rng).as_millis()).collect()
+        };
+
+        // The third retry backs off 400ms
+        assert_eq!(millis(Jitter::None, 3, &mut rng), vec![400; 50]);
+        let full = millis(Jitter::Full, 3, &mut rng);
+        assert!(full.iter().all(|&ms| ms <= 400), "{full:?}");
+        let equal = millis(Jitter::Equal, 3, &mut rng);
+        assert!(equal.iter().all(|&ms| (200..=400).contains(&ms)), "{equal:?}");
+        // Spread out, not all at once
+        for delays in [&full, &equal] {
+            assert!(delays.iter().any(|&ms| ms != delays[0]), "{delays:?}");
+        }
+
+        // It stops doubling at the maximum
+        assert!(millis(Jitter::Equal, 20, &mut rng).iter().all(|&ms| (500..=1000).contains(&ms)));
+    }
+}
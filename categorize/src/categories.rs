@@ -0,0 +1,314 @@
+//! The categories the LLM is allowed to choose from.
+
+use std::collections::HashMap;
+use std::path::Path;
+use anyhow::Result;
+use itertools::Itertools;
+use serde::Deserialize;
+
+/// Used when no category file is given.
+const DEFAULT_CATEGORIES: &[&str] = &[
+    "Adult", "Agriculture", "Automotive", "Banking/Finance", "Cloud", "Construction",
+    "Education", "Energy", "Food/Beverage", "Gaming", "Government", "Healthcare",
+    "Hosting", "Insurance", "ISP", "Legal", "Logistics", "Manufacturing",
+    "Media/Entertainment", "News", "Non-Profit", "Real Estate", "Religion", "Retail",
+    "Security", "Social Media", "Sports", "Technology", "Telecommunications", "Travel",
+    "Utilities", "Other",
+];
+
+pub struct Category {
+    pub keyword: String,
+    /// A short definition, to help the LLM pick the right one
+    pub description: Option<String>,
+    /// How to tell it apart from categories it gets confused with
+    pub hint: Option<String>,
+}
+
+pub struct Categories(Vec<Category>);
+
+impl Default for Categories {
+    fn default() -> Self {
+        Self(DEFAULT_CATEGORIES
+            .iter()
+            .map(|keyword| Category { keyword: keyword.to_string(), description: None, hint: None })
+            .collect())
+    }
+}
+
+impl Categories {
+    /// Parse a category file: one category per line, either `Keyword` or
+    /// `Keyword: description`. Blank lines are ignored.
+    pub fn parse(text: &str) -> Self {
+        Self(text
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(|line| match line.split_once(':') {
+                Some((keyword, description)) => Category {
+                    keyword: keyword.trim().to_string(),
+                    description: Some(description.trim().to_string()),
+                    hint: None,
+                },
+                None => Category { keyword: line.to_string(), description: None, hint: None },
+            })
+            .collect())
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        Ok(Self::parse(&std::fs::read_to_string(path)?))
+    }
+
+    /// Add hints from a file of `Keyword: hint` lines. Hints for categories
+    /// that aren't in the list are skipped, since the LLM can't pick them anyway.
+    pub fn parse_hints(&mut self, text: &str) {
+        for line in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+            let Some((keyword, hint)) = line.split_once(':') else {
+                tracing::warn!("Ignoring hint without a category: {line:?}");
+                continue;
+            };
+            match self.0.iter_mut().find(|c| c.keyword.eq_ignore_ascii_case(keyword.trim())) {
+                Some(category) => category.hint = Some(hint.trim().to_string()),
+                None => tracing::warn!("Ignoring hint for {:?}, which isn't in the category list", keyword.trim()),
+            }
+        }
+    }
+
+    pub fn load_hints(&mut self, path: &Path) -> Result<()> {
+        self.parse_hints(&std::fs::read_to_string(path)?);
+        Ok(())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Category> {
+        self.0.iter()
+    }
+
+    /// Just the keywords, comma separated.
+    pub fn keywords(&self) -> String {
+        self.0.iter().map(|c| c.keyword.as_str()).collect::<Vec<_>>().join(", ")
+    }
+
+    /// The part of the prompt listing the allowed categories.
+    pub fn category_prompt(&self) -> String {
+        let mut prompt = if self.0.iter().all(|c| c.description.is_none()) {
+            format!("Choose exactly one of these categories: {}.", self.keywords())
+        } else {
+            let mut prompt = String::from("Choose exactly one of these categories:\n");
+            for category in self.0.iter() {
+                match &category.description {
+                    Some(description) => prompt.push_str(&format!("- {}: {}\n", category.keyword, description)),
+                    None => prompt.push_str(&format!("- {}\n", category.keyword)),
+                }
+            }
+            prompt
+        };
+        let hints = self.0.iter().filter_map(|c| Some((&c.keyword, c.hint.as_ref()?))).collect::<Vec<_>>();
+        if !hints.is_empty() {
+            prompt.push_str(if prompt.ends_with('\n') { "" } else { "\n" });
+            prompt.push_str("Some categories are easily confused:\n");
+            for (keyword, hint) in hints {
+                prompt.push_str(&format!("- {keyword}: {hint}\n"));
+            }
+        }
+        prompt
+    }
+
+    /// Check the list before a run. An empty list, or one without `fallback`
+    /// (which abstaining needs), is an error listing every problem. Duplicate
+    /// keywords (ignoring case) are only logged, and returned.
+    pub fn validate(&self, fallback: Option<&str>) -> Result<Vec<String>> {
+        let mut problems = Vec::new();
+        if self.0.is_empty() {
+            problems.push("there are no categories".to_string());
+        }
+        if let Some(fallback) = fallback {
+            if !self.word_in_list(fallback) {
+                problems.push(format!("the fallback category {fallback:?} isn't in the list"));
+            }
+        }
+        anyhow::ensure!(problems.is_empty(), "Invalid category list: {}", problems.join("; "));
+
+        let duplicates: Vec<String> = self.0
+            .iter()
+            .map(|c| c.keyword.to_lowercase())
+            .duplicates()
+            .collect();
+        for duplicate in duplicates.iter() {
+            tracing::warn!("Category {duplicate:?} is listed more than once");
+        }
+        Ok(duplicates)
+    }
+
+    /// Is `word` one of the category keywords? Case is ignored.
+    pub fn word_in_list(&self, word: &str) -> bool {
+        self.resolve_category(word).is_some()
+    }
+
+    /// The category an LLM answer names. The whole answer is tried first, then
+    /// with punctuation treated as spaces (so "Media Entertainment" is
+    /// "Media/Entertainment"), then the longest keyword the answer starts with
+    /// ("Gaming. The site sells games." is "Gaming"). Anything else is `None`.
+    pub fn match_response(&self, response: &str) -> Option<&str> {
+        let response = response.trim().trim_matches(|c: char| c == '"' || c == '\'' || c == '.' || c == '*');
+        if let Some(keyword) = self.resolve_category(response) {
+            return Some(keyword);
+        }
+        let response = normalize(response);
+        if let Some(category) = self.0.iter().find(|c| normalize(&c.keyword) == response) {
+            return Some(category.keyword.as_str());
+        }
+        self.0
+            .iter()
+            .map(|c| (c.keyword.as_str(), normalize(&c.keyword)))
+            .filter(|(_, keyword)| !keyword.is_empty())
+            .filter(|(_, keyword)| response.strip_prefix(keyword.as_str()).is_some_and(|rest| rest.starts_with(' ')))
+            .max_by_key(|(_, keyword)| keyword.len())
+            .map(|(keyword, _)| keyword)
+    }
+
+    /// The keyword `word` matches, as it's written in the list ("gaming" gives "Gaming").
+    pub fn resolve_category(&self, word: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|c| c.keyword.eq_ignore_ascii_case(word.trim()))
+            .map(|c| c.keyword.as_str())
+    }
+}
+
+/// Lowercase words, with anything that isn't a letter or digit as a single space.
+fn normalize(text: &str) -> String {
+    text.to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .join(" ")
+}
+
+/// A worked example for the prompt: a domain, its keywords, and the right answer.
+#[derive(Debug, Deserialize)]
+pub struct Example {
+    pub domain: String,
+    pub keywords: String,
+    pub category: String,
+}
+
+/// Read few-shot examples from CSV (`domain,keywords,category`, with a header).
+/// Every example has to use one of `categories`, or the LLM would be taught
+/// to answer with something we then reject.
+pub fn parse_examples(reader: impl std::io::Read, categories: &Categories) -> Result<Vec<Example>> {
+    let mut examples = Vec::new();
+    for example in csv::Reader::from_reader(reader).deserialize::<Example>() {
+        let mut example: Example = example?;
+        let Some(category) = categories.resolve_category(&example.category) else {
+            anyhow::bail!(
+                "Example {} uses category {:?}, which isn't in the category list",
+                example.domain, example.category
+            );
+        };
+        example.category = category.to_string();
+        examples.push(example);
+    }
+    Ok(examples)
+}
+
+pub fn load_examples(path: &Path, categories: &Categories) -> Result<Vec<Example>> {
+    parse_examples(std::fs::File::open(path)?, categories)
+}
+
+/// Read category overrides: `domain,category` lines, with or without a
+/// header. Domains are lowercased, and later lines win. Like the examples,
+/// every category has to be in `categories`.
+pub fn parse_overrides(reader: impl std::io::Read, categories: &Categories) -> Result<HashMap<String, String>> {
+    let mut overrides = HashMap::new();
+    let mut reader = csv::ReaderBuilder::new().has_headers(false).trim(csv::Trim::All).from_reader(reader);
+    for record in reader.records() {
+        let record = record?;
+        let (Some(domain), Some(category)) = (record.get(0), record.get(1)) else {
+            anyhow::bail!("Override {:?} isn't `domain,category`", record.iter().join(","));
+        };
+        if domain.eq_ignore_ascii_case("domain") {
+            continue;
+        }
+        let Some(canonical) = categories.resolve_category(category) else {
+            anyhow::bail!("Override for {domain} uses category {category:?}, which isn't in the category list");
+        };
+        overrides.insert(domain.to_lowercase(), canonical.to_string());
+    }
+    Ok(overrides)
+}
+
+pub fn load_overrides(path: &Path, categories: &Categories) -> Result<HashMap<String, String>> {
+    parse_overrides(std::fs::File::open(path)?, categories)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_descriptions_in_prompt() {
+        let categories = Categories::parse("Logistics: shipping, freight, warehousing\nGaming\n\n");
+        let prompt = categories.category_prompt();
+        assert!(prompt.contains("- Logistics: shipping, freight, warehousing"));
+        assert!(prompt.contains("- Gaming"));
+
+        // Validation only looks at the keyword
+        assert!(categories.word_in_list("Logistics"));
+        assert!(categories.word_in_list("gaming"));
+        assert!(!categories.word_in_list("Logistics: shipping, freight, warehousing"));
+        assert!(!categories.word_in_list("Shipping"));
+    }
+
+    #[test]
+    fn test_default_prompt_lists_keywords() {
+        let prompt = Categories::default().category_prompt();
+        assert!(prompt.contains("Banking/Finance, Cloud"));
+    }
+
+    #[test]
+    fn test_hints_in_prompt() {
+        let mut categories = Categories::default();
+        categories.parse_hints("Hosting: they rent out servers or websites, not their own software\nWeather: not a category\n");
+        let prompt = categories.category_prompt();
+        assert!(prompt.contains("- Hosting: they rent out servers or websites, not their own software"), "{prompt}");
+        assert!(!prompt.contains("Weather"));
+        assert!(!Categories::default().category_prompt().contains("confused"));
+    }
+
+    #[test]
+    fn test_empty_list_is_invalid() {
+        let err = Categories::parse("\n  \n").validate(None).unwrap_err();
+        assert!(err.to_string().contains("no categories"));
+        // Every problem is listed
+        let err = Categories::parse("").validate(Some("Other")).unwrap_err().to_string();
+        assert!(err.contains("no categories") && err.contains("\"Other\""), "{err}");
+    }
+
+    #[test]
+    fn test_duplicates_are_warnings() {
+        let categories = Categories::parse("News\nGaming\nnews: daily headlines\nOther\n");
+        assert_eq!(categories.validate(Some("Other")).unwrap(), vec!["news".to_string()]);
+        assert!(Categories::default().validate(Some("Other")).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_missing_fallback_is_invalid() {
+        let categories = Categories::parse("News\nGaming\n");
+        assert!(categories.validate(None).is_ok());
+        let err = categories.validate(Some("Other")).unwrap_err();
+        assert!(err.to_string().contains("fallback category \"Other\""));
+    }
+
+    #[test]
+    fn test_overrides_are_validated() {
+        let overrides = parse_overrides("domain,category\nGoogle.com, technology\n".as_bytes(), &Categories::default()).unwrap();
+        assert_eq!(overrides["google.com"], "Technology");
+        let err = parse_overrides("bbc.example,Journalism\n".as_bytes(), &Categories::default()).unwrap_err();
+        assert!(err.to_string().contains("Journalism"));
+    }
+
+    #[test]
+    fn test_example_with_invalid_category_is_rejected() {
+        let csv = "domain,keywords,category\nsteam.example,games store play,Gaming\nbbc.example,news weather,Journalism\n";
+        let err = parse_examples(csv.as_bytes(), &Categories::default()).unwrap_err();
+        assert!(err.to_string().contains("Journalism"));
+    }
+}
@@ -0,0 +1,171 @@
+//! Remembering how far through the domain list a run got. With a fixed
+//! shuffle seed the order is the same every time, so a resumed run can skip
+//! straight to that position instead of checking every domain against
+//! `categories.csv`. The list itself can change between runs (a different
+//! sample, or new input), so the checkpoint also records which list it's for.
+
+use std::collections::BTreeSet;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use anyhow::Result;
+use tokio::sync::mpsc::Sender;
+use tokio::task::JoinHandle;
+
+/// Every domain before `position` in the order given by `seed` is finished.
+/// `list` is the [`list_digest`] of the domains in that order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Checkpoint {
+    pub seed: u64,
+    pub position: usize,
+    pub list: u64,
+}
+
+/// A digest of the domains in run order, to tell whether a checkpoint is for
+/// this list. Any change to the domains, their order or how many there are
+/// changes it.
+pub fn list_digest(domains: &[String]) -> u64 {
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    domains.hash(&mut hasher);
+    hasher.finish()
+}
+
+impl Checkpoint {
+    /// Parse a checkpoint file: `seed,position,list`.
+    pub fn parse(text: &str) -> Option<Self> {
+        let mut fields = text.trim().split(',');
+        let checkpoint = Self {
+            seed: fields.next()?.parse().ok()?,
+            position: fields.next()?.parse().ok()?,
+            list: fields.next()?.parse().ok()?,
+        };
+        fields.next().is_none().then_some(checkpoint)
+    }
+
+    /// Where to start a run over `list` (a [`list_digest`]) shuffled with
+    /// `seed`. A missing checkpoint, or one for a different order or list,
+    /// means starting from the beginning.
+    pub fn load(path: &Path, seed: u64, list: u64) -> usize {
+        std::fs::read_to_string(path)
+            .ok()
+            .and_then(|text| Self::parse(&text))
+            .filter(|checkpoint| checkpoint.seed == seed && checkpoint.list == list)
+            .map(|checkpoint| checkpoint.position)
+            .unwrap_or(0)
+    }
+
+    /// Write the checkpoint, replacing the old one in a single step.
+    async fn save(&self, path: &Path) -> Result<()> {
+        let temp = path.with_extension("tmp");
+        tokio::fs::write(&temp, format!("{},{},{}\n", self.seed, self.position, self.list)).await?;
+        tokio::fs::rename(&temp, path).await?;
+        Ok(())
+    }
+}
+
+/// Domains finish out of order. This tracks the end of the unbroken run of
+/// finished ones, which is as far as it's safe to skip on a resume.
+struct Watermark {
+    position: usize,
+    /// Finished domains beyond `position`
+    ahead: BTreeSet<usize>,
+}
+
+impl Watermark {
+    fn new(position: usize) -> Self {
+        Self { position, ahead: BTreeSet::new() }
+    }
+
+    /// Mark a domain finished. Returns `true` if the position moved.
+    fn finish(&mut self, index: usize) -> bool {
+        if index < self.position {
+            return false;
+        }
+        self.ahead.insert(index);
+        let before = self.position;
+        while self.ahead.remove(&self.position) {
+            self.position += 1;
+        }
+        self.position != before
+    }
+}
+
+/// Send the index of each domain as it finishes (whatever the outcome), and
+/// the checkpoint file is kept up to date. `list` is the [`list_digest`] of
+/// the run's domains, and `start` is where this run began. Drop the sender
+/// and await the writer so the last checkpoint is saved.
+pub async fn checkpoint(path: PathBuf, seed: u64, list: u64, start: usize, capacity: usize) -> (Sender<usize>, JoinHandle<()>) {
+    let (tx, mut rx) = tokio::sync::mpsc::channel::<usize>(capacity.max(1));
+    let writer = tokio::spawn(async move {
+        let mut watermark = Watermark::new(start);
+        while let Some(index) = rx.recv().await {
+            if watermark.finish(index) {
+                let checkpoint = Checkpoint { seed, position: watermark.position, list };
+                if let Err(e) = checkpoint.save(&path).await {
+                    tracing::error!("Unable to write checkpoint: {e:?}");
+                }
+            }
+        }
+    });
+    (tx, writer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_checkpoint_resumes_after_finished_run() {
+        // 0, 1 and 3 are done, but 2 is still in flight
+        let mut watermark = Watermark::new(0);
+        for index in [1, 0, 3] {
+            watermark.finish(index);
+        }
+        assert_eq!(watermark.position, 2);
+
+        let path = std::env::temp_dir().join(format!("checkpoint-test-{}.txt", std::process::id()));
+        Checkpoint { seed: 42, position: watermark.position, list: 9 }.save(&path).await.unwrap();
+        assert_eq!(Checkpoint::load(&path, 42, 9), 2);
+        // A different shuffle means a different order, so the position is useless
+        assert_eq!(Checkpoint::load(&path, 7, 9), 0);
+
+        // Once 2 finishes, the run moves past 3 as well
+        assert!(watermark.finish(2));
+        assert_eq!(watermark.position, 4);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_checkpoint_is_saved_before_the_writer_finishes() {
+        let path = std::env::temp_dir().join(format!("checkpoint-writer-test-{}.txt", std::process::id()));
+        let (tx, writer) = checkpoint(path.clone(), 42, 9, 0, 1).await;
+        for index in [1, 0, 2] {
+            tx.send(index).await.unwrap();
+        }
+        drop(tx);
+        writer.await.unwrap();
+        assert_eq!(Checkpoint::load(&path, 42, 9), 3);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_checkpoint_for_a_different_list_starts_over() {
+        let domains = |names: &[&str]| names.iter().map(|name| name.to_string()).collect::<Vec<_>>();
+        let first = list_digest(&domains(&["a.com", "b.com", "c.com"]));
+        let path = std::env::temp_dir().join(format!("checkpoint-list-test-{}.txt", std::process::id()));
+        Checkpoint { seed: 42, position: 2, list: first }.save(&path).await.unwrap();
+        assert_eq!(Checkpoint::load(&path, 42, first), 2);
+
+        // Same seed, but a different sample, an extra domain, or another order
+        for other in [&["a.com", "d.com", "c.com"][..], &["a.com", "b.com", "c.com", "d.com"], &["b.com", "a.com", "c.com"]] {
+            assert_eq!(Checkpoint::load(&path, 42, list_digest(&domains(other))), 0, "{other:?}");
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_checkpoint_without_a_list_is_ignored() {
+        // Written before checkpoints recorded their list
+        assert_eq!(Checkpoint::parse("42,2\n"), None);
+        assert_eq!(Checkpoint::parse("42,2,9\n"), Some(Checkpoint { seed: 42, position: 2, list: 9 }));
+    }
+}
@@ -0,0 +1,91 @@
+//! Everything a run is set up with, in one place. `main` builds a [`Config`]
+//! from the command line once, and the run reads its settings from there: the
+//! [`Pipeline`](crate::Pipeline) holds it, scraping gets its `scrape` part,
+//! the [`Categorizer`](crate::llm::Categorizer) is built on its `llm` part,
+//! and [`Config::file_sink`] opens the result files it describes.
+
+use std::path::PathBuf;
+use std::time::Duration;
+use crate::llm::LlmConfig;
+use crate::runner::{DEFAULT_CONCURRENCY, DEFAULT_DOMAIN_TIMEOUT_SECS};
+use crate::scraping::ScrapeConfig;
+use crate::success_fail::{FileSink, SuccessOptions, DEFAULT_CHANNEL_CAPACITY};
+
+#[derive(Clone)]
+pub struct Config {
+    /// How websites are fetched and read
+    pub scrape: ScrapeConfig,
+    /// Where the LLM is, and how to call it
+    pub llm: LlmConfig,
+    /// Which columns the results get
+    pub results: SuccessOptions,
+    /// Where the result files go
+    pub output_dir: PathBuf,
+    /// The category list, instead of the built-in one
+    pub categories: Option<PathBuf>,
+    /// How many domains are worked on at once
+    pub concurrency: usize,
+    /// How long each domain gets, from DNS lookup to category
+    pub domain_timeout: Duration,
+    /// How many results can queue up for each writer
+    pub channel_capacity: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            scrape: ScrapeConfig::default(),
+            llm: LlmConfig::default(),
+            results: SuccessOptions::default(),
+            output_dir: PathBuf::from("."),
+            categories: None,
+            concurrency: DEFAULT_CONCURRENCY,
+            domain_timeout: Duration::from_secs(DEFAULT_DOMAIN_TIMEOUT_SECS),
+            channel_capacity: DEFAULT_CHANNEL_CAPACITY,
+        }
+    }
+}
+
+impl Config {
+    /// The usual result files, in the output directory, with these options.
+    pub async fn file_sink(&self) -> FileSink {
+        FileSink::in_dir(&self.output_dir, self.results, self.channel_capacity).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::success_fail::{Domain, FailReason, ResultSink};
+
+    #[test]
+    fn test_default_config() {
+        let config = Config::default();
+        assert_eq!(config.llm.model, "llama3.1");
+        assert_eq!(config.llm.endpoint, "http://localhost:11434/api/generate");
+        assert_eq!(config.scrape.timeout(), Duration::from_secs(30));
+        assert_eq!(config.scrape.max_words, 100);
+        assert!(!config.results.upsert);
+        assert_eq!(config.results.fsync_every, 0);
+        assert_eq!(config.output_dir, PathBuf::from("."));
+        assert_eq!(config.categories, None);
+        assert_eq!(config.concurrency, 32);
+        assert_eq!(config.domain_timeout, Duration::from_secs(120));
+        assert_eq!(config.channel_capacity, 32);
+    }
+
+    #[tokio::test]
+    async fn test_file_sink_writes_to_the_output_dir() {
+        let dir = std::env::temp_dir().join(format!("config-sink-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = Config { output_dir: dir.clone(), ..Default::default() };
+        let sink = config.file_sink().await;
+        let domain = Domain { domain: "bakery.example".to_string(), category: "Food/Beverage".to_string(), ..Default::default() };
+        sink.record_success(&domain).await;
+        sink.record_failure("nothing-here.invalid", FailReason::Nxdomain).await;
+        sink.close().await;
+        assert!(std::fs::read_to_string(dir.join("categories.csv")).unwrap().contains("bakery.example,Food/Beverage"));
+        assert!(std::fs::read_to_string(dir.join("failures.txt")).unwrap().contains("nothing-here.invalid"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
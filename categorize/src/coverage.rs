@@ -0,0 +1,88 @@
+//! How much of the domain list the results cover so far, and how each domain
+//! ended up, for checking on a run before and after.
+
+use std::collections::HashSet;
+use std::path::Path;
+use crate::success_fail::result_domains;
+
+/// How many of the domains ended up each way. A domain is counted once, by
+/// its best outcome: categorized, then parked, unchanged, failed. Remaining
+/// is everything not in any results file.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Coverage {
+    pub total: usize,
+    pub categorized: usize,
+    pub parked: usize,
+    pub unchanged: usize,
+    pub failed: usize,
+    pub remaining: usize,
+}
+
+impl Coverage {
+    /// `count` as a percentage of all the domains.
+    pub fn percent(&self, count: usize) -> f64 {
+        match self.total {
+            0 => 0.0,
+            total => count as f64 * 100.0 / total as f64,
+        }
+    }
+
+    /// One line per outcome, with counts and percentages.
+    pub fn report(&self) -> String {
+        [
+            ("Categorized", self.categorized),
+            ("Parked", self.parked),
+            ("Unchanged", self.unchanged),
+            ("Failed", self.failed),
+            ("Remaining", self.remaining),
+        ]
+        .iter()
+        .map(|(label, count)| format!("{label:<12} {count:>8} ({:.1}%)\n", self.percent(*count)))
+        .chain(std::iter::once(format!("{:<12} {:>8}\n", "Total", self.total)))
+        .collect()
+    }
+}
+
+/// Coverage of `domains` by the results files' text. Domains in the files
+/// that aren't in `domains` are ignored.
+pub fn count_coverage(domains: &[String], categorized: &str, parked: &str, unchanged: &str, failed: &str) -> Coverage {
+    let outcomes: Vec<HashSet<String>> = [categorized, parked, unchanged, failed].map(result_domains).into();
+    let mut coverage = Coverage { total: domains.len(), ..Default::default() };
+    for domain in domains {
+        let counter = match outcomes.iter().position(|done| done.contains(domain)) {
+            Some(0) => &mut coverage.categorized,
+            Some(1) => &mut coverage.parked,
+            Some(2) => &mut coverage.unchanged,
+            Some(_) => &mut coverage.failed,
+            None => &mut coverage.remaining,
+        };
+        *counter += 1;
+    }
+    coverage
+}
+
+/// Coverage of `domains` by the results in `dir`. Missing files count as empty.
+pub fn coverage(domains: &[String], dir: &Path) -> Coverage {
+    let read = |name: &str| std::fs::read_to_string(dir.join(name)).unwrap_or_default();
+    count_coverage(domains, &read("categories.csv"), &read("parked.csv"), &read("unchanged.txt"), &read("failures.txt"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use load_data::load_asn_domains_from_paths;
+
+    #[test]
+    fn test_coverage_of_asn_fixtures() {
+        let asn_fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("../load_data/fixtures");
+        let paths: Vec<PathBuf> = ["asn-east.csv", "asn-west.csv"].iter().map(|name| asn_fixtures.join(name)).collect();
+        let (domains, _) = load_asn_domains_from_paths(&paths).unwrap();
+
+        let coverage = coverage(&domains, &Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/coverage"));
+        // google.com failed once, but was categorized after
+        assert_eq!(coverage, Coverage { total: 5, categorized: 2, parked: 1, unchanged: 0, failed: 1, remaining: 1 });
+        assert_eq!(coverage.percent(coverage.categorized), 40.0);
+        assert!(coverage.report().contains("Categorized         2 (40.0%)"), "{}", coverage.report());
+    }
+}
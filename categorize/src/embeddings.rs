@@ -0,0 +1,160 @@
+//! Categorizing by embedding similarity: each category's description is
+//! embedded once, and a page gets the category nearest its keywords.
+
+use std::hash::{Hash, Hasher};
+use std::path::Path;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use crate::categories::Categories;
+use crate::llm::Completion;
+
+/// Each category, with the embedding of its description.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EmbeddingCategorizer {
+    /// Identifies the category list the vectors were made from
+    hash: String,
+    categories: Vec<(String, Vec<f32>)>,
+}
+
+/// What's embedded for a category: its keyword, and its description if it has one.
+fn category_text(keyword: &str, description: Option<&str>) -> String {
+    match description {
+        Some(description) => format!("{keyword}: {description}"),
+        None => keyword.to_string(),
+    }
+}
+
+/// A hash of what would be embedded for `categories`, so a cache made from a
+/// different list can be spotted.
+pub fn categories_hash(categories: &Categories) -> String {
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    for category in categories.iter() {
+        category_text(&category.keyword, category.description.as_deref()).hash(&mut hasher);
+    }
+    format!("{:016x}", hasher.finish())
+}
+
+/// How alike two vectors are, from -1 to 1. Zero if either is all zeroes.
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(a, b)| a * b).sum();
+    let norms = a.iter().map(|a| a * a).sum::<f32>().sqrt() * b.iter().map(|b| b * b).sum::<f32>().sqrt();
+    if norms == 0.0 { 0.0 } else { dot / norms }
+}
+
+impl EmbeddingCategorizer {
+    /// Embed every category's description.
+    pub async fn new<L: Completion>(llm: &L, categories: &Categories) -> Result<Self> {
+        let mut vectors = Vec::new();
+        for category in categories.iter() {
+            let vector = llm.embed(&category_text(&category.keyword, category.description.as_deref())).await?;
+            vectors.push((category.keyword.clone(), vector));
+        }
+        anyhow::ensure!(!vectors.is_empty(), "There are no categories to embed");
+        Ok(Self { hash: categories_hash(categories), categories: vectors })
+    }
+
+    /// The embeddings saved at `path`, if they were made from `categories`.
+    /// Otherwise (or if there's no cache yet) they're embedded again, and saved.
+    pub async fn cached<L: Completion>(llm: &L, categories: &Categories, path: &Path) -> Result<Self> {
+        if let Ok(cache) = std::fs::read_to_string(path) {
+            match serde_json::from_str::<Self>(&cache) {
+                Ok(cached) if cached.hash == categories_hash(categories) => return Ok(cached),
+                Ok(_) => tracing::info!("The categories have changed since {} was made", path.display()),
+                Err(e) => tracing::warn!("Ignoring unreadable {}: {e}", path.display()),
+            }
+        }
+        let embeddings = Self::new(llm, categories).await?;
+        embeddings.save(path)?;
+        Ok(embeddings)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        std::fs::write(path, serde_json::to_string(self)?)?;
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.categories.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.categories.is_empty()
+    }
+
+    /// The category nearest `vector`, and how similar it is.
+    pub fn nearest(&self, vector: &[f32]) -> Result<(&str, f32)> {
+        let (category, similarity) = self.categories
+            .iter()
+            .map(|(category, embedding)| (category, cosine_similarity(vector, embedding)))
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            .ok_or_else(|| anyhow::anyhow!("There are no category embeddings"))?;
+        anyhow::ensure!(
+            similarity > 0.0,
+            "The page isn't like any of the categories"
+        );
+        Ok((category, similarity))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::Categorizer;
+    use crate::test_support::MockLlm;
+
+    fn categories() -> Categories {
+        Categories::parse("Gaming: video games, consoles and esports\nFood/Beverage: bakery, bread, cakes and restaurants\n")
+    }
+
+    #[tokio::test]
+    async fn test_nearest_category_by_embedding() {
+        let llm = MockLlm::new([""]);
+        let mut categorizer = Categorizer::new(llm);
+        categorizer.categories = categories();
+        categorizer.embeddings = Some(EmbeddingCategorizer::new(&categorizer.llm, &categorizer.categories).await.unwrap());
+        assert_eq!(categorizer.llm.embedded.lock().unwrap().len(), 2);
+
+        let result = categorizer.categorize_domain("bakery.example", "fresh bread cakes bakery").await.unwrap();
+        assert_eq!(result.category, "Food/Beverage");
+        let result = categorizer.categorize_domain("games.example", "consoles esports video games").await.unwrap();
+        assert_eq!(result.category, "Gaming");
+
+        // Each category was embedded once, then one embedding per page, and
+        // the LLM wasn't asked anything
+        assert_eq!(categorizer.llm.embedded.lock().unwrap().len(), 4);
+        assert!(categorizer.llm.prompts.lock().unwrap().is_empty());
+        assert!(categorizer.categorize_domain("blank.example", "").await.is_err());
+    }
+
+    #[test]
+    fn test_cosine_similarity() {
+        assert_eq!(cosine_similarity(&[1.0, 0.0], &[2.0, 0.0]), 1.0);
+        assert_eq!(cosine_similarity(&[1.0, 0.0], &[0.0, 1.0]), 0.0);
+        assert_eq!(cosine_similarity(&[0.0, 0.0], &[1.0, 1.0]), 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_cache_is_written_reused_and_regenerated() {
+        let dir = std::env::temp_dir().join(format!("categorize-embeddings-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("category-embeddings.json");
+        let _ = std::fs::remove_file(&path);
+        let llm = MockLlm::new([""]);
+
+        let first = EmbeddingCategorizer::cached(&llm, &categories(), &path).await.unwrap();
+        assert!(path.exists());
+        assert_eq!(llm.embedded.lock().unwrap().len(), 2);
+
+        let again = EmbeddingCategorizer::cached(&llm, &categories(), &path).await.unwrap();
+        assert_eq!(again, first);
+        assert_eq!(llm.embedded.lock().unwrap().len(), 2, "the cache should be reused");
+
+        let changed = Categories::parse("Gaming: video games\nNews: headlines\nSports: football\n");
+        let regenerated = EmbeddingCategorizer::cached(&llm, &changed, &path).await.unwrap();
+        assert_eq!(regenerated.len(), 3);
+        assert_eq!(llm.embedded.lock().unwrap().len(), 5);
+        let saved: EmbeddingCategorizer = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(saved, regenerated);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
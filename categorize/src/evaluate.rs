@@ -0,0 +1,126 @@
+//! Measuring accuracy against a labeled corpus: saved `{domain}.html` pages,
+//! and a `labels.csv` of `domain,category` giving the right answers. Nothing
+//! touches the network except the LLM, so prompts can be compared run to run.
+
+use std::path::Path;
+use anyhow::Result;
+use serde::Deserialize;
+use crate::llm::{Categorizer, Completion};
+use crate::scraping::{website_text, ScrapeConfig};
+
+#[derive(Deserialize)]
+struct Label {
+    domain: String,
+    category: String,
+}
+
+/// One labeled domain's result.
+pub struct Judged {
+    pub domain: String,
+    pub expected: String,
+    /// `None` if extraction or categorization failed
+    pub predicted: Option<String>,
+}
+
+impl Judged {
+    pub fn is_correct(&self) -> bool {
+        self.predicted.as_deref().is_some_and(|p| p.eq_ignore_ascii_case(&self.expected))
+    }
+}
+
+pub struct Evaluation {
+    pub results: Vec<Judged>,
+}
+
+impl Evaluation {
+    pub fn correct(&self) -> usize {
+        self.results.iter().filter(|r| r.is_correct()).count()
+    }
+
+    /// The share of domains categorized as labeled, from 0 to 1.
+    pub fn accuracy(&self) -> f64 {
+        match self.results.len() {
+            0 => 0.0,
+            total => self.correct() as f64 / total as f64,
+        }
+    }
+
+    /// An error, listing the misses, if accuracy is below `min` (0 to 1).
+    pub fn check_accuracy(&self, min: f64) -> Result<()> {
+        if self.accuracy() >= min {
+            return Ok(());
+        }
+        let misses = self.results
+            .iter()
+            .filter(|r| !r.is_correct())
+            .map(|r| format!("{} ({} as {})", r.domain, r.expected, r.predicted.as_deref().unwrap_or("nothing")))
+            .collect::<Vec<_>>();
+        anyhow::bail!(
+            "Accuracy {:.1}% is below {:.1}%; missed {}",
+            self.accuracy() * 100.0, min * 100.0, misses.join(", ")
+        )
+    }
+}
+
+/// Categorize every domain in `corpus/labels.csv` from its saved page, using
+/// `scrape`'s extraction settings, and compare with the labels.
+pub async fn evaluate<L: Completion>(corpus: &Path, scrape: &ScrapeConfig, categorizer: &Categorizer<L>) -> Result<Evaluation> {
+    let mut scrape = scrape.clone();
+    scrape.fixtures = Some(corpus.to_path_buf());
+    let mut results = Vec::new();
+    for label in csv::Reader::from_path(corpus.join("labels.csv"))?.deserialize::<Label>() {
+        let label = label?;
+        let predicted = match website_text(&label.domain, &scrape).await {
+            Ok(page) => categorizer
+                .categorize_domain_in(&label.domain, &page.keywords, page.language.as_deref())
+                .await
+                .ok()
+                .map(|result| result.category),
+            Err(e) => {
+                tracing::warn!(domain = %label.domain, "Couldn't read the saved page: {e}");
+                None
+            }
+        };
+        results.push(Judged { domain: label.domain, expected: label.category, predicted });
+    }
+    Ok(Evaluation { results })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{MockLlm, RecordedLlm};
+
+    /// The accuracy the sample corpus has to keep. One of its six recorded
+    /// answers is wrong, so this allows for that and nothing more.
+    const MIN_CORPUS_ACCURACY: f64 = 0.8;
+
+    #[tokio::test]
+    async fn test_accuracy_on_labeled_corpus() {
+        let corpus = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures"));
+        // Calls everything food, so gets the bakery right and the games site wrong
+        let categorizer = Categorizer::new(MockLlm::new(["Food/Beverage"]));
+        let evaluation = evaluate(corpus, &ScrapeConfig::default(), &categorizer).await.unwrap();
+
+        assert_eq!(evaluation.results.len(), 2);
+        assert_eq!(evaluation.correct(), 1);
+        assert_eq!(evaluation.accuracy(), 0.5);
+        let miss = evaluation.results.iter().find(|r| !r.is_correct()).unwrap();
+        assert_eq!(miss.domain, "games.example");
+        assert_eq!(miss.predicted.as_deref(), Some("Food/Beverage"));
+    }
+
+    #[tokio::test]
+    async fn test_corpus_accuracy_does_not_regress() {
+        // Recorded answers keep this deterministic. A change to extraction or
+        // answer parsing that loses a right answer fails here.
+        let corpus = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/corpus"));
+        let categorizer = Categorizer::new(RecordedLlm::load(&corpus.join("responses.csv")).unwrap());
+        let evaluation = evaluate(corpus, &ScrapeConfig::default(), &categorizer).await.unwrap();
+
+        assert_eq!(evaluation.results.len(), 6);
+        evaluation.check_accuracy(MIN_CORPUS_ACCURACY).unwrap();
+        let err = evaluation.check_accuracy(0.9).unwrap_err().to_string();
+        assert!(err.contains("stackyard.example (Hosting as Technology)"), "{err}");
+    }
+}
@@ -0,0 +1,92 @@
+//! What the `categorize` binary exits with, so scripts can tell a clean run
+//! from one with failures or one that couldn't start:
+//!
+//! | Code | Meaning |
+//! |------|---------|
+//! | 0 | Every domain was processed: categorized, parked or unchanged |
+//! | 1 | Something else stopped the run, e.g. a results file couldn't be written, or Ctrl-C before every domain was done |
+//! | 2 | The run finished, but some domains failed |
+//! | 3 | The LLM couldn't be reached (for any domain) |
+//! | 4 | The configuration is invalid: bad arguments, or a bad category, example or template file |
+
+use std::fmt;
+use crate::success_fail::{FailReason, RunEvent};
+use crate::Outcome;
+
+pub const SUCCESS: u8 = 0;
+pub const ERROR: u8 = 1;
+pub const SOME_FAILED: u8 = 2;
+pub const LLM_UNREACHABLE: u8 = 3;
+pub const CONFIG_ERROR: u8 = 4;
+
+/// Marks an error as a problem with the configuration, as context:
+/// `Categories::load(path).context(ConfigError)`.
+#[derive(Debug)]
+pub struct ConfigError;
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Invalid configuration")
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// How a run's domains ended up, for its exit code.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RunSummary {
+    pub categorized: usize,
+    /// Parked or unchanged
+    pub skipped: usize,
+    pub failed: usize,
+    /// Of the failures, how many were because the LLM couldn't be reached
+    pub llm_unreachable: usize,
+    /// The run was stopped (Ctrl-C, or fail-fast) before every domain was done
+    pub stopped: bool,
+    /// Of the domains started, how many were abandoned when it stopped
+    pub abandoned: usize,
+}
+
+impl RunSummary {
+    pub fn record(&mut self, outcome: &Outcome) {
+        match outcome {
+            Outcome::Categorized(_) => self.categorized += 1,
+            Outcome::Parked(_) | Outcome::Unchanged => self.skipped += 1,
+            Outcome::Failed(reason) => self.record_failure(*reason),
+        }
+    }
+
+    pub fn record_event(&mut self, event: &RunEvent) {
+        match event {
+            RunEvent::DomainSucceeded(_) => self.categorized += 1,
+            RunEvent::DomainSkipped { .. } => self.skipped += 1,
+            RunEvent::DomainFailed { reason, .. } => self.record_failure(*reason),
+        }
+    }
+
+    fn record_failure(&mut self, reason: FailReason) {
+        self.failed += 1;
+        if reason == FailReason::LlmUnreachable {
+            self.llm_unreachable += 1;
+        }
+    }
+
+    /// The exit code for a run: an unreachable LLM matters more than other
+    /// failures, and a run that was stopped early never succeeded.
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Self { llm_unreachable: 1.., .. } => LLM_UNREACHABLE,
+            Self { failed: 1.., .. } => SOME_FAILED,
+            Self { stopped: true, .. } => ERROR,
+            _ => SUCCESS,
+        }
+    }
+}
+
+/// The exit code for a run stopped by `error`.
+pub fn error_exit_code(error: &anyhow::Error) -> u8 {
+    match error.is::<ConfigError>() {
+        true => CONFIG_ERROR,
+        false => ERROR,
+    }
+}
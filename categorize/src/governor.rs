@@ -0,0 +1,82 @@
+//! A shared limit on outbound requests, across scraping and the LLM.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// A token bucket: `burst` requests can go at once, then they're spaced out
+/// to `rate` per second. Clones share the same bucket.
+#[derive(Clone, Default)]
+pub struct Governor(Option<Arc<Mutex<Bucket>>>);
+
+struct Bucket {
+    rate: f64,
+    burst: f64,
+    /// Goes negative when requests are queued up waiting
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Governor {
+    /// No limit at all.
+    pub fn unlimited() -> Self {
+        Self(None)
+    }
+
+    pub fn new(rate: f64, burst: usize) -> anyhow::Result<Self> {
+        anyhow::ensure!(rate.is_finite() && rate > 0.0, "The request rate must be more than zero, not {rate}");
+        let burst = burst.max(1) as f64;
+        Ok(Self(Some(Arc::new(Mutex::new(Bucket {
+            rate,
+            burst,
+            tokens: burst,
+            updated: Instant::now(),
+        })))))
+    }
+
+    /// Wait until another request is allowed. Callers are let through in the
+    /// order they asked.
+    pub async fn wait(&self) {
+        let Some(bucket) = &self.0 else { return };
+        let delay = {
+            let mut bucket = bucket.lock().unwrap();
+            let now = Instant::now();
+            let refill = now.duration_since(bucket.updated).as_secs_f64() * bucket.rate;
+            bucket.tokens = (bucket.tokens + refill).min(bucket.burst);
+            bucket.updated = now;
+            // Take a token now, even if that means owing one
+            bucket.tokens -= 1.0;
+            if bucket.tokens >= 0.0 {
+                return;
+            }
+            Duration::from_secs_f64(-bucket.tokens / bucket.rate)
+        };
+        tokio::time::sleep(delay).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_requests_beyond_the_burst_are_delayed() {
+        let governor = Governor::new(2.0, 3).unwrap();
+        let start = Instant::now();
+        let mut times = Vec::new();
+        for _ in 0..6 {
+            governor.clone().wait().await;
+            times.push(start.elapsed());
+        }
+        // The burst goes straight away, then two a second
+        assert_eq!(&times[..3], &[Duration::ZERO; 3]);
+        assert_eq!(times[3], Duration::from_millis(500));
+        assert_eq!(times[5], Duration::from_millis(1500));
+
+        // Unlimited never waits
+        for _ in 0..100 {
+            Governor::unlimited().wait().await;
+        }
+        assert_eq!(times.last(), Some(&start.elapsed()));
+    }
+}
@@ -0,0 +1,95 @@
+//! Checking a `categories.csv` that has built up over many runs: domains
+//! listed more than once, categories that aren't allowed any more, and rows
+//! that don't parse.
+
+use std::collections::HashMap;
+use crate::categories::Categories;
+
+/// What's wrong with a results file, and a cleaned copy of it.
+#[derive(Debug, Default)]
+pub struct Integrity {
+    /// Domains with more than one row
+    pub duplicates: Vec<String>,
+    /// `(domain, category)` for rows whose category isn't in the list
+    pub unknown: Vec<(String, String)>,
+    /// `(line number, line)` for rows that couldn't be read
+    pub malformed: Vec<(usize, String)>,
+    /// The file without malformed rows or unknown categories, keeping each
+    /// domain's latest row where it first appeared
+    pub cleaned: String,
+}
+
+impl Integrity {
+    pub fn is_clean(&self) -> bool {
+        self.duplicates.is_empty() && self.unknown.is_empty() && self.malformed.is_empty()
+    }
+}
+
+/// The domain and category of one line, if it's a well-formed row.
+fn parse_row(line: &str) -> Option<(String, String)> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .from_reader(line.as_bytes());
+    let record = reader.records().next()?.ok()?;
+    let domain = record.get(0)?.trim();
+    let category = record.get(1)?.trim();
+    if domain.is_empty() || domain.contains(char::is_whitespace) || category.is_empty() {
+        return None;
+    }
+    Some((domain.to_string(), category.to_string()))
+}
+
+/// Check the text of a `categories.csv` against the allowed `categories`.
+pub fn check_results(text: &str, categories: &Categories) -> Integrity {
+    let mut integrity = Integrity::default();
+    let mut lines: Vec<&str> = Vec::new();
+    let mut index: HashMap<String, usize> = HashMap::new();
+    for (number, line) in text.lines().enumerate().filter(|(_, l)| !l.trim().is_empty()) {
+        let Some((domain, category)) = parse_row(line) else {
+            integrity.malformed.push((number + 1, line.to_string()));
+            continue;
+        };
+        if !categories.word_in_list(&category) {
+            integrity.unknown.push((domain, category));
+            continue;
+        }
+        match index.get(&domain) {
+            Some(&i) => {
+                if !integrity.duplicates.contains(&domain) {
+                    integrity.duplicates.push(domain);
+                }
+                lines[i] = line;
+            }
+            None => {
+                index.insert(domain, lines.len());
+                lines.push(line);
+            }
+        }
+    }
+    integrity.cleaned = lines.iter().map(|line| format!("{line}\n")).collect();
+    integrity
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_duplicates_and_unknown_categories_are_reported() {
+        let text = concat!(
+            "a.com,News,ipv4\n",
+            "b.com,Journalism,ipv4\n",
+            "a.com,Technology,ipv4\n",
+            "LLM output with no commas\n",
+            "d.com,Gaming,dual-stack\n",
+        );
+        let integrity = check_results(text, &Categories::default());
+        assert!(!integrity.is_clean());
+        assert_eq!(integrity.duplicates, vec!["a.com"]);
+        assert_eq!(integrity.unknown, vec![("b.com".to_string(), "Journalism".to_string())]);
+        assert_eq!(integrity.malformed.iter().map(|(n, _)| *n).collect::<Vec<_>>(), vec![4]);
+        assert_eq!(integrity.cleaned, "a.com,Technology,ipv4\nd.com,Gaming,dual-stack\n");
+
+        assert!(check_results(&integrity.cleaned, &Categories::default()).is_clean());
+    }
+}
@@ -0,0 +1,185 @@
+//! Running the pipeline in two halves: scrape every domain and keep what the
+//! LLM would be shown on disk, then categorize from that file once the LLM is
+//! available, without fetching anything again. Scraping can then carry on
+//! while the LLM is down.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::Sender;
+use tokio::task::JoinHandle;
+use crate::scraping::Page;
+use crate::success_fail::{append_to_file, complete_lines, AddressFamily, Domain};
+
+/// Where the scrape phase keeps its pages, in the output directory.
+pub const KEYWORD_CACHE_FILE: &str = "keyword-cache.jsonl";
+
+/// A domain that scraped well enough to categorize: everything the LLM step needs.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScrapedPage {
+    pub domain: String,
+    /// What the domain resolved to
+    pub addrs: Vec<IpAddr>,
+    pub keywords: String,
+    pub status: u16,
+    pub fetch_ms: u64,
+    pub language: Option<String>,
+    /// The signal headers, as they'd go in the prompt
+    pub headers: Option<String>,
+    pub image: Option<String>,
+    pub favicon: Option<String>,
+    /// [`Page::content_hash`], for spotting identical pages
+    pub content_hash: u64,
+}
+
+impl ScrapedPage {
+    pub fn new(domain: &str, addrs: Vec<IpAddr>, page: Page) -> Self {
+        Self {
+            domain: domain.to_string(),
+            addrs,
+            content_hash: page.content_hash(),
+            headers: page.header_summary(),
+            keywords: page.keywords,
+            status: page.status,
+            fetch_ms: page.elapsed.as_millis() as u64,
+            language: page.language,
+            image: page.image,
+            favicon: page.favicon,
+        }
+    }
+
+    /// The result row for this page, given its category.
+    pub fn into_domain(self, category: String) -> Domain {
+        Domain {
+            address_family: AddressFamily::from_addrs(self.addrs),
+            domain: self.domain,
+            category,
+            http_status: Some(self.status),
+            fetch_time: Some(Duration::from_millis(self.fetch_ms)),
+            keywords: Some(self.keywords),
+            duplicate_of: None,
+            language: self.language,
+            image: self.image,
+            favicon: self.favicon,
+            agreement: None,
+            from_name: false,
+        }
+    }
+}
+
+/// The pages in a keyword cache, in the order first seen. A domain scraped
+/// more than once keeps its latest page. Lines that don't parse are skipped.
+pub fn read_keyword_cache(text: &str) -> Vec<ScrapedPage> {
+    let mut pages: Vec<ScrapedPage> = Vec::new();
+    let mut index: HashMap<String, usize> = HashMap::new();
+    for line in complete_lines(text).lines().filter(|l| !l.trim().is_empty()) {
+        let page: ScrapedPage = match serde_json::from_str(line) {
+            Ok(page) => page,
+            Err(e) => {
+                tracing::warn!("Skipping a keyword cache line that doesn't parse: {e}");
+                continue;
+            }
+        };
+        match index.get(&page.domain) {
+            Some(&i) => pages[i] = page,
+            None => {
+                index.insert(page.domain.clone(), pages.len());
+                pages.push(page);
+            }
+        }
+    }
+    pages
+}
+
+/// Append scraped pages to `filename`, one JSON object per line.
+pub async fn keyword_cache(filename: PathBuf, capacity: usize) -> (Sender<ScrapedPage>, JoinHandle<()>) {
+    let (tx, mut rx) = tokio::sync::mpsc::channel::<ScrapedPage>(capacity.max(1));
+    let writer = tokio::spawn(async move {
+        while let Some(page) = rx.recv().await {
+            tracing::info!(domain = %page.domain, "Scraped");
+            let line = match serde_json::to_string(&page) {
+                Ok(line) => line,
+                Err(e) => {
+                    tracing::error!("Failed to serialize scraped page: {}", e);
+                    continue;
+                }
+            };
+            if let Err(e) = append_to_file(&filename, &line).await {
+                tracing::error!("Failed to write to file: {}", e);
+            }
+        }
+    });
+    (tx, writer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "llm")]
+    use {
+        crate::llm::Categorizer,
+        crate::scraping::{DnsCache, ScrapeConfig},
+        crate::success_fail::close,
+        crate::test_support::MockLlm,
+        crate::{categorize_scraped, scrape_domain, Outcome},
+    };
+
+    #[cfg(feature = "llm")]
+    #[tokio::test]
+    async fn test_categorize_phase_runs_from_the_cache() {
+        let path = std::env::temp_dir().join(format!("keyword-cache-test-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let dns = DnsCache::default();
+        dns.insert("bakery.example", vec!["192.0.2.1".parse().unwrap()]);
+        let fixtures = ScrapeConfig::builder()
+            .fixtures(concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures"))
+            .build()
+            .unwrap();
+
+        let (tx, writer) = keyword_cache(path.clone(), 4).await;
+        tx.send(scrape_domain("bakery.example", &dns, &fixtures).await.unwrap()).await.unwrap();
+        // Parked domains are recorded, not cached
+        dns.insert("parked.example", vec!["192.0.2.1".parse().unwrap()]);
+        assert!(matches!(scrape_domain("parked.example", &dns, &fixtures).await, Err(Outcome::Parked(_))));
+        close(tx, writer).await;
+
+        let pages = read_keyword_cache(&std::fs::read_to_string(&path).unwrap());
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(pages.len(), 1);
+        assert!(pages[0].keywords.contains("bread"));
+
+        // Without fixtures, any attempt to fetch bakery.example would fail
+        let offline = ScrapeConfig::builder().build().unwrap();
+        let categorizer = Categorizer::new(MockLlm::new(["Food/Beverage"]));
+        let Outcome::Categorized(result) = categorize_scraped(pages[0].clone(), &offline, &categorizer).await else {
+            panic!("expected a category");
+        };
+        assert_eq!(result.category, "Food/Beverage");
+        assert_eq!(result.http_status, Some(200));
+        assert!(categorizer.llm.prompts.lock().unwrap()[0].contains("bread"));
+    }
+
+    #[test]
+    fn test_latest_scrape_wins() {
+        let page = |domain: &str, keywords: &str| ScrapedPage {
+            domain: domain.to_string(),
+            addrs: Vec::new(),
+            keywords: keywords.to_string(),
+            status: 200,
+            fetch_ms: 5,
+            language: None,
+            headers: None,
+            image: None,
+            favicon: None,
+            content_hash: 0,
+        };
+        let text: String = [page("a.example", "old"), page("b.example", "bee"), page("a.example", "new")]
+            .iter()
+            .map(|p| serde_json::to_string(p).unwrap() + "\n")
+            .collect();
+        let pages = read_keyword_cache(&format!("{text}not json\n"));
+        assert_eq!(pages, vec![page("a.example", "new"), page("b.example", "bee")]);
+    }
+}
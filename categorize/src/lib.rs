@@ -0,0 +1,1162 @@
+//! Scrapes the websites behind the ASN domains, and asks a local LLM to
+//! categorize them.
+//!
+//! Scraping and the LLM are the `scrape` and `llm` features, both on by
+//! default. Without them (`--no-default-features`) only the offline parts
+//! are built: the categories, result files, checkpoints and run ordering,
+//! with no reqwest, scraper or tokio networking.
+
+#[cfg(all(feature = "scrape", feature = "llm"))]
+pub mod asn;
+pub mod backoff;
+pub mod categories;
+pub mod checkpoint;
+#[cfg(all(feature = "scrape", feature = "llm"))]
+pub mod config;
+pub mod coverage;
+#[cfg(feature = "llm")]
+pub mod embeddings;
+#[cfg(all(feature = "scrape", feature = "llm"))]
+pub mod evaluate;
+pub mod exit;
+pub mod governor;
+pub mod integrity;
+#[cfg(feature = "scrape")]
+pub mod keyword_cache;
+#[cfg(feature = "llm")]
+pub mod llm;
+pub mod logging;
+#[cfg(feature = "scrape")]
+pub mod render;
+pub mod runner;
+#[cfg(feature = "scrape")]
+pub mod scraping;
+pub mod success_fail;
+pub mod tokenize;
+#[cfg(test)]
+mod test_support;
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tokio::sync::Notify;
+use load_data::cap_per_tld;
+use rand::prelude::SliceRandom;
+use rand::{Rng, SeedableRng};
+use success_fail::{result_domains, Domain, FailReason, ResultSink};
+#[cfg(feature = "scrape")]
+use {
+    std::panic::AssertUnwindSafe,
+    futures::FutureExt,
+    keyword_cache::ScrapedPage,
+    scraping::{has_enough_content, is_tls_error, website_text, DnsCache, NotModified, RedirectLoop, ScrapeConfig, TooSmall},
+};
+#[cfg(all(feature = "scrape", feature = "llm"))]
+use {
+    std::future::Future,
+    std::sync::atomic::{AtomicUsize, Ordering},
+    std::time::Duration,
+    futures::{Stream, StreamExt},
+    tokio::sync::mpsc::Sender,
+    config::Config,
+    exit::RunSummary,
+    llm::{Categorizer, Completion},
+    runner::{run_bounded, with_deadline, Coalesce},
+    success_fail::{EventSink, RunEvent},
+};
+
+/// What happened to a domain.
+#[derive(Debug, Clone)]
+pub enum Outcome {
+    Categorized(Domain),
+    /// A parked or placeholder page, with what gave it away. These aren't
+    /// worth asking the LLM about.
+    Parked(String),
+    /// The homepage hasn't changed since `ScrapeConfig::since`
+    Unchanged,
+    Failed(FailReason),
+}
+
+impl Outcome {
+    /// A one-line report for the terminal: `domain,category`, or a JSON object.
+    pub fn summary(&self, domain: &str, json: bool) -> String {
+        if json {
+            let value = match self {
+                Self::Categorized(result) => match &result.duplicate_of {
+                    Some(original) => serde_json::json!({ "domain": domain, "category": result.category, "duplicate_of": original }),
+                    None if result.from_name => serde_json::json!({ "domain": domain, "category": result.category, "from_name": true }),
+                    None => serde_json::json!({ "domain": domain, "category": result.category }),
+                },
+                Self::Parked(signal) => serde_json::json!({ "domain": domain, "parked": signal }),
+                Self::Unchanged => serde_json::json!({ "domain": domain, "unchanged": true }),
+                Self::Failed(reason) => serde_json::json!({ "domain": domain, "failed": reason.to_string() }),
+            };
+            return value.to_string();
+        }
+        match self {
+            Self::Categorized(result) => format!("{domain},{}", result.category),
+            Self::Parked(signal) => format!("{domain},parked ({signal})"),
+            Self::Unchanged => format!("{domain},unchanged"),
+            Self::Failed(reason) => format!("{domain},failed ({reason})"),
+        }
+    }
+}
+
+/// Stops a run at the first permanent failure (one that isn't
+/// [transient](FailReason::is_transient)), for smoke tests where any problem
+/// should fail the whole run. Clones share it.
+#[derive(Clone, Default)]
+pub struct FailFast {
+    failure: Arc<Mutex<Option<(String, FailReason)>>>,
+    tripped: Arc<Notify>,
+}
+
+impl FailFast {
+    /// Note `domain`'s outcome, returning true if it's the one that stops the run.
+    pub fn check(&self, domain: &str, outcome: &Outcome) -> bool {
+        let Outcome::Failed(reason) = outcome else {
+            return false;
+        };
+        let mut failure = self.failure.lock().unwrap();
+        if reason.is_transient() || failure.is_some() {
+            return false;
+        }
+        *failure = Some((domain.to_string(), *reason));
+        self.tripped.notify_one();
+        true
+    }
+
+    /// The domain that stopped the run, and why.
+    pub fn failure(&self) -> Option<(String, FailReason)> {
+        self.failure.lock().unwrap().clone()
+    }
+
+    /// Completes once there's been a permanent failure, for `run_bounded`'s shutdown.
+    pub async fn tripped(&self) {
+        while self.failure().is_none() {
+            self.tripped.notified().await;
+        }
+    }
+}
+
+/// The order a run works through `domains`: shuffled with `seed` (so test runs
+/// aren't always hitting the same ones), then capped to `max_per_tld` per TLD.
+pub fn run_order(mut domains: Vec<String>, seed: u64, max_per_tld: Option<usize>) -> Vec<String> {
+    domains.shuffle(&mut rand::rngs::StdRng::seed_from_u64(seed));
+    // Trimming after the shuffle gives a random sample of each TLD
+    match max_per_tld {
+        Some(cap) => cap_per_tld(domains, cap),
+        None => domains,
+    }
+}
+
+/// Like [`run_order`], but for only `n` of the domains, picked uniformly at
+/// random in one pass without shuffling the rest. The same seed gives the
+/// same sample, in the same order.
+pub fn sample_order(domains: impl IntoIterator<Item = String>, n: usize, seed: u64, max_per_tld: Option<usize>) -> Vec<String> {
+    let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+    let mut sample = reservoir_sample(domains, n, &mut rng);
+    // The reservoir keeps the list's order where nothing replaced it
+    sample.shuffle(&mut rng);
+    match max_per_tld {
+        Some(cap) => cap_per_tld(sample, cap),
+        None => sample,
+    }
+}
+
+/// Reservoir sampling: `n` of `items`, each equally likely, holding only `n`
+/// at a time.
+fn reservoir_sample<T>(items: impl IntoIterator<Item = T>, n: usize, rng: &mut impl Rng) -> Vec<T> {
+    let mut reservoir = Vec::with_capacity(n);
+    for (i, item) in items.into_iter().enumerate() {
+        if i < n {
+            reservoir.push(item);
+        } else {
+            let j = rng.gen_range(0..=i);
+            if j < n {
+                reservoir[j] = item;
+            }
+        }
+    }
+    reservoir
+}
+
+/// The domains a run still has to do, with their place in the run order:
+/// everything from `start` on that isn't in `done` (the text of a
+/// `categories.csv`).
+pub fn remaining(domains: Vec<String>, start: usize, done: &str) -> Vec<(usize, String)> {
+    let done = result_domains(done);
+    domains
+        .into_iter()
+        .enumerate()
+        .skip(start)
+        .filter(|(_, domain)| !done.contains(domain))
+        .collect()
+}
+
+/// The domains that aren't in any of the `done` results files (given as
+/// text), in their original order. Only whole domains match, so having done
+/// `example.com` doesn't rule out `ample.com`.
+pub fn missing(domains: Vec<String>, done: &[&str]) -> Vec<String> {
+    let done: HashSet<String> = done.iter().flat_map(|text| result_domains(text)).collect();
+    domains.into_iter().filter(|domain| !done.contains(domain)).collect()
+}
+
+/// Where a run writes its results. Resuming uses `resume_from`, which has to
+/// exist. Otherwise, with `runs` set, each run gets a new directory in it
+/// named for when it `started` (`runs/2024-06-01T12-00-00`). Without either,
+/// it's the current directory, as it always was.
+pub fn output_dir(runs: Option<&Path>, resume_from: Option<&Path>, started: SystemTime) -> anyhow::Result<PathBuf> {
+    if let Some(dir) = resume_from {
+        anyhow::ensure!(dir.is_dir(), "Can't resume from {}: it isn't a directory", dir.display());
+        return Ok(dir.to_path_buf());
+    }
+    let Some(runs) = runs else {
+        return Ok(PathBuf::from("."));
+    };
+    std::fs::create_dir_all(runs)?;
+    // Colons aren't allowed in Windows file names
+    let name = humantime::format_rfc3339_seconds(started).to_string().trim_end_matches('Z').replace(':', "-");
+    for n in 1.. {
+        let dir = match n {
+            1 => runs.join(&name),
+            n => runs.join(format!("{name}-{n}")),
+        };
+        match std::fs::create_dir(&dir) {
+            Ok(()) => return Ok(dir),
+            // Two runs in the same second
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    unreachable!()
+}
+
+/// Send an outcome to the matching part of `sink`.
+pub async fn record_outcome(sink: &impl ResultSink, domain: &str, outcome: &Outcome) {
+    match outcome {
+        // Shared from another spelling of the domain, which is its own row
+        Outcome::Categorized(result) if result.domain != domain => {
+            sink.record_success(&Domain { domain: domain.to_string(), ..result.clone() }).await
+        }
+        Outcome::Categorized(result) => sink.record_success(result).await,
+        Outcome::Parked(signal) => sink.record_parked(domain, signal).await,
+        Outcome::Unchanged => sink.record_unchanged(domain).await,
+        Outcome::Failed(reason) => sink.record_failure(domain, *reason).await,
+    }
+}
+
+/// Run `domains` through the pipeline, `concurrency` at a time, yielding an
+/// event for each as it finishes (so not in the order given). This is the
+/// run without any files: what to do with the results is up to the caller.
+/// The pipeline runs in the background, and dropping the stream stops it.
+#[cfg(all(feature = "scrape", feature = "llm"))]
+pub fn event_stream<L: Completion + 'static>(
+    domains: Vec<String>,
+    dns: DnsCache,
+    scrape: Arc<ScrapeConfig>,
+    categorizer: Arc<Categorizer<L>>,
+    concurrency: usize,
+    domain_timeout: Duration,
+) -> impl Stream<Item = RunEvent> {
+    let (tx, rx) = tokio::sync::mpsc::channel(concurrency.max(1));
+    let closed = tx.clone();
+    tokio::spawn(async move {
+        run_bounded(domains, concurrency, |domain| {
+            let sink = EventSink(tx.clone());
+            let dns = dns.clone();
+            let scrape = scrape.clone();
+            let categorizer = categorizer.clone();
+            async move {
+                let outcome = with_deadline(&domain, domain_timeout, process_domain(&domain, &dns, &scrape, &categorizer))
+                    .await
+                    .unwrap_or(Outcome::Failed(FailReason::Timeout));
+                record_outcome(&sink, &domain, &outcome).await;
+            }
+        }, async move { closed.closed().await }).await;
+    });
+    futures::stream::unfold(rx, |mut rx| async move { rx.recv().await.map(|event| (event, rx)) })
+}
+
+/// Run `domains` through the pipeline like [`event_stream`], counting how
+/// they ended up.
+#[cfg(all(feature = "scrape", feature = "llm"))]
+pub async fn run_categorization<L: Completion + 'static>(
+    domains: Vec<String>,
+    dns: DnsCache,
+    scrape: Arc<ScrapeConfig>,
+    categorizer: Arc<Categorizer<L>>,
+    concurrency: usize,
+    domain_timeout: Duration,
+) -> RunSummary {
+    event_stream(domains, dns, scrape, categorizer, concurrency, domain_timeout)
+        .fold(RunSummary::default(), |mut summary, event| async move {
+            summary.record_event(&event);
+            summary
+        })
+        .await
+}
+
+/// What every domain in a [`run_domains`] run goes through. Clones share
+/// everything.
+#[cfg(all(feature = "scrape", feature = "llm"))]
+pub struct Pipeline<L> {
+    pub dns: DnsCache,
+    /// The run's settings, including how to scrape
+    pub config: Arc<Config>,
+    pub categorizer: Arc<Categorizer<L>>,
+}
+
+#[cfg(all(feature = "scrape", feature = "llm"))]
+impl<L> Clone for Pipeline<L> {
+    fn clone(&self) -> Self {
+        Self {
+            dns: self.dns.clone(),
+            config: self.config.clone(),
+            categorizer: self.categorizer.clone(),
+        }
+    }
+}
+
+/// Run the numbered `domains` through `pipeline`, writing each outcome to
+/// `sink` and its number to `progress` as it finishes. Domains that are the
+/// same apart from a `www.` share one run. Stops early if `shutdown`
+/// completes or `fail_fast` trips, abandoning whatever is in flight; the
+/// summary says so, and how many were abandoned.
+#[cfg(all(feature = "scrape", feature = "llm"))]
+pub async fn run_domains<L: Completion + 'static, S: ResultSink + 'static>(
+    domains: impl IntoIterator<Item = (usize, String)>,
+    pipeline: &Pipeline<L>,
+    sink: Arc<S>,
+    progress: Option<Sender<usize>>,
+    fail_fast: Option<FailFast>,
+    shutdown: impl Future<Output = ()>,
+) -> RunSummary {
+    let in_flight = Coalesce::default();
+    let summary = Arc::new(Mutex::new(RunSummary::default()));
+    let (started, finished) = (AtomicUsize::new(0), Arc::new(AtomicUsize::new(0)));
+    let done = run_bounded(domains, pipeline.config.concurrency, |(index, domain)| {
+        started.fetch_add(1, Ordering::Relaxed);
+        let finished = finished.clone();
+        // Clone the channels - they are designed for this.
+        let sink = sink.clone();
+        let progress = progress.clone();
+        let pipeline = pipeline.clone();
+        let in_flight = in_flight.clone();
+        let fail_fast = fail_fast.clone();
+        let summary = summary.clone();
+        async move {
+            // If the same domain (perhaps with a www.) is already being
+            // worked on, wait for that instead
+            let (outcome, first) = in_flight.run(&domain, || {
+                let domain = domain.clone();
+                async move {
+                    // A slow domain gives up its slot when its time is up
+                    let process = process_domain(&domain, &pipeline.dns, &pipeline.config.scrape, &pipeline.categorizer);
+                    with_deadline(&domain, pipeline.config.domain_timeout, process)
+                        .await
+                        .unwrap_or(Outcome::Failed(FailReason::Timeout))
+                }
+            }).await;
+            // Only one of each spelling writes the result
+            if first {
+                record_outcome(&*sink, &domain, &outcome).await;
+                summary.lock().unwrap().record(&outcome);
+                if let Some(fail_fast) = &fail_fast {
+                    fail_fast.check(&domain, &outcome);
+                }
+            }
+            if let Some(progress) = progress {
+                let _ = progress.send(index).await;
+            }
+            finished.fetch_add(1, Ordering::Relaxed);
+        }
+    }, shutdown).await;
+    let mut summary = summary.lock().unwrap().clone();
+    if !done {
+        let started = started.load(Ordering::Relaxed);
+        summary.stopped = true;
+        summary.abandoned = started - finished.load(Ordering::Relaxed);
+        tracing::warn!("Stopped early: {} of the {started} domains started were abandoned", summary.abandoned);
+    }
+    summary
+}
+
+/// Run one domain through the pipeline: resolve it, scrape it, and ask the LLM
+/// for a category.
+///
+/// Whatever goes wrong with one domain is its failure, not the run's: even a
+/// panic (say, in an HTML parser) comes back as [`FailReason::Internal`].
+#[cfg(all(feature = "scrape", feature = "llm"))]
+pub async fn process_domain<L: Completion>(
+    domain: &str,
+    dns: &DnsCache,
+    scrape: &ScrapeConfig,
+    categorizer: &Categorizer<L>,
+) -> Outcome {
+    let attempt = AssertUnwindSafe(try_process_domain(domain, dns, scrape, categorizer)).catch_unwind();
+    match attempt.await {
+        Ok(Ok(outcome)) => outcome,
+        Ok(Err(reason)) => Outcome::Failed(reason),
+        Err(_) => {
+            tracing::error!(domain, "Panicked while processing, skipping it");
+            Outcome::Failed(FailReason::Internal)
+        }
+    }
+}
+
+#[cfg(all(feature = "scrape", feature = "llm"))]
+async fn try_process_domain<L: Completion>(
+    domain: &str,
+    dns: &DnsCache,
+    scrape: &ScrapeConfig,
+    categorizer: &Categorizer<L>,
+) -> Result<Outcome, FailReason> {
+    if let Some(result) = categorizer.overridden(domain) {
+        tracing::debug!(domain, category = %result.category, "Using the override");
+        return Ok(Outcome::Categorized(result));
+    }
+    match try_scrape_domain(domain, dns, scrape).await {
+        Ok(page) => try_categorize_scraped(page, scrape, categorizer).await,
+        Err(Outcome::Failed(reason)) if categorizer.name_fallback => {
+            tracing::debug!(domain, %reason, "Couldn't scrape it, going by the name");
+            match categorizer.categorize_name(domain).await {
+                Ok(result) => Ok(Outcome::Categorized(result)),
+                Err(e) if llm::is_unreachable(&e) => Err(FailReason::LlmUnreachable),
+                // Still failed for the reason it couldn't be scraped
+                Err(_) => Err(reason),
+            }
+        }
+        Err(outcome) => Ok(outcome),
+    }
+}
+
+/// The half of [`process_domain`] that doesn't need the LLM: resolve and
+/// scrape the domain. It's either ready to categorize, or this is as far as
+/// it goes (it's parked, unchanged or failed). Panics are caught the same way.
+#[cfg(feature = "scrape")]
+pub async fn scrape_domain(domain: &str, dns: &DnsCache, scrape: &ScrapeConfig) -> Result<ScrapedPage, Outcome> {
+    match AssertUnwindSafe(try_scrape_domain(domain, dns, scrape)).catch_unwind().await {
+        Ok(result) => result,
+        Err(_) => {
+            tracing::error!(domain, "Panicked while scraping, skipping it");
+            Err(Outcome::Failed(FailReason::Internal))
+        }
+    }
+}
+
+/// The LLM half of [`process_domain`], for a page from [`scrape_domain`] or
+/// the keyword cache.
+#[cfg(all(feature = "scrape", feature = "llm"))]
+pub async fn categorize_scraped<L: Completion>(page: ScrapedPage, scrape: &ScrapeConfig, categorizer: &Categorizer<L>) -> Outcome {
+    let domain = page.domain.clone();
+    match AssertUnwindSafe(try_categorize_scraped(page, scrape, categorizer)).catch_unwind().await {
+        Ok(Ok(outcome)) => outcome,
+        Ok(Err(reason)) => Outcome::Failed(reason),
+        Err(_) => {
+            tracing::error!(domain, "Panicked while categorizing, skipping it");
+            Outcome::Failed(FailReason::Internal)
+        }
+    }
+}
+
+#[cfg(feature = "scrape")]
+async fn try_scrape_domain(domain: &str, dns: &DnsCache, scrape: &ScrapeConfig) -> Result<ScrapedPage, Outcome> {
+    // Dead domains would otherwise sit in a TCP connect until the timeout,
+    // so check that it resolves before trying HTTP.
+    let addrs = dns.resolve(domain).await;
+    if addrs.is_empty() {
+        return Err(Outcome::Failed(FailReason::Nxdomain));
+    }
+
+    let page = website_text(domain, scrape).await.map_err(|e| {
+        if e.is::<NotModified>() {
+            return Outcome::Unchanged;
+        }
+        Outcome::Failed(if e.is::<TooSmall>() {
+            FailReason::TooSmall
+        } else if let Some(looped) = e.chain().find_map(|e| e.downcast_ref::<RedirectLoop>()) {
+            tracing::warn!(domain, "{looped}");
+            FailReason::RedirectLoop
+        } else if is_tls_error(&e) {
+            FailReason::Tls
+        } else {
+            FailReason::Scrape
+        })
+    })?;
+    if let Some(signal) = page.parked {
+        return Err(Outcome::Parked(signal));
+    }
+    if !has_enough_content(&page.keywords, scrape) {
+        // Worth telling apart: these need a browser, not a retry
+        return Err(Outcome::Failed(match page.js_rendered {
+            true => FailReason::JsRendered,
+            false => FailReason::InsufficientContent,
+        }));
+    }
+    Ok(ScrapedPage::new(domain, addrs, page))
+}
+
+#[cfg(all(feature = "scrape", feature = "llm"))]
+async fn try_categorize_scraped<L: Completion>(
+    page: ScrapedPage,
+    scrape: &ScrapeConfig,
+    categorizer: &Categorizer<L>,
+) -> Result<Outcome, FailReason> {
+    let domain = page.domain.clone();
+    let hash = page.content_hash;
+    if let Some((original, category)) = categorizer.content_cache.as_ref().and_then(|cache| cache.get(hash)) {
+        tracing::info!(domain, duplicate_of = %original, "Same content as an earlier domain, reusing its category");
+        let mut result = page.into_domain(category);
+        result.duplicate_of = Some(original);
+        return Ok(Outcome::Categorized(result));
+    }
+    let text = match &page.headers {
+        Some(headers) if scrape.headers_in_prompt => format!("{}. HTTP headers: {headers}", page.keywords),
+        _ => page.keywords.clone(),
+    };
+    let answer = categorizer
+        .categorize_domain_in(&domain, &text, page.language.as_deref())
+        .await
+        .map_err(|e| if e.is::<llm::Refusal>() || e.is::<llm::NoConsensus>() {
+            FailReason::NeedsReview
+        } else if llm::is_unreachable(&e) {
+            FailReason::LlmUnreachable
+        } else {
+            FailReason::Categorize
+        })?;
+    let mut result = page.into_domain(answer.category);
+    result.agreement = answer.agreement;
+    if let Some(cache) = &categorizer.content_cache {
+        cache.insert(hash, &domain, &result.category);
+    }
+    Ok(Outcome::Categorized(result))
+}
+
+#[cfg(all(test, feature = "scrape", feature = "llm"))]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use anyhow::Context;
+    use itertools::Itertools;
+    use categories::Categories;
+    use test_support::{http_response, MemorySink, MockLlm, TestServer};
+
+    #[tokio::test]
+    async fn test_name_fallback_for_domains_that_cant_be_scraped() {
+        let mut categorizer = Categorizer::new(MockLlm::new(["Banking/Finance"]));
+        let domain = "cloudbank.invalid";
+        let result = process_domain(domain, &DnsCache::default(), &ScrapeConfig::default(), &categorizer).await;
+        assert!(matches!(result, Outcome::Failed(FailReason::Nxdomain)));
+        assert!(categorizer.llm.prompts.lock().unwrap().is_empty());
+
+        categorizer.name_fallback = true;
+        let Outcome::Categorized(result) = process_domain(domain, &DnsCache::default(), &ScrapeConfig::default(), &categorizer).await else {
+            panic!("should have been categorized from the name");
+        };
+        assert_eq!(result.category, "Banking/Finance");
+        assert!(result.from_name);
+        assert!(result.keywords.is_none());
+        let prompts = categorizer.llm.prompts.lock().unwrap().clone();
+        assert!(prompts[0].contains("domain name alone. The domain is: cloudbank.invalid."), "{}", prompts[0]);
+        assert!(Outcome::Categorized(result).summary(domain, true).contains("\"from_name\":true"));
+    }
+
+    #[tokio::test]
+    async fn test_non_resolving_domain_fails_before_http() {
+        // The server would answer, but the name doesn't resolve
+        let server = TestServer::start(|_| http_response(200, &[], "<title>Hello</title>")).await;
+        let dns = DnsCache::with_resolver(|_| async { Vec::new() });
+        let categorizer = Categorizer::new(MockLlm::new(["Other"]));
+        let result = process_domain(&server.domain(), &dns, &ScrapeConfig::default(), &categorizer).await;
+        assert!(matches!(result, Outcome::Failed(FailReason::Nxdomain)));
+        assert!(server.requests.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_outcomes_map_to_exit_codes() {
+        let dns = DnsCache::default();
+        dns.insert("bakery.example", vec!["192.0.2.1".parse().unwrap()]);
+        dns.insert("parked.example", vec!["192.0.2.1".parse().unwrap()]);
+        let scrape = Arc::new(ScrapeConfig::builder()
+            .fixtures(concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures"))
+            .build()
+            .unwrap());
+        let run = |domains: &[&str], categorizer| {
+            let domains = domains.iter().map(|d| d.to_string()).collect();
+            run_categorization(domains, dns.clone(), scrape.clone(), Arc::new(categorizer), 2, Duration::from_secs(30))
+        };
+
+        let clean = run(&["bakery.example", "parked.example"], Categorizer::new(MockLlm::new(["Food/Beverage"]))).await;
+        assert_eq!(clean, RunSummary { categorized: 1, skipped: 1, ..Default::default() });
+        assert_eq!(clean.exit_code(), exit::SUCCESS);
+
+        let failed = run(&["bakery.example", "nothing-here.invalid"], Categorizer::new(MockLlm::new(["Food/Beverage"]))).await;
+        assert_eq!(failed.failed, 1);
+        assert_eq!(failed.exit_code(), exit::SOME_FAILED);
+
+        // Nothing is listening on the LLM's port
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let llm = llm::LlmConfig::builder().endpoint(format!("http://127.0.0.1:{port}/api/generate")).build().unwrap();
+        let domains = vec!["bakery.example".to_string(), "nothing-here.invalid".to_string()];
+        let unreachable = run_categorization(domains, dns.clone(), scrape.clone(), Arc::new(Categorizer::new(llm)), 2, Duration::from_secs(30)).await;
+        assert_eq!(unreachable.llm_unreachable, 1);
+        assert_eq!(unreachable.exit_code(), exit::LLM_UNREACHABLE);
+
+        let config = Categories::load(Path::new("no-such-categories.txt")).context(exit::ConfigError).err().unwrap();
+        assert_eq!(exit::error_exit_code(&config), exit::CONFIG_ERROR);
+        assert_eq!(exit::error_exit_code(&anyhow::anyhow!("disk full")), exit::ERROR);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_fail_fast_stops_at_a_permanent_failure() {
+        let fail_fast = FailFast::default();
+        let finished = Arc::new(Mutex::new(Vec::new()));
+        let domains = ["slow.example", "flaky.example", "broken.example", "later.example", "last.example"];
+        let done = run_bounded(domains, 3, |domain| {
+            let fail_fast = fail_fast.clone();
+            let finished = finished.clone();
+            async move {
+                let (delay, outcome) = match domain {
+                    "flaky.example" => (1, Outcome::Failed(FailReason::Timeout)),
+                    "broken.example" => (2, Outcome::Failed(FailReason::Tls)),
+                    _ => (60, Outcome::Unchanged),
+                };
+                tokio::time::sleep(Duration::from_secs(delay)).await;
+                finished.lock().unwrap().push(domain);
+                fail_fast.check(domain, &outcome);
+            }
+        }, fail_fast.tripped()).await;
+
+        assert!(!done);
+        // The timeout could be luck, the TLS failure won't be
+        assert_eq!(fail_fast.failure(), Some(("broken.example".to_string(), FailReason::Tls)));
+        // Nothing slow got to finish
+        assert_eq!(*finished.lock().unwrap(), vec!["flaky.example", "broken.example"]);
+    }
+
+    #[tokio::test]
+    async fn test_overridden_domain_skips_scraping_and_llm() {
+        let server = TestServer::start(|_| http_response(200, &[], "<title>Search</title>")).await;
+        let domain = server.domain();
+        let dns = DnsCache::default();
+        dns.insert(&domain, vec!["127.0.0.1".parse().unwrap()]);
+        let mut categorizer = Categorizer::new(MockLlm::new(["Other"]));
+        categorizer.overrides.insert(domain.clone(), "Technology".to_string());
+
+        let Outcome::Categorized(result) = process_domain(&domain, &dns, &ScrapeConfig::default(), &categorizer).await else {
+            panic!("expected the override");
+        };
+        assert_eq!(result.category, "Technology");
+        assert!(server.requests.lock().unwrap().is_empty());
+        assert!(categorizer.llm.prompts.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_parked_domain_skips_the_llm() {
+        let dns = DnsCache::default();
+        dns.insert("parked.example", vec!["192.0.2.1".parse().unwrap()]);
+        let scrape = ScrapeConfig::builder()
+            .fixtures(concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures"))
+            .build()
+            .unwrap();
+        let categorizer = Categorizer::new(MockLlm::new(["Other"]));
+
+        let result = process_domain("parked.example", &dns, &scrape, &categorizer).await;
+        assert!(matches!(result, Outcome::Parked(_)));
+        assert!(categorizer.llm.prompts.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_single_domain_summary() {
+        let server = TestServer::start(|_| {
+            http_response(200, &[], "<title>Village Bakery</title><p>Fresh bread, cakes and pastries baked daily</p>")
+        }).await;
+        let domain = server.domain();
+        let dns = DnsCache::default();
+        dns.insert(&domain, vec!["127.0.0.1".parse().unwrap()]);
+        let categorizer = Categorizer::new(MockLlm::new(["Food/Beverage"]));
+
+        let outcome = process_domain(&domain, &dns, &ScrapeConfig::default(), &categorizer).await;
+        assert_eq!(outcome.summary(&domain, false), format!("{domain},Food/Beverage"));
+        let json: serde_json::Value = serde_json::from_str(&outcome.summary(&domain, true)).unwrap();
+        assert_eq!(json["category"], "Food/Beverage");
+        assert_eq!(json["domain"], domain.as_str());
+    }
+
+    #[tokio::test]
+    async fn test_unchanged_sites_are_skipped() {
+        let since = httpdate::parse_http_date("Mon, 01 Jan 2024 00:00:00 GMT").unwrap();
+        let scrape = ScrapeConfig::builder().since(since).build().unwrap();
+        let site = |last_modified: &'static str| TestServer::start(move |_| {
+            http_response(200, &[("Last-Modified", last_modified)], "<title>Village Bakery</title><p>Fresh bread, cakes and pastries baked daily</p>")
+        });
+        let old = site("Fri, 01 Dec 2023 12:00:00 GMT").await;
+        let new = site("Tue, 02 Jan 2024 12:00:00 GMT").await;
+        let dns = DnsCache::default();
+        for server in [&old, &new] {
+            dns.insert(&server.domain(), vec!["127.0.0.1".parse().unwrap()]);
+        }
+        let categorizer = Categorizer::new(MockLlm::new(["Food/Beverage"]));
+
+        let outcome = process_domain(&old.domain(), &dns, &scrape, &categorizer).await;
+        assert!(matches!(outcome, Outcome::Unchanged));
+        assert!(categorizer.llm.prompts.lock().unwrap().is_empty());
+
+        let outcome = process_domain(&new.domain(), &dns, &scrape, &categorizer).await;
+        assert!(matches!(outcome, Outcome::Categorized(_)));
+        // Both were asked whether anything had changed
+        let asked = new.requests.lock().unwrap().iter().any(|r| r.to_lowercase().contains("if-modified-since: mon, 01 jan 2024"));
+        assert!(asked);
+    }
+
+    #[tokio::test]
+    async fn test_not_modified_skips_the_download() {
+        let since = httpdate::parse_http_date("Mon, 01 Jan 2024 00:00:00 GMT").unwrap();
+        let scrape = ScrapeConfig::builder()
+            .since(since)
+            .extra_paths(["/about"])
+            .build()
+            .unwrap();
+        let conditional = TestServer::start(|path| match path {
+            "/" => http_response(304, &[], ""),
+            _ => http_response(200, &[], "<title>About us</title>"),
+        }).await;
+        let sitemap = TestServer::start(|path| match path {
+            "/sitemap.xml" => http_response(200, &[], "<urlset><url><loc>/</loc><lastmod>2023-11-30</lastmod></url>\
+                <url><loc>/about</loc><lastmod>2023-12-01T09:30:00+00:00</lastmod></url></urlset>"),
+            _ => http_response(200, &[], "<title>Village Bakery</title>"),
+        }).await;
+        let dns = DnsCache::default();
+        for server in [&conditional, &sitemap] {
+            dns.insert(&server.domain(), vec!["127.0.0.1".parse().unwrap()]);
+        }
+        let categorizer = Categorizer::new(MockLlm::new(["Food/Beverage"]));
+
+        // A 304, and the extra page isn't fetched
+        let outcome = process_domain(&conditional.domain(), &dns, &scrape, &categorizer).await;
+        assert!(matches!(outcome, Outcome::Unchanged));
+        assert!(conditional.requests.lock().unwrap().iter().all(|r| !r.starts_with("GET /about")));
+
+        // An old sitemap, and only the sitemap is fetched
+        let outcome = process_domain(&sitemap.domain(), &dns, &scrape, &categorizer).await;
+        assert!(matches!(outcome, Outcome::Unchanged));
+        let requests = sitemap.requests.lock().unwrap();
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0].starts_with("GET /sitemap.xml"));
+    }
+
+    #[tokio::test]
+    async fn test_german_page_gets_german_instructions() {
+        let server = TestServer::start(|_| {
+            http_response(200, &[], "<html lang=\"de-DE\"><title>Dorfbäckerei</title><p>Frisches Brot, Kuchen und Gebäck, täglich gebacken</p></html>")
+        }).await;
+        let domain = server.domain();
+        let dns = DnsCache::default();
+        dns.insert(&domain, vec!["127.0.0.1".parse().unwrap()]);
+        let mut categorizer = Categorizer::new(MockLlm::new(["Food/Beverage"]));
+        categorizer.templates.insert(
+            "de".to_string(),
+            "Bitte ordne diese Domain genau einer Kategorie zu. {categories} Antworte nur mit der Kategorie.".to_string(),
+        );
+
+        let outcome = process_domain(&domain, &dns, &ScrapeConfig::default(), &categorizer).await;
+        assert!(matches!(outcome, Outcome::Categorized(_)));
+        let prompt = categorizer.llm.prompts.lock().unwrap()[0].clone();
+        assert!(prompt.starts_with("Bitte ordne diese Domain genau einer Kategorie zu. Choose exactly one of these categories:"));
+        assert!(prompt.contains("frisches"));
+    }
+
+    #[tokio::test]
+    async fn test_headers_can_go_in_the_prompt() {
+        let server = TestServer::start(|_| {
+            http_response(200, &[("Server", "Shopify")], "<title>Kettle Store</title><p>Kettles, teapots, mugs and saucers, shipped daily</p>")
+        }).await;
+        let domain = server.domain();
+        let dns = DnsCache::default();
+        dns.insert(&domain, vec!["127.0.0.1".parse().unwrap()]);
+        let categorizer = Categorizer::new(MockLlm::new(["Retail"]));
+
+        let scrape = ScrapeConfig::builder().headers_in_prompt(true).build().unwrap();
+        process_domain(&domain, &dns, &scrape, &categorizer).await;
+        process_domain(&domain, &dns, &ScrapeConfig::default(), &categorizer).await;
+
+        let prompts = categorizer.llm.prompts.lock().unwrap();
+        assert!(prompts[0].ends_with("HTTP headers: server: Shopify"), "{}", prompts[0]);
+        // They're left out unless asked for
+        assert!(!prompts[1].contains("Shopify"));
+    }
+
+    #[tokio::test]
+    async fn test_outcomes_reach_the_sink() {
+        let dns = DnsCache::default();
+        dns.insert("bakery.example", vec!["192.0.2.1".parse().unwrap()]);
+        dns.insert("parked.example", vec!["192.0.2.2".parse().unwrap()]);
+        let scrape = ScrapeConfig::builder()
+            .fixtures(concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures"))
+            .build()
+            .unwrap();
+        let categorizer = Categorizer::new(MockLlm::new(["Food/Beverage"]));
+        let sink = MemorySink::default();
+
+        for domain in ["bakery.example", "parked.example", "nothing-here.invalid"] {
+            let outcome = process_domain(domain, &dns, &scrape, &categorizer).await;
+            record_outcome(&sink, domain, &outcome).await;
+        }
+
+        let records = sink.records.lock().unwrap();
+        assert_eq!(*records, vec![
+            "success bakery.example Food/Beverage".to_string(),
+            "parked parked.example this domain may be for sale".to_string(),
+            "failure nothing-here.invalid nxdomain".to_string(),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_domains_from_stdin_are_processed() {
+        let dns = DnsCache::default();
+        dns.insert("bakery.example", vec!["192.0.2.1".parse().unwrap()]);
+        dns.insert("parked.example", vec!["192.0.2.2".parse().unwrap()]);
+        let scrape = ScrapeConfig::builder()
+            .fixtures(concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures"))
+            .build()
+            .unwrap();
+        let pipeline = Pipeline {
+            dns,
+            config: Arc::new(Config { scrape, concurrency: 2, ..Default::default() }),
+            categorizer: Arc::new(Categorizer::new(MockLlm::new(["Food/Beverage"]))),
+        };
+        let sink = Arc::new(MemorySink::default());
+
+        // As piped in with --stdin: mixed case, repeats and blank lines
+        let stdin = "Bakery.example\n\nparked.example\nbakery.example \n";
+        let domains = load_data::read_domain_list(stdin.as_bytes()).unwrap();
+        let summary = run_domains(domains.into_iter().enumerate(), &pipeline, sink.clone(), None, None, std::future::pending()).await;
+
+        assert_eq!(summary, RunSummary { categorized: 1, skipped: 1, ..Default::default() });
+        let records = sink.records.lock().unwrap();
+        assert_eq!(records.iter().sorted().collect_vec(), vec![
+            "parked parked.example this domain may be for sale",
+            "success bakery.example Food/Beverage",
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_a_run_stopped_early_isnt_a_success() {
+        // Accepts connections, but never answers. Says when the first one
+        // arrives, so the stop comes while that domain is in flight.
+        let stalled = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let domain = stalled.local_addr().unwrap().to_string();
+        let (connected, in_flight) = tokio::sync::oneshot::channel();
+        tokio::spawn(async move {
+            let mut connected = Some(connected);
+            let mut held = Vec::new();
+            while let Ok((socket, _)) = stalled.accept().await {
+                held.push(socket);
+                if let Some(connected) = connected.take() {
+                    let _ = connected.send(());
+                }
+            }
+        });
+        let dns = DnsCache::default();
+        dns.insert(&domain, vec!["127.0.0.1".parse().unwrap()]);
+        let pipeline = Pipeline {
+            dns,
+            config: Arc::new(Config { concurrency: 1, ..Default::default() }),
+            categorizer: Arc::new(Categorizer::new(MockLlm::new(["Other"]))),
+        };
+        // Ctrl-C while the first is in flight, before the second has started
+        let domains = [domain, "never-started.example".to_string()];
+        let shutdown = async {
+            in_flight.await.unwrap();
+        };
+        let summary = run_domains(domains.into_iter().enumerate(), &pipeline, Arc::new(MemorySink::default()), None, None, shutdown).await;
+
+        assert_eq!(summary, RunSummary { stopped: true, abandoned: 1, ..Default::default() });
+        assert_eq!(summary.exit_code(), exit::ERROR);
+        // Failures before the stop still say so
+        assert_eq!(RunSummary { failed: 1, ..summary }.exit_code(), exit::SOME_FAILED);
+    }
+
+    #[tokio::test]
+    async fn test_tls_handshake_failure_is_its_own_reason() {
+        // Something that answers a TLS hello in plaintext, like a misconfigured server
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let https = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                use tokio::io::AsyncWriteExt;
+                let _ = socket.write_all(&http_response(400, &[], "not tls")).await;
+            }
+        });
+        let location = format!("https://{https}/");
+        let server = TestServer::start(move |_| http_response(301, &[("Location", &location)], "")).await;
+        let dns = DnsCache::default();
+        dns.insert(&server.domain(), vec!["127.0.0.1".parse().unwrap()]);
+        let categorizer = Categorizer::new(MockLlm::new(["Other"]));
+
+        let result = process_domain(&server.domain(), &dns, &ScrapeConfig::default(), &categorizer).await;
+        assert!(matches!(result, Outcome::Failed(FailReason::Tls)));
+
+        // Other connection failures are still just scrape failures
+        let closed = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let location = format!("http://{closed}/");
+        let server = TestServer::start(move |_| http_response(301, &[("Location", &location)], "")).await;
+        dns.insert(&server.domain(), vec!["127.0.0.1".parse().unwrap()]);
+        let result = process_domain(&server.domain(), &dns, &ScrapeConfig::default(), &categorizer).await;
+        assert!(matches!(result, Outcome::Failed(FailReason::Scrape)));
+    }
+
+    #[tokio::test]
+    async fn test_redirect_loop_is_classified() {
+        // Two URLs that send the client to each other
+        let server = TestServer::start(|path| match path {
+            "/" => http_response(301, &[("Location", "/secure")], ""),
+            _ => http_response(301, &[("Location", "/")], ""),
+        }).await;
+        let dns = DnsCache::default();
+        dns.insert(&server.domain(), vec!["127.0.0.1".parse().unwrap()]);
+        let categorizer = Categorizer::new(MockLlm::new(["Other"]));
+
+        let result = process_domain(&server.domain(), &dns, &ScrapeConfig::default(), &categorizer).await;
+        assert!(matches!(result, Outcome::Failed(FailReason::RedirectLoop)));
+        // It stopped as soon as it came back round, not at the hop limit
+        assert_eq!(server.requests.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_redirect_loop_on_an_ssl_host_isnt_a_tls_error() {
+        // The proxy answers for any host, so the name can be anything
+        let proxy = TestServer::start(|path| match path.ends_with("/secure") {
+            true => http_response(301, &[("Location", "/")], ""),
+            false => http_response(301, &[("Location", "/secure")], ""),
+        }).await;
+        let domain = "ssl-tls-certificate.example";
+        let dns = DnsCache::default();
+        dns.insert(domain, vec!["192.0.2.1".parse().unwrap()]);
+        let scrape = ScrapeConfig::builder().proxy(format!("http://{}", proxy.domain())).build().unwrap();
+        let categorizer = Categorizer::new(MockLlm::new(["Other"]));
+
+        let result = process_domain(domain, &dns, &scrape, &categorizer).await;
+        assert!(matches!(result, Outcome::Failed(FailReason::RedirectLoop)), "{result:?}");
+    }
+
+    #[tokio::test]
+    async fn test_small_content_length_is_skipped() {
+        let server = TestServer::start(|_| http_response(200, &[], "<meta http-equiv=refresh content=0;url=/home>")).await;
+        let dns = DnsCache::default();
+        dns.insert(&server.domain(), vec!["127.0.0.1".parse().unwrap()]);
+        let categorizer = Categorizer::new(MockLlm::new(["Other"]));
+
+        let scrape = ScrapeConfig::builder().min_content_length(512).build().unwrap();
+        let result = process_domain(&server.domain(), &dns, &scrape, &categorizer).await;
+        assert!(matches!(result, Outcome::Failed(FailReason::TooSmall)));
+
+        // Without a minimum, the word count is what rejects it
+        let result = process_domain(&server.domain(), &dns, &ScrapeConfig::default(), &categorizer).await;
+        assert!(matches!(result, Outcome::Failed(FailReason::InsufficientContent)));
+        assert!(categorizer.llm.prompts.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_sample_is_stable_and_uniform() {
+        let domains: Vec<String> = (0..10).map(|i| format!("{i}.example")).collect();
+        let sample = sample_order(domains.clone(), 3, 42, None);
+        assert_eq!(sample.len(), 3);
+        assert_eq!(sample.iter().unique().count(), 3);
+        assert_eq!(sample, sample_order(domains.clone(), 3, 42, None));
+        assert_eq!(sample_order(domains.clone(), 20, 42, None).len(), 10);
+
+        // Over many seeds, every domain is picked about as often
+        let mut counts: HashMap<String, usize> = HashMap::new();
+        for seed in 0..3000 {
+            for domain in sample_order(domains.clone(), 3, seed, None) {
+                *counts.entry(domain).or_default() += 1;
+            }
+        }
+        assert_eq!(counts.len(), 10);
+        assert!(counts.values().all(|&count| (800..1000).contains(&count)), "{counts:?}");
+    }
+
+    #[test]
+    fn test_remaining_domains_follow_the_filters() {
+        let domains: Vec<String> = ["a.com", "b.com", "c.com", "a.org", "b.org", "a.net"].map(String::from).to_vec();
+        let order = run_order(domains.clone(), 42, Some(1));
+        // One per TLD, and the same every time for the same seed
+        assert_eq!(order.len(), 3);
+        assert_eq!(order, run_order(domains.clone(), 42, Some(1)));
+        assert_eq!(run_order(domains.clone(), 42, None).len(), 6);
+
+        let order = run_order(domains, 7, None);
+        let done = format!("{},Gaming,ipv4\n", order[3]);
+        let left = remaining(order.clone(), 1, &done);
+        // The first is skipped by position, the fourth because it's already done
+        assert_eq!(left.iter().map(|(i, _)| *i).collect::<Vec<_>>(), vec![1, 2, 4, 5]);
+        assert_eq!(left[0].1, order[1]);
+    }
+
+    #[test]
+    fn test_missing_is_an_exact_difference() {
+        let domains = ["example.com", "ample.com", "news.example", "failed.example", "new.example"]
+            .map(String::from)
+            .to_vec();
+        let categories = "example.com,Technology,ipv4\nnews.example,News,ipv4,200,35\n";
+        let failures = "domain,reason\nfailed.example,nxdomain\n";
+
+        // A substring match would think ample.com was done too
+        assert_eq!(missing(domains.clone(), &[categories]), vec!["ample.com", "failed.example", "new.example"]);
+        assert_eq!(missing(domains.clone(), &[categories, failures]), vec!["ample.com", "new.example"]);
+        assert_eq!(remaining(domains, 0, categories).len(), 3);
+    }
+
+    /// An LLM with a bug in it.
+    struct PanickingLlm;
+
+    impl Completion for PanickingLlm {
+        async fn complete(&self, _prompt: &str) -> anyhow::Result<String> {
+            panic!("bug")
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bad_domains_dont_stop_the_run() {
+        // Everything resolves, so each domain gets as far as scraping
+        let dns = DnsCache::with_resolver(|_| async { vec!["192.0.2.1".parse().unwrap()] });
+        let scrape = ScrapeConfig::builder()
+            .fixtures(concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures"))
+            .build()
+            .unwrap();
+        let categorizer = Categorizer::new(MockLlm::new(["Food/Beverage"]));
+        let sink = MemorySink::default();
+        for domain in ["exa mple..com", "%zz.example", "", "bakery.example"] {
+            let outcome = process_domain(domain, &dns, &scrape, &categorizer).await;
+            record_outcome(&sink, domain, &outcome).await;
+        }
+        let records = sink.records.lock().unwrap().clone();
+        assert_eq!(records.len(), 4);
+        assert_eq!(records[3], "success bakery.example Food/Beverage");
+
+        // Even a panic is only that domain's failure
+        let categorizer = Categorizer::new(PanickingLlm);
+        let outcome = process_domain("bakery.example", &dns, &scrape, &categorizer).await;
+        assert!(matches!(outcome, Outcome::Failed(FailReason::Internal)));
+    }
+
+    #[tokio::test]
+    async fn test_identical_content_reuses_the_category() {
+        let page = "<title>Coming soon</title><p>This site is hosted by Example Hosting customer portal login</p>";
+        let server = TestServer::start(move |_| http_response(200, &[], page)).await;
+        let dns = DnsCache::default();
+        // The same server under two names
+        let second_name = format!("localhost:{}", server.addr.port());
+        for name in [server.domain(), second_name.clone()] {
+            dns.insert(&name, vec!["127.0.0.1".parse().unwrap()]);
+        }
+        let mut categorizer = Categorizer::new(MockLlm::new(["Hosting", "Gaming"]));
+        categorizer.content_cache = Some(Default::default());
+
+        let first = process_domain(&server.domain(), &dns, &ScrapeConfig::default(), &categorizer).await;
+        let second = process_domain(&second_name, &dns, &ScrapeConfig::default(), &categorizer).await;
+
+        let (Outcome::Categorized(first), Outcome::Categorized(second)) = (first, second) else { panic!("not categorized") };
+        assert_eq!(first.category, "Hosting");
+        assert_eq!(second.category, "Hosting");
+        assert_eq!(second.duplicate_of, Some(server.domain()));
+        assert_eq!(categorizer.llm.prompts.lock().unwrap().len(), 1);
+
+        // The results file says which rows were reused, and from where
+        let path = std::env::temp_dir().join(format!("dedup-test-{}.csv", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let (tx, writer) = success_fail::success_to(path.clone(), Default::default(), 4).await;
+        for result in [first, second] {
+            tx.send(result).await.unwrap();
+        }
+        success_fail::close(tx, writer).await;
+        let text = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(text, format!(
+            "{},Hosting,ipv4\n{second_name},Hosting,ipv4,dup={}\n",
+            server.domain(),
+            server.domain(),
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_one_event_per_domain() {
+        use success_fail::{EventSink, RunEvent, SkipReason};
+
+        let dns = DnsCache::default();
+        dns.insert("bakery.example", vec!["192.0.2.1".parse().unwrap()]);
+        dns.insert("parked.example", vec!["192.0.2.2".parse().unwrap()]);
+        let scrape = ScrapeConfig::builder()
+            .fixtures(concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures"))
+            .build()
+            .unwrap();
+        let categorizer = Categorizer::new(MockLlm::new(["Food/Beverage"]));
+        let (tx, mut rx) = tokio::sync::mpsc::channel(8);
+        // Events go alongside another sink
+        let sink = (MemorySink::default(), EventSink(tx));
+
+        let domains = ["bakery.example", "parked.example", "nothing-here.invalid"];
+        for domain in domains {
+            let outcome = process_domain(domain, &dns, &scrape, &categorizer).await;
+            record_outcome(&sink, domain, &outcome).await;
+        }
+        drop(sink);
+
+        let mut events = Vec::new();
+        while let Some(event) = rx.recv().await {
+            events.push(event);
+        }
+        assert_eq!(events.len(), 3);
+        assert!(matches!(&events[0], RunEvent::DomainSucceeded(d) if d.category == "Food/Beverage"));
+        assert!(matches!(&events[1], RunEvent::DomainSkipped { reason: SkipReason::Parked(_), .. }));
+        assert!(matches!(&events[2], RunEvent::DomainFailed { reason: FailReason::Nxdomain, .. }));
+        assert_eq!(events[2].to_json()["event"], "failed");
+    }
+
+    #[tokio::test]
+    async fn test_event_stream_yields_every_domain() {
+        use futures::StreamExt;
+
+        let dns = DnsCache::default();
+        dns.insert("bakery.example", vec!["192.0.2.1".parse().unwrap()]);
+        dns.insert("parked.example", vec!["192.0.2.2".parse().unwrap()]);
+        let scrape = ScrapeConfig::builder()
+            .fixtures(concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures"))
+            .build()
+            .unwrap();
+        let categorizer = Categorizer::new(MockLlm::new(["Food/Beverage"]));
+        let domains = ["bakery.example", "parked.example", "nothing-here.invalid"].map(String::from).to_vec();
+
+        let stream = event_stream(domains, dns, Arc::new(scrape), Arc::new(categorizer), 2, Duration::from_secs(10));
+        let events: Vec<RunEvent> = stream.collect().await;
+        let mut seen: Vec<String> = events
+            .iter()
+            .map(|event| event.to_json()["domain"].as_str().unwrap().to_string())
+            .collect();
+        seen.sort();
+        assert_eq!(seen, vec!["bakery.example", "nothing-here.invalid", "parked.example"]);
+        assert!(events.iter().any(|e| matches!(e, RunEvent::DomainSucceeded(d) if d.category == "Food/Beverage")));
+    }
+
+    #[test]
+    fn test_each_run_gets_its_own_directory() {
+        let runs = std::env::temp_dir().join(format!("runs-test-{}", std::process::id()));
+        let started = humantime::parse_rfc3339("2024-06-01T12:00:00Z").unwrap();
+        let first = output_dir(Some(&runs), None, started).unwrap();
+        assert_eq!(first, runs.join("2024-06-01T12-00-00"));
+        assert!(first.is_dir());
+        // Another run in the same second doesn't share it
+        assert_eq!(output_dir(Some(&runs), None, started).unwrap(), runs.join("2024-06-01T12-00-00-2"));
+
+        // Resuming picks up the given one, and doesn't make another
+        assert_eq!(output_dir(Some(&runs), Some(&first), SystemTime::now()).unwrap(), first);
+        assert_eq!(std::fs::read_dir(&runs).unwrap().count(), 2);
+        assert!(output_dir(None, Some(&runs.join("missing")), started).is_err());
+        // Without either, it's the current directory
+        assert_eq!(output_dir(None, None, started).unwrap(), PathBuf::from("."));
+        std::fs::remove_dir_all(runs).unwrap();
+    }
+}
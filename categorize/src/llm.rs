@@ -0,0 +1,1001 @@
+//! Talking to the local LLM (Ollama), and using it to categorize domains.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use anyhow::Result;
+use futures::future::join_all;
+use itertools::Itertools;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::json;
+use tokio::sync::mpsc::Sender;
+use crate::backoff::Backoff;
+use crate::categories::{Categories, Example};
+use crate::embeddings::EmbeddingCategorizer;
+use crate::governor::Governor;
+use crate::success_fail::{AuditRecord, Domain};
+
+/// Where to find the LLM, and how to call it.
+#[derive(Clone)]
+pub struct LlmConfig {
+    /// Ollama's generate endpoint
+    pub endpoint: String,
+    pub model: String,
+    /// Sampling temperature. `None` leaves it to the model's default.
+    pub temperature: Option<f32>,
+    /// Consulted before every request
+    pub governor: Governor,
+    /// Ollama's embeddings endpoint, for [`EmbeddingCategorizer`](crate::embeddings::EmbeddingCategorizer)
+    pub embedding_endpoint: String,
+    pub embedding_model: String,
+}
+
+impl Default for LlmConfig {
+    fn default() -> Self {
+        Self {
+            endpoint: "http://localhost:11434/api/generate".to_string(),
+            model: "llama3.1".to_string(),
+            temperature: None,
+            governor: Governor::unlimited(),
+            embedding_endpoint: "http://localhost:11434/api/embeddings".to_string(),
+            embedding_model: "nomic-embed-text".to_string(),
+        }
+    }
+}
+
+impl LlmConfig {
+    pub fn builder() -> LlmConfigBuilder {
+        LlmConfigBuilder(Self::default())
+    }
+}
+
+/// Builds an [`LlmConfig`], starting from the defaults and checking the result.
+pub struct LlmConfigBuilder(LlmConfig);
+
+impl LlmConfigBuilder {
+    pub fn endpoint(mut self, endpoint: impl Into<String>) -> Self {
+        self.0.endpoint = endpoint.into();
+        self
+    }
+
+    pub fn model(mut self, model: impl Into<String>) -> Self {
+        self.0.model = model.into();
+        self
+    }
+
+    pub fn temperature(mut self, temperature: f32) -> Self {
+        self.0.temperature = Some(temperature);
+        self
+    }
+
+    /// Share a request limit, e.g. with scraping.
+    pub fn governor(mut self, governor: Governor) -> Self {
+        self.0.governor = governor;
+        self
+    }
+
+    pub fn embedding_endpoint(mut self, endpoint: impl Into<String>) -> Self {
+        self.0.embedding_endpoint = endpoint.into();
+        self
+    }
+
+    pub fn embedding_model(mut self, model: impl Into<String>) -> Self {
+        self.0.embedding_model = model.into();
+        self
+    }
+
+    pub fn build(self) -> Result<LlmConfig> {
+        let config = self.0;
+        reqwest::Url::parse(&config.endpoint)
+            .map_err(|e| anyhow::anyhow!("Invalid LLM endpoint {}: {e}", config.endpoint))?;
+        reqwest::Url::parse(&config.embedding_endpoint)
+            .map_err(|e| anyhow::anyhow!("Invalid embedding endpoint {}: {e}", config.embedding_endpoint))?;
+        anyhow::ensure!(!config.model.trim().is_empty(), "The LLM model name can't be empty");
+        anyhow::ensure!(!config.embedding_model.trim().is_empty(), "The embedding model name can't be empty");
+        if let Some(temperature) = config.temperature {
+            anyhow::ensure!(
+                temperature.is_finite() && temperature >= 0.0,
+                "Temperature must be zero or more, not {temperature}"
+            );
+        }
+        Ok(config)
+    }
+}
+
+#[derive(Deserialize)]
+struct Response {
+    response: String,
+}
+
+pub async fn llm_completion(config: &LlmConfig, prompt: &str) -> Result<String> {
+    generate(config, prompt, None).await
+}
+
+/// Call Ollama's generate endpoint. `format` is passed through as Ollama's
+/// `format`: `"json"`, or a JSON schema.
+async fn generate(config: &LlmConfig, prompt: &str, format: Option<&serde_json::Value>) -> Result<String> {
+    let mut request = json!({
+        "model": config.model,
+        "prompt": prompt,
+    });
+    if let Some(temperature) = config.temperature {
+        request["options"] = json!({ "temperature": temperature });
+    }
+    if let Some(format) = format {
+        request["format"] = format.clone();
+    }
+
+    config.governor.wait().await;
+    let started = Instant::now();
+    let client = reqwest::Client::new();
+    let mut res = client.post(&config.endpoint)
+        .json(&request)
+        .send()
+        .await?;
+
+    // Ollama streams one JSON object per line, which needn't line up with
+    // the HTTP chunks
+    let mut response = String::new();
+    let mut pending = Vec::new();
+    let mut chunks = 0;
+    let mut first_token = None;
+    loop {
+        let data = res.chunk().await?;
+        let done = data.is_none();
+        // At the end, a newline finishes off a last line that didn't have one
+        pending.extend_from_slice(data.as_deref().unwrap_or(b"\n"));
+        while let Some(end) = pending.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = pending.drain(..=end).collect();
+            if line.trim_ascii().is_empty() {
+                continue;
+            }
+            let chunk: Response = serde_json::from_slice(&line)?;
+            chunks += 1;
+            if first_token.is_none() && !chunk.response.is_empty() {
+                first_token = Some(started.elapsed());
+            }
+            response.push_str(&chunk.response);
+        }
+        if done {
+            break;
+        }
+    }
+    tracing::debug!(
+        prompt_len = prompt.len(),
+        response_len = response.len(),
+        chunks,
+        first_token_ms = first_token.map(|t| t.as_millis() as u64),
+        total_ms = started.elapsed().as_millis() as u64,
+        "LLM request"
+    );
+
+    Ok(response)
+}
+
+#[derive(Deserialize)]
+struct EmbeddingResponse {
+    embedding: Vec<f32>,
+}
+
+/// Call Ollama's embeddings endpoint, for a vector representing `text`.
+pub async fn llm_embedding(config: &LlmConfig, text: &str) -> Result<Vec<f32>> {
+    let request = json!({
+        "model": config.embedding_model,
+        "prompt": text,
+    });
+    config.governor.wait().await;
+    let started = Instant::now();
+    let response: EmbeddingResponse = reqwest::Client::new()
+        .post(&config.embedding_endpoint)
+        .json(&request)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    anyhow::ensure!(!response.embedding.is_empty(), "The embedding model returned an empty vector");
+    tracing::debug!(
+        text_len = text.len(),
+        dimensions = response.embedding.len(),
+        total_ms = started.elapsed().as_millis() as u64,
+        "Embedding request"
+    );
+    Ok(response.embedding)
+}
+
+/// A category with the LLM's working, from a JSON reply.
+#[derive(Debug, PartialEq, Deserialize)]
+pub struct Judgement {
+    pub category: String,
+    /// How sure the model says it is, from 0 to 1
+    #[serde(default)]
+    pub confidence: Option<f32>,
+    /// The model's explanation
+    #[serde(default)]
+    pub reason: Option<String>,
+}
+
+/// Ask for a JSON reply and deserialize it. `format` is `json!("json")`, or a
+/// JSON schema for models that support one; the prompt should still describe
+/// the fields wanted.
+pub async fn llm_structured<T: DeserializeOwned>(config: &LlmConfig, prompt: &str, format: serde_json::Value) -> Result<T> {
+    parse_structured(&generate(config, prompt, Some(&format)).await?)
+}
+
+/// Parse a JSON reply. Malformed or incomplete JSON is an error, so the domain
+/// fails instead of being given a guess.
+pub fn parse_structured<T: DeserializeOwned>(reply: &str) -> Result<T> {
+    serde_json::from_str(reply.trim()).map_err(|e| anyhow::anyhow!("LLM reply isn't the expected JSON ({e}): {reply}"))
+}
+
+/// Did the request fail because the LLM couldn't be connected to at all?
+pub fn is_unreachable(error: &anyhow::Error) -> bool {
+    error.chain().any(|e| e.downcast_ref::<reqwest::Error>().is_some_and(reqwest::Error::is_connect))
+}
+
+/// Something that can complete a prompt. The real thing is Ollama, as
+/// described by an [`LlmConfig`]; tests substitute canned answers.
+pub trait Completion: Send + Sync {
+    fn complete(&self, prompt: &str) -> impl Future<Output = Result<String>> + Send;
+
+    /// A vector for `text`, for categorizing by similarity. Not every
+    /// backend has one.
+    fn embed(&self, _text: &str) -> impl Future<Output = Result<Vec<f32>>> + Send {
+        async { Err(anyhow::anyhow!("This LLM can't embed text")) }
+    }
+}
+
+impl Completion for LlmConfig {
+    fn complete(&self, prompt: &str) -> impl Future<Output = Result<String>> + Send {
+        llm_completion(self, prompt)
+    }
+
+    fn embed(&self, text: &str) -> impl Future<Output = Result<Vec<f32>>> + Send {
+        llm_embedding(self, text)
+    }
+}
+
+/// The instructions at the start of the prompt. `{categories}` is replaced
+/// with the category list.
+pub const DEFAULT_INSTRUCTIONS: &str = "Please categorize this domain with a single category. {categories} \
+    Do not elaborate, do not explain or otherwise enhance the answer.";
+
+/// A whole prompt, for trying other wordings without rebuilding. `{domain}`,
+/// `{keywords}` and `{categories}` are filled in, and `{examples}` (which is
+/// optional) gets the worked examples.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PromptTemplate(String);
+
+/// Placeholders every [`PromptTemplate`] has to have.
+const REQUIRED_PLACEHOLDERS: &[&str] = &["{domain}", "{keywords}", "{categories}"];
+
+impl PromptTemplate {
+    /// Check that `text` has all the required placeholders.
+    pub fn parse(text: &str) -> Result<Self> {
+        let missing = REQUIRED_PLACEHOLDERS.iter().filter(|p| !text.contains(*p)).join(", ");
+        anyhow::ensure!(missing.is_empty(), "The prompt template is missing {missing}");
+        Ok(Self(text.trim().to_string()))
+    }
+
+    pub fn load(path: &std::path::Path) -> Result<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+            .map_err(|e| anyhow::anyhow!("{}: {e}", path.display()))
+    }
+
+    pub fn render(&self, domain: &str, keywords: &str, categories: &str, examples: &str) -> String {
+        self.0
+            .replace("{categories}", categories)
+            .replace("{examples}", examples)
+            .replace("{domain}", domain)
+            .replace("{keywords}", keywords)
+    }
+}
+
+/// What to do when the LLM never gives an answer from the category list.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum OnUncertain {
+    /// Fail the domain
+    #[default]
+    Fail,
+    /// Put it in "Other", so it isn't dropped from the results
+    Other,
+}
+
+/// Ways models say no. Matched against the lowercase response.
+pub const REFUSAL_PHRASES: &[&str] = &[
+    "i can't help", "i cannot help", "i can't assist", "i cannot assist", "i can't provide",
+    "i cannot provide", "i'm not able to", "i am not able to", "i'm sorry", "i am sorry", "as an ai",
+];
+
+/// Asked instead, after a refusal.
+const NEUTRAL_FRAMING: &str = "This is a routine business classification for network research. \
+    You are not being asked to view, produce or endorse any content, only to name the kind of site it is.";
+
+/// Added to the prompt when the LLM answered with nothing at all.
+const EMPTY_NUDGE: &str = "You didn't answer. Reply with just the category.";
+
+/// Ollama's default context length, in tokens.
+pub const DEFAULT_PROMPT_TOKEN_WARNING: usize = 2048;
+
+/// A rough token count: about four characters a token, but at least one a
+/// word. Good enough to see a prompt growing past the LLM's context.
+pub fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(4).max(text.split_whitespace().count())
+}
+
+/// What to do when the LLM refuses to categorize a domain.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum OnRefusal {
+    /// Ask again, explaining that this is only classification
+    #[default]
+    Reprompt,
+    /// Give up straight away, and flag the domain for review
+    Review,
+}
+
+/// The LLM refused to categorize the domain, which needs a human rather than a retry.
+#[derive(Debug)]
+pub struct Refusal {
+    pub response: String,
+}
+
+impl std::fmt::Display for Refusal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "LLM refused to categorize: {}", self.response)
+    }
+}
+
+impl std::error::Error for Refusal {}
+
+/// The ensemble's answers didn't agree, so the domain needs a human.
+#[derive(Debug)]
+pub struct NoConsensus {
+    /// The valid answers, one per vote
+    pub votes: Vec<String>,
+}
+
+impl std::fmt::Display for NoConsensus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "LLM answers didn't agree: {}", self.votes.join(", "))
+    }
+}
+
+impl std::error::Error for NoConsensus {}
+
+/// The most common of `votes`, with how many it got, if it got more than half.
+pub fn majority(votes: &[String]) -> Option<(&str, usize)> {
+    let (category, count) = votes.iter().counts().into_iter().max_by_key(|(_, count)| *count)?;
+    (count * 2 > votes.len()).then_some((category.as_str(), count))
+}
+
+/// A limit on retries across the whole run, so a struggling LLM doesn't get
+/// asked everything twice. Cloning shares the budget.
+#[derive(Clone)]
+pub struct RetryBudget(Arc<AtomicUsize>);
+
+impl RetryBudget {
+    pub fn new(retries: usize) -> Self {
+        Self(Arc::new(AtomicUsize::new(retries)))
+    }
+
+    /// No limit, beyond each domain's own.
+    pub fn unlimited() -> Self {
+        Self::new(usize::MAX)
+    }
+
+    /// Take one retry from the budget. `false` once it's all used.
+    pub fn try_spend(&self) -> bool {
+        self.0.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |left| left.checked_sub(1)).is_ok()
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// The category given to each page content hash, and the domain it came from.
+/// Clones share the cache.
+#[derive(Clone, Default)]
+pub struct ContentCache(Arc<Mutex<HashMap<u64, (String, String)>>>);
+
+impl ContentCache {
+    /// The `(domain, category)` already given to this content, if any.
+    pub fn get(&self, hash: u64) -> Option<(String, String)> {
+        self.0.lock().unwrap().get(&hash).cloned()
+    }
+
+    /// Remember a category. The first domain with the content is kept.
+    pub fn insert(&self, hash: u64, domain: &str, category: &str) {
+        self.0.lock().unwrap().entry(hash).or_insert_with(|| (domain.to_string(), category.to_string()));
+    }
+}
+
+/// Asks the LLM to categorize domains.
+pub struct Categorizer<L> {
+    pub llm: L,
+    /// The categories the LLM may answer with
+    pub categories: Categories,
+    /// Worked examples, shown to the LLM before the real question
+    pub examples: Vec<Example>,
+    /// Instructions for pages in other languages, keyed by language code
+    /// (e.g. `de`). Like [`DEFAULT_INSTRUCTIONS`], but translated. The answer
+    /// still has to be one of the categories.
+    pub templates: HashMap<String, String>,
+    /// How many times to re-ask when the LLM fails, or the answer isn't one of the categories
+    pub reprompts: usize,
+    /// How many times to ask again, with a nudge, when the answer is empty.
+    /// These don't use up `reprompts`.
+    pub empty_retries: usize,
+    /// Retries left for the whole run. Once it's empty, failures are final.
+    pub retry_budget: RetryBudget,
+    /// What happens once the reprompts are used up
+    pub on_uncertain: OnUncertain,
+    /// Lowercase phrases that mark a response as a refusal
+    pub refusal_phrases: Vec<String>,
+    pub on_refusal: OnRefusal,
+    /// If set, every prompt and response is sent here for auditing
+    pub audit: Option<Sender<AuditRecord>>,
+    /// If set, pages identical to one already categorized reuse its category
+    pub content_cache: Option<ContentCache>,
+    /// Replaces the whole prompt, including any language's template. `None` is
+    /// the usual wording.
+    pub prompt_template: Option<PromptTemplate>,
+    /// Ask this many times and take the majority answer, recording how many
+    /// agreed. Only useful with a temperature above zero. One turns it off.
+    pub ensemble: usize,
+    /// Warn about prompts estimated to be more tokens than this, since the
+    /// LLM may quietly drop whatever doesn't fit in its context
+    pub prompt_token_warning: Option<usize>,
+    /// Known categories for some domains, by lowercase domain. These are
+    /// used as they are, without scraping or asking the LLM.
+    pub overrides: HashMap<String, String>,
+    /// How long to wait before asking again after the LLM request itself
+    /// failed. Wrong answers are re-asked straight away.
+    pub backoff: Backoff,
+    /// When a domain can't be scraped, categorize it from its name instead
+    /// of failing it
+    pub name_fallback: bool,
+    /// If set, pages are given the category whose embedding is nearest
+    /// theirs, instead of asking the LLM
+    pub embeddings: Option<EmbeddingCategorizer>,
+}
+
+impl<L: Completion> Categorizer<L> {
+    pub fn new(llm: L) -> Self {
+        Self {
+            llm,
+            categories: Categories::default(),
+            examples: Vec::new(),
+            templates: HashMap::new(),
+            reprompts: 1,
+            empty_retries: 1,
+            retry_budget: RetryBudget::unlimited(),
+            on_uncertain: OnUncertain::Fail,
+            refusal_phrases: REFUSAL_PHRASES.iter().map(|p| p.to_string()).collect(),
+            on_refusal: OnRefusal::Reprompt,
+            audit: None,
+            content_cache: None,
+            prompt_template: None,
+            ensemble: 1,
+            prompt_token_warning: Some(DEFAULT_PROMPT_TOKEN_WARNING),
+            overrides: HashMap::new(),
+            backoff: Backoff::default(),
+            name_fallback: false,
+            embeddings: None,
+        }
+    }
+
+    /// Assemble the prompt: instructions (in the page's language if there's a
+    /// template for it), the category list, any examples, then the domain itself.
+    fn prompt(&self, domain: &str, text: &str, language: Option<&str>) -> String {
+        if let Some(template) = &self.prompt_template {
+            return template.render(domain, text, &self.categories.category_prompt(), &self.examples_prompt());
+        }
+        let instructions = language
+            .and_then(|language| self.templates.get(&language.to_lowercase()))
+            .map(String::as_str)
+            .unwrap_or(DEFAULT_INSTRUCTIONS);
+        let mut prompt = instructions.replace("{categories}", &self.categories.category_prompt());
+        if !self.examples.is_empty() {
+            prompt.push_str("\n\nHere are some examples:\n");
+            prompt.push_str(&self.examples_prompt());
+            prompt.push('\n');
+        } else {
+            prompt.push(' ');
+        }
+        prompt.push_str(&format!("The domain is: {domain}. Here are some items from the website: {text}"));
+        prompt
+    }
+
+    /// The worked examples, one question and answer per line.
+    fn examples_prompt(&self) -> String {
+        self.examples
+            .iter()
+            .map(|example| format!(
+                "The domain is: {}. Here are some items from the website: {}\nCategory: {}\n",
+                example.domain, example.keywords, example.category
+            ))
+            .collect()
+    }
+
+    /// The result for a domain with an override, if it has one.
+    pub fn overridden(&self, domain: &str) -> Option<Domain> {
+        self.overrides.get(domain).map(|category| Self::result(domain, category))
+    }
+
+    /// Log the prompt's estimated size, warning if it's over
+    /// `prompt_token_warning`. Returns whether it was.
+    fn check_prompt_size(&self, domain: &str, prompt: &str) -> bool {
+        let tokens = estimate_tokens(prompt);
+        tracing::debug!(domain, tokens, "Prompt size");
+        let too_big = self.prompt_token_warning.is_some_and(|limit| tokens > limit);
+        if too_big {
+            tracing::warn!(domain, tokens, "Prompt is about {tokens} tokens, which may not fit; try a lower max_words");
+        }
+        too_big
+    }
+
+    /// Does the response look like the model declining to answer?
+    pub fn is_refusal(&self, response: &str) -> bool {
+        let response = response.to_lowercase();
+        self.refusal_phrases.iter().any(|phrase| response.contains(phrase.as_str()))
+    }
+
+    pub async fn categorize_domain(&self, domain: &str, text: &str) -> Result<Domain> {
+        self.categorize_domain_in(domain, text, None).await
+    }
+
+    /// Categorize a domain whose page is in `language`, using that language's
+    /// template if there is one. With an ensemble, the majority answer wins;
+    /// without a majority it's a [`NoConsensus`] error.
+    pub async fn categorize_domain_in(&self, domain: &str, text: &str, language: Option<&str>) -> Result<Domain> {
+        if let Some(embeddings) = &self.embeddings {
+            let (category, similarity) = embeddings.nearest(&self.llm.embed(text).await?)?;
+            tracing::debug!(domain, category, similarity, "Nearest category");
+            return Ok(Self::result(domain, category));
+        }
+        if self.ensemble <= 1 {
+            return self.categorize_once(domain, text, language).await;
+        }
+        let attempts = (0..self.ensemble).map(|_| self.categorize_once(domain, text, language));
+        let mut votes = Vec::new();
+        let mut error = None;
+        for attempt in join_all(attempts).await {
+            match attempt {
+                Ok(result) => votes.push(result.category),
+                Err(e) => error = error.or(Some(e)),
+            }
+        }
+        // Nothing valid came back, so there was nothing to vote on
+        if votes.is_empty() {
+            return Err(error.unwrap_or_else(|| anyhow::anyhow!("No ensemble answers")));
+        }
+        let Some((category, count)) = majority(&votes) else {
+            tracing::debug!(domain, ?votes, "No majority");
+            return Err(NoConsensus { votes }.into());
+        };
+        tracing::debug!(domain, category, "{count} of {} answers agreed", votes.len());
+        let mut result = Self::result(domain, category);
+        result.agreement = Some((count, votes.len()));
+        Ok(result)
+    }
+
+    /// Categorize a domain from its name alone ("cloudbank.io"), for when
+    /// there's no website to go on. The result is marked `from_name`.
+    pub async fn categorize_name(&self, domain: &str) -> Result<Domain> {
+        let prompt = format!(
+            "{} The website couldn't be fetched, so go by the domain name alone. The domain is: {domain}.",
+            DEFAULT_INSTRUCTIONS.replace("{categories}", &self.categories.category_prompt()),
+        );
+        let mut result = self.ask(domain, prompt).await?;
+        result.from_name = true;
+        Ok(result)
+    }
+
+    /// Ask once, re-asking as configured until there's a listed answer.
+    async fn categorize_once(&self, domain: &str, text: &str, language: Option<&str>) -> Result<Domain> {
+        let initial_prompt = self.prompt(domain, text, language);
+        self.check_prompt_size(domain, &initial_prompt);
+        self.ask(domain, initial_prompt).await
+    }
+
+    /// Ask `initial_prompt`, re-asking after failures, refusals and answers
+    /// that aren't in the list.
+    async fn ask(&self, domain: &str, initial_prompt: String) -> Result<Domain> {
+        let mut prompt = initial_prompt.clone();
+        let mut failed = None;
+        let mut refused = None;
+        for attempt in 0..=self.reprompts {
+            // Every try after the first comes out of the shared budget
+            if attempt > 0 && !self.retry_budget.try_spend() {
+                tracing::debug!(domain, "Retry budget used up, not asking again");
+                break;
+            }
+            let response = match self.complete_nonempty(domain, &prompt).await {
+                Ok(response) => response,
+                Err(e) => {
+                    tracing::debug!(domain, "LLM request failed: {e}");
+                    failed = Some(e);
+                    if attempt < self.reprompts {
+                        self.backoff.wait(attempt as u32 + 1).await;
+                    }
+                    continue;
+                }
+            };
+            failed = None;
+            refused = None;
+            let category = response.trim().to_string();
+            let canonical = self.categories.match_response(&category);
+            self.record_audit(domain, &prompt, &response, &category, canonical.is_some()).await;
+
+            if let Some(canonical) = canonical {
+                // Write the list's spelling, not whatever casing the LLM used
+                return Ok(Self::result(domain, canonical));
+            }
+
+            if self.is_refusal(&category) {
+                tracing::debug!(domain, "LLM refused: {category}");
+                if self.on_refusal == OnRefusal::Review {
+                    return Err(Refusal { response: category }.into());
+                }
+                prompt = format!("{NEUTRAL_FRAMING}\n\n{initial_prompt}");
+                refused = Some(category);
+                continue;
+            }
+
+            // Tell the LLM what it did wrong, and ask again
+            prompt = format!("{initial_prompt}\n\nYou answered \"{category}\". That wasn't in the list. \
+                Choose exactly one of: {}.", self.categories.keywords());
+        }
+
+        // An LLM that isn't answering isn't the same as an uncertain one
+        if let Some(e) = failed {
+            return Err(e);
+        }
+        if let Some(response) = refused {
+            return Err(Refusal { response }.into());
+        }
+        match self.on_uncertain {
+            OnUncertain::Fail => anyhow::bail!("LLM didn't answer with a category from the list"),
+            OnUncertain::Other => {
+                tracing::debug!(domain, "No valid answer, using Other");
+                Ok(Self::result(domain, self.categories.resolve_category("Other").unwrap_or("Other")))
+            }
+        }
+    }
+
+    /// Ask the LLM, asking again (up to `empty_retries` times) if nothing
+    /// comes back but whitespace. Models sometimes stop straight away.
+    async fn complete_nonempty(&self, domain: &str, prompt: &str) -> Result<String> {
+        let mut response = self.llm.complete(prompt).await?;
+        for _ in 0..self.empty_retries {
+            if !response.trim().is_empty() || !self.retry_budget.try_spend() {
+                break;
+            }
+            tracing::debug!(domain, "Empty response, asking again");
+            response = self.llm.complete(&format!("{prompt}\n\n{EMPTY_NUDGE}")).await?;
+        }
+        Ok(response)
+    }
+
+    fn result(domain: &str, category: &str) -> Domain {
+        Domain { domain: domain.to_string(), category: category.to_string(), ..Default::default() }
+    }
+
+    async fn record_audit(&self, domain: &str, prompt: &str, response: &str, category: &str, accepted: bool) {
+        if let Some(audit) = &self.audit {
+            let record = AuditRecord {
+                domain: domain.to_string(),
+                prompt: prompt.to_string(),
+                response: response.to_string(),
+                category: Some(category.to_string()),
+                accepted,
+            };
+            let _ = audit.send(record).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logging::LogFormat;
+    use crate::test_support::{http_response, LogBuffer, MockLlm, TestServer};
+
+    #[tokio::test]
+    async fn test_one_audit_record_per_domain() {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(8);
+        let mut categorizer = Categorizer::new(MockLlm::new(["Gaming", "News"]));
+        categorizer.audit = Some(tx);
+        categorizer.categorize_domain("games.example", "play games online").await.unwrap();
+        categorizer.categorize_domain("news.example", "daily headlines").await.unwrap();
+        drop(categorizer);
+
+        let mut records = Vec::new();
+        while let Some(record) = rx.recv().await {
+            records.push(serde_json::to_value(record).unwrap());
+        }
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0]["domain"], "games.example");
+        assert!(records[0]["prompt"].as_str().unwrap().contains("play games online"));
+        assert_eq!(records[0]["response"], "Gaming");
+        assert_eq!(records[0]["category"], "Gaming");
+        assert_eq!(records[0]["accepted"], true);
+        assert_eq!(records[1]["domain"], "news.example");
+    }
+
+    #[test]
+    fn test_default_llm_build() {
+        let config = LlmConfig::builder().build().unwrap();
+        assert_eq!(config.endpoint, "http://localhost:11434/api/generate");
+        assert_eq!(config.model, "llama3.1");
+        assert_eq!(config.temperature, None);
+    }
+
+    #[test]
+    fn test_customized_llm_build() {
+        let config = LlmConfig::builder()
+            .endpoint("http://gpu-box:11434/api/generate")
+            .model("mistral")
+            .temperature(0.2)
+            .build()
+            .unwrap();
+        assert_eq!(config.endpoint, "http://gpu-box:11434/api/generate");
+        assert_eq!(config.model, "mistral");
+        assert_eq!(config.temperature, Some(0.2));
+
+        assert!(LlmConfig::builder().model(" ").build().is_err());
+        assert!(LlmConfig::builder().endpoint("not a url").build().is_err());
+        assert!(LlmConfig::builder().temperature(-1.0).build().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_multi_word_category_is_accepted() {
+        let categorizer = Categorizer::new(MockLlm::new(["Media Entertainment", "Real Estate.", "Gaming - an online arcade"]));
+        let domain = categorizer.categorize_domain("films.example", "films trailers cinema").await.unwrap();
+        assert_eq!(domain.category, "Media/Entertainment");
+        let domain = categorizer.categorize_domain("homes.example", "houses for sale").await.unwrap();
+        assert_eq!(domain.category, "Real Estate");
+        let domain = categorizer.categorize_domain("games.example", "play games online").await.unwrap();
+        assert_eq!(domain.category, "Gaming");
+    }
+
+    #[tokio::test]
+    async fn test_empty_response_is_retried() {
+        let mut categorizer = Categorizer::new(MockLlm::new(["  \n", "Gaming"]));
+        categorizer.reprompts = 0;
+        let domain = categorizer.categorize_domain("games.example", "play games online").await.unwrap();
+        assert_eq!(domain.category, "Gaming");
+        let prompts = categorizer.llm.prompts.lock().unwrap().clone();
+        assert_eq!(prompts.len(), 2);
+        assert!(prompts[1].ends_with(EMPTY_NUDGE));
+
+        // With the retries off, an empty answer is just a wrong one
+        let mut categorizer = Categorizer::new(MockLlm::new(["", "Gaming"]));
+        categorizer.reprompts = 0;
+        categorizer.empty_retries = 0;
+        assert!(categorizer.categorize_domain("games.example", "play games online").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_rambling_response_is_rejected() {
+        let llm = MockLlm::new(["This website appears to be about games and news.", "I think it's Gaming"]);
+        let mut categorizer = Categorizer::new(llm);
+        categorizer.reprompts = 1;
+        assert!(categorizer.categorize_domain("games.example", "play games online").await.is_err());
+        // Both were asked, and both rejected
+        assert_eq!(categorizer.llm.prompts.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_ensemble_takes_the_majority() {
+        let mut categorizer = Categorizer::new(MockLlm::new(["Gaming", "Other", "Gaming"]));
+        categorizer.ensemble = 3;
+        let domain = categorizer.categorize_domain("games.example", "play games online").await.unwrap();
+        assert_eq!(domain.category, "Gaming");
+        assert_eq!(domain.agreement, Some((2, 3)));
+
+        // A three-way split has no majority, so it goes to review
+        let mut categorizer = Categorizer::new(MockLlm::new(["Gaming", "Other", "News"]));
+        categorizer.ensemble = 3;
+        categorizer.reprompts = 0;
+        let err = categorizer.categorize_domain("games.example", "play games online").await.unwrap_err();
+        assert_eq!(err.downcast_ref::<NoConsensus>().unwrap().votes.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_custom_prompt_template_is_filled_in() {
+        let mut categorizer = Categorizer::new(MockLlm::new(["Gaming"]));
+        categorizer.categories = Categories::parse("Gaming\nNews\n");
+        categorizer.prompt_template = Some(PromptTemplate::parse("Site {domain} says: {keywords}. {categories} One word.").unwrap());
+        categorizer.categorize_domain("games.example", "play games online").await.unwrap();
+        let prompts = categorizer.llm.prompts.lock().unwrap().clone();
+        assert_eq!(prompts[0], "Site games.example says: play games online. Choose exactly one of these categories: Gaming, News. One word.");
+
+        let err = PromptTemplate::parse("Categorize {domain}: {keywords}").unwrap_err();
+        assert!(err.to_string().contains("{categories}"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn test_reprompt_after_invalid_category() {
+        let categorizer = Categorizer::new(MockLlm::new(["Videogames", "Gaming"]));
+        let domain = categorizer.categorize_domain("games.example", "play games online").await.unwrap();
+        assert_eq!(domain.category, "Gaming");
+
+        let prompts = categorizer.llm.prompts.lock().unwrap();
+        assert_eq!(prompts.len(), 2);
+        assert!(prompts[1].contains("You answered \"Videogames\". That wasn't in the list."));
+    }
+
+    #[test]
+    fn test_examples_come_before_the_task() {
+        let mut categorizer = Categorizer::new(MockLlm::new(["Gaming"]));
+        let csv = "domain,keywords,category\nsteam.example,games store play,Gaming\n";
+        categorizer.examples = crate::categories::parse_examples(csv.as_bytes(), &categorizer.categories).unwrap();
+
+        let prompt = categorizer.prompt("bbc.example", "news weather sport", None);
+        let example = prompt.find("The domain is: steam.example. Here are some items from the website: games store play\nCategory: Gaming").unwrap();
+        let task = prompt.find("The domain is: bbc.example").unwrap();
+        assert!(example < task);
+    }
+
+    #[tokio::test]
+    async fn test_category_is_canonicalized() {
+        let categorizer = Categorizer::new(MockLlm::new([" banking/finance\n"]));
+        let domain = categorizer.categorize_domain("bank.example", "loans savings accounts").await.unwrap();
+        assert_eq!(domain.category, "Banking/Finance");
+        assert_eq!(categorizer.llm.prompts.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_uncertain_answer_becomes_other() {
+        let mut categorizer = Categorizer::new(MockLlm::new(["I'm not sure, maybe a blog?"]));
+        categorizer.on_uncertain = OnUncertain::Other;
+        let domain = categorizer.categorize_domain("blog.example", "thoughts posts archive").await.unwrap();
+        assert_eq!(domain.category, "Other");
+        assert_eq!(categorizer.llm.prompts.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_retry_budget_is_shared() {
+        let mut categorizer = Categorizer::new(MockLlm::new(["Blogging"]));
+        categorizer.reprompts = 3;
+        categorizer.retry_budget = RetryBudget::new(2);
+
+        // The first domain uses up the budget...
+        assert!(categorizer.categorize_domain("a.example", "posts archive").await.is_err());
+        assert_eq!(categorizer.llm.prompts.lock().unwrap().len(), 3);
+        assert_eq!(categorizer.retry_budget.remaining(), 0);
+
+        // ...so the next one is only asked once
+        assert!(categorizer.categorize_domain("b.example", "posts archive").await.is_err());
+        assert_eq!(categorizer.llm.prompts.lock().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn test_structured_replies() {
+        let judgement: Judgement = parse_structured(r#" {"category": "Gaming", "confidence": 0.9, "reason": "Sells games"} "#).unwrap();
+        assert_eq!(judgement.category, "Gaming");
+        assert_eq!(judgement.confidence, Some(0.9));
+        assert_eq!(judgement.reason.as_deref(), Some("Sells games"));
+
+        // Missing optional fields are fine, a missing category or broken JSON isn't
+        assert!(parse_structured::<Judgement>(r#"{"category": "News"}"#).is_ok());
+        assert!(parse_structured::<Judgement>(r#"{"confidence": 0.2}"#).is_err());
+        assert!(parse_structured::<Judgement>(r#"{"category": "News", "confidence": "#).is_err());
+        assert!(parse_structured::<Judgement>("News").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_structured_completion() {
+        let server = TestServer::start(|_| {
+            http_response(200, &[], r#"{"response": "{\"category\": \"Gaming\", \"confidence\": 0.75}", "done": true}"#)
+        }).await;
+        let config = LlmConfig::builder().endpoint(format!("http://{}/api/generate", server.domain())).build().unwrap();
+        let judgement: Judgement = llm_structured(&config, "Categorize steam.example", json!("json")).await.unwrap();
+        assert_eq!(judgement, Judgement { category: "Gaming".to_string(), confidence: Some(0.75), reason: None });
+    }
+
+    #[tokio::test]
+    async fn test_embedding() {
+        let server = TestServer::start(|path| match path {
+            "/api/embeddings" => http_response(200, &[], r#"{"embedding": [0.5, -0.25, 1.0]}"#),
+            _ => http_response(404, &[], ""),
+        }).await;
+        let config = LlmConfig::builder()
+            .embedding_endpoint(format!("http://{}/api/embeddings", server.domain()))
+            .build()
+            .unwrap();
+        assert_eq!(config.embed("steam.example video games").await.unwrap(), vec![0.5, -0.25, 1.0]);
+
+        let config = LlmConfig::builder()
+            .embedding_endpoint(format!("http://{}/missing", server.domain()))
+            .build()
+            .unwrap();
+        assert!(config.embed("steam.example").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_request_sizes_are_traced() {
+        let stream = "{\"response\": \"\"}\n{\"response\": \"Gam\"}\n{\"response\": \"ing\", \"done\": true}\n";
+        let server = TestServer::start(move |_| http_response(200, &[], stream)).await;
+        let config = LlmConfig::builder().endpoint(format!("http://{}/api/generate", server.domain())).build().unwrap();
+
+        let buffer = LogBuffer::default();
+        let writer = buffer.clone();
+        let subscriber = crate::logging::subscriber(LogFormat::Json, Some("categorize::llm=debug"), move || writer.clone()).unwrap();
+        let response = {
+            let _guard = tracing::subscriber::set_default(subscriber);
+            llm_completion(&config, "Categorize steam.example").await.unwrap()
+        };
+        assert_eq!(response, "Gaming");
+
+        let output = buffer.contents();
+        let event: serde_json::Value = output
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .find(|record| record["fields"]["message"] == "LLM request")
+            .unwrap_or_else(|| panic!("no LLM request event in {output}"));
+        let fields = &event["fields"];
+        assert_eq!(fields["prompt_len"], 24);
+        assert_eq!(fields["response_len"], 6);
+        assert_eq!(fields["chunks"], 3);
+        assert!(fields["first_token_ms"].is_u64() && fields["total_ms"].is_u64(), "{fields}");
+    }
+
+    #[test]
+    fn test_oversize_prompt_warns() {
+        let mut categorizer = Categorizer::new(MockLlm::new(["News"]));
+        categorizer.prompt_token_warning = Some(200);
+        let small = categorizer.prompt("example.com", "news weather sport", None);
+        let large = categorizer.prompt("example.com", &"keyword ".repeat(300), None);
+        assert!(estimate_tokens(&large) > 300);
+
+        let buffer = LogBuffer::default();
+        let writer = buffer.clone();
+        let subscriber = crate::logging::subscriber(LogFormat::Json, Some("warn"), move || writer.clone()).unwrap();
+        tracing::subscriber::with_default(subscriber, || {
+            assert!(!categorizer.check_prompt_size("small.example", &small));
+            assert!(categorizer.check_prompt_size("large.example", &large));
+        });
+        let output = buffer.contents();
+        assert_eq!(output.lines().count(), 1);
+        assert!(output.contains("large.example") && output.contains("max_words"), "{output}");
+    }
+
+    #[tokio::test]
+    async fn test_refusal_is_reprompted_neutrally() {
+        let categorizer = Categorizer::new(MockLlm::new(["I'm sorry, but I can't help with that.", "Adult"]));
+        let domain = categorizer.categorize_domain("adult.example", "explicit videos").await.unwrap();
+        assert_eq!(domain.category, "Adult");
+        let prompts = categorizer.llm.prompts.lock().unwrap();
+        assert!(prompts[1].starts_with(NEUTRAL_FRAMING));
+    }
+
+    #[tokio::test]
+    async fn test_refusal_goes_to_review() {
+        let mut categorizer = Categorizer::new(MockLlm::new(["Sorry, that's not something I do."]));
+        categorizer.refusal_phrases = vec!["not something i do".to_string()];
+        categorizer.on_refusal = OnRefusal::Review;
+        let err = categorizer.categorize_domain("adult.example", "explicit videos").await.unwrap_err();
+        assert!(err.is::<Refusal>());
+        assert_eq!(categorizer.llm.prompts.lock().unwrap().len(), 1);
+
+        // Refusals are still refusals after the reprompts run out, even with a fallback
+        categorizer.on_refusal = OnRefusal::Reprompt;
+        categorizer.on_uncertain = OnUncertain::Other;
+        let err = categorizer.categorize_domain("adult.example", "explicit videos").await.unwrap_err();
+        assert!(err.is::<Refusal>());
+    }
+
+    #[tokio::test]
+    async fn test_gives_up_after_reprompts() {
+        let mut categorizer = Categorizer::new(MockLlm::new(["Videogames"]));
+        categorizer.reprompts = 2;
+        assert!(categorizer.categorize_domain("games.example", "play games online").await.is_err());
+        assert_eq!(categorizer.llm.prompts.lock().unwrap().len(), 3);
+    }
+}
@@ -0,0 +1,72 @@
+//! Log output setup. Logs are human-readable by default, or one JSON object
+//! per line for feeding into a log pipeline.
+
+use anyhow::Result;
+use clap::ValueEnum;
+use tracing::Subscriber;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::EnvFilter;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    /// Human-readable lines
+    Pretty,
+    /// One JSON record per line
+    Json,
+}
+
+/// Build the filter: an explicit level wins, then `RUST_LOG`, then `info`.
+fn filter(level: Option<&str>) -> Result<EnvFilter> {
+    let filter = match level {
+        Some(level) => EnvFilter::try_new(level)?,
+        None => EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+    };
+    Ok(filter)
+}
+
+/// Build a subscriber that writes to `writer` in the requested format.
+pub fn subscriber<W>(format: LogFormat, level: Option<&str>, writer: W) -> Result<Box<dyn Subscriber + Send + Sync>>
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter(level)?)
+        .with_writer(writer);
+    Ok(match format {
+        LogFormat::Pretty => Box::new(builder.finish()),
+        LogFormat::Json => Box::new(builder.json().finish()),
+    })
+}
+
+/// Install the global logger, writing to stdout.
+pub fn init_logging(format: LogFormat, level: Option<&str>) -> Result<()> {
+    tracing::subscriber::set_global_default(subscriber(format, level, std::io::stdout)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::LogBuffer;
+
+    #[test]
+    fn test_json_logs_are_parseable() {
+        let buffer = LogBuffer::default();
+        let writer = buffer.clone();
+        let subscriber = subscriber(LogFormat::Json, Some("info"), move || writer.clone()).unwrap();
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!(domain = "example.com", "Categorized");
+            tracing::debug!("Filtered out by the level");
+        });
+
+        let output = buffer.contents();
+        let records: Vec<serde_json::Value> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0]["level"], "INFO");
+        assert_eq!(records[0]["fields"]["domain"], "example.com");
+        assert_eq!(records[0]["fields"]["message"], "Categorized");
+    }
+}
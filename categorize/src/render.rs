@@ -0,0 +1,51 @@
+//! Rendering pages that only fill themselves in with JavaScript. There's no
+//! browser built in; implement [`Renderer`] (e.g. with a headless Chromium)
+//! and hand it to the scrape config.
+
+use std::future::Future;
+use std::sync::Arc;
+use anyhow::Result;
+use futures::future::{BoxFuture, FutureExt};
+
+/// Something that can load a page like a browser would.
+pub trait Renderer: Send + Sync {
+    /// Load `url` and return its HTML once the scripts have run.
+    fn render(&self, url: &str) -> impl Future<Output = Result<String>> + Send;
+}
+
+/// [`Renderer`] with the future boxed, so any renderer fits in one field.
+trait BoxedRenderer: Send + Sync {
+    fn render_boxed<'a>(&'a self, url: &'a str) -> BoxFuture<'a, Result<String>>;
+}
+
+impl<R: Renderer> BoxedRenderer for R {
+    fn render_boxed<'a>(&'a self, url: &'a str) -> BoxFuture<'a, Result<String>> {
+        self.render(url).boxed()
+    }
+}
+
+/// The renderer the scraper uses, if any. Without one (the default), pages
+/// are used exactly as they were fetched. Clones share the renderer.
+#[derive(Clone, Default)]
+pub struct Rendering(Option<Arc<dyn BoxedRenderer>>);
+
+impl Rendering {
+    /// No rendering: the fetched HTML passes straight through.
+    pub fn none() -> Self {
+        Self(None)
+    }
+
+    pub fn new(renderer: impl Renderer + 'static) -> Self {
+        Self(Some(Arc::new(renderer)))
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.0.is_some()
+    }
+
+    /// Render `url`, or `None` without a renderer.
+    pub async fn render(&self, url: &str) -> Option<Result<String>> {
+        let renderer = self.0.as_ref()?;
+        Some(renderer.render_boxed(url).await)
+    }
+}
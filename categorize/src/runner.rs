@@ -0,0 +1,324 @@
+//! Running many tasks with a bounded number in flight, and sharing the
+//! work between tasks for the same domain.
+
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use futures::future::{BoxFuture, FutureExt, Shared};
+use tokio::task::JoinSet;
+
+/// Used when no concurrency is configured.
+pub const DEFAULT_CONCURRENCY: usize = 32;
+/// Used when no per-domain time limit is configured, in seconds.
+pub const DEFAULT_DOMAIN_TIMEOUT_SECS: u64 = 120;
+
+/// Run `task` for every item, with at most `limit` running at once. A new
+/// task starts as soon as any running one finishes, so one slow task doesn't
+/// hold up the rest.
+///
+/// If `shutdown` completes first, every task still in flight is aborted.
+/// Returns `true` if all the items were processed.
+pub async fn run_bounded<T, F, Fut>(
+    items: impl IntoIterator<Item = T>,
+    limit: usize,
+    mut task: F,
+    shutdown: impl Future<Output = ()>,
+) -> bool
+where
+    F: FnMut(T) -> Fut,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let mut items = items.into_iter();
+    let mut in_flight = JoinSet::new();
+    tokio::pin!(shutdown);
+
+    loop {
+        // Top up to the limit
+        while in_flight.len() < limit.max(1) {
+            let Some(item) = items.next() else { break };
+            in_flight.spawn(task(item));
+        }
+        if in_flight.is_empty() {
+            return true;
+        }
+
+        tokio::select! {
+            _ = in_flight.join_next() => {
+                // Others may have finished at the same time
+                while in_flight.try_join_next().is_some() {}
+            }
+            _ = &mut shutdown => {
+                in_flight.abort_all();
+                while in_flight.join_next().await.is_some() {}
+                return false;
+            }
+        }
+    }
+}
+
+/// The same run, and the spellings of the key that have joined it.
+type InFlight<T> = (Shared<BoxFuture<'static, T>>, HashSet<String>);
+
+/// Lets tasks that want the same domain at the same time share one run.
+/// Domains are compared by [`domain_key`], so `www.Example.com` and
+/// `example.com` share. Clones share the in-flight map.
+pub struct Coalesce<T: Clone>(Arc<Mutex<HashMap<String, InFlight<T>>>>);
+
+/// The ASCII form of a domain, for URLs and DNS: percent-encoding is undone,
+/// and Unicode labels become punycode (`bücher.example` is
+/// `xn--bcher-kva.example`). Domains that are already ASCII come back as they are.
+pub fn ascii_domain(domain: &str) -> anyhow::Result<String> {
+    let decoded = percent_encoding::percent_decode_str(domain).decode_utf8()?;
+    idna::domain_to_ascii(&decoded).map_err(|e| anyhow::anyhow!("Invalid domain {domain}: {e}"))
+}
+
+/// What [`Coalesce`] compares domains by: lowercase ASCII (punycode for
+/// international names), without a `www.` or a trailing dot.
+pub fn domain_key(domain: &str) -> String {
+    let domain = domain.trim().trim_end_matches('.').to_lowercase();
+    let domain = ascii_domain(&domain).unwrap_or(domain);
+    // Not from `www.com`, which is a domain in its own right
+    match domain.strip_prefix("www.") {
+        Some(bare) if bare.contains('.') => bare.to_string(),
+        _ => domain,
+    }
+}
+
+impl<T: Clone> Clone for Coalesce<T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<T: Clone> Default for Coalesce<T> {
+    fn default() -> Self {
+        Self(Arc::default())
+    }
+}
+
+impl<T: Clone + Send + Sync + 'static> Coalesce<T> {
+    /// The result of `make()` for `domain`. If another call for the same
+    /// domain is already running, its result is shared instead. Also returns
+    /// whether this is the first call for this exact spelling of it, which
+    /// should be the one to record the result.
+    pub async fn run<F>(&self, domain: &str, make: impl FnOnce() -> F) -> (T, bool)
+    where
+        F: Future<Output = T> + Send + 'static,
+    {
+        let key = domain_key(domain);
+        let (shared, leader, first) = {
+            let mut in_flight = self.0.lock().unwrap();
+            match in_flight.get_mut(&key) {
+                Some((shared, spellings)) => (shared.clone(), None, spellings.insert(domain.to_string())),
+                None => {
+                    let shared = make().boxed().shared();
+                    in_flight.insert(key.clone(), (shared.clone(), HashSet::from([domain.to_string()])));
+                    let leave = Leave { in_flight: &self.0, key, shared: shared.clone() };
+                    (shared, Some(leave), true)
+                }
+            }
+        };
+        let output = shared.await;
+        drop(leader);
+        (output, first)
+    }
+}
+
+/// Held by the call that started a run. When it's done with, whether the run
+/// finished or the call was dropped first (it timed out, or the run was
+/// stopped), the run is taken out of the map. Later calls then start a run
+/// of their own, rather than waiting on one nobody may be driving. Calls
+/// already waiting keep their share of it.
+struct Leave<'a, T: Clone> {
+    in_flight: &'a Mutex<HashMap<String, InFlight<T>>>,
+    key: String,
+    shared: Shared<BoxFuture<'static, T>>,
+}
+
+impl<T: Clone> Drop for Leave<'_, T> {
+    fn drop(&mut self) {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        // Only this run's entry
+        if in_flight.get(&self.key).is_some_and(|(shared, _)| shared.ptr_eq(&self.shared)) {
+            in_flight.remove(&self.key);
+        }
+    }
+}
+
+/// Give `fut` at most `limit` to finish, returning `None` if it doesn't.
+/// Logs a warning when 80% of the time has gone, so slow domains are visible
+/// before they're cut off.
+pub async fn with_deadline<F: Future>(domain: &str, limit: Duration, fut: F) -> Option<F::Output> {
+    tokio::pin!(fut);
+    let warn_at = limit.mul_f32(0.8);
+    tokio::select! {
+        output = &mut fut => return Some(output),
+        _ = tokio::time::sleep(warn_at) => {
+            tracing::warn!(domain, "Still working after {:?}, giving up at {:?}", warn_at, limit);
+        }
+    }
+    tokio::time::timeout(limit - warn_at, fut).await.ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[tokio::test(start_paused = true)]
+    async fn test_in_flight_stays_at_limit() {
+        let running = Arc::new(AtomicUsize::new(0));
+
+        // Check how many tasks are running every 10ms, between task starts
+        let observer = tokio::spawn({
+            let running = running.clone();
+            async move {
+                let mut samples = Vec::new();
+                tokio::time::sleep(Duration::from_millis(5)).await;
+                for _ in 0..20 {
+                    samples.push(running.load(Ordering::SeqCst));
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+                samples
+            }
+        });
+
+        // Every 4th task is slow. Batching would wait for it each time.
+        let start = tokio::time::Instant::now();
+        let done = run_bounded(0..40u64, 4, |i| {
+            let running = running.clone();
+            async move {
+                running.fetch_add(1, Ordering::SeqCst);
+                let ms = if i % 4 == 0 { 100 } else { 10 };
+                tokio::time::sleep(Duration::from_millis(ms)).await;
+                running.fetch_sub(1, Ordering::SeqCst);
+            }
+        }, std::future::pending()).await;
+
+        assert!(done);
+        // 10 batches of 100ms would take a second
+        assert!(start.elapsed() < Duration::from_millis(600));
+        // Until the items ran out, there were always 4 running
+        assert!(observer.await.unwrap().iter().all(|&n| n == 4));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_slow_domains_dont_block_fast_ones() {
+        let start = tokio::time::Instant::now();
+        let results = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let domains = ["slow1", "slow2", "fast1", "fast2", "fast3", "fast4"];
+
+        run_bounded(domains, 3, |domain| {
+            let results = results.clone();
+            async move {
+                let work = if domain.starts_with("slow") { 3600 } else { 1 };
+                let outcome = with_deadline(domain, Duration::from_secs(10), async {
+                    tokio::time::sleep(Duration::from_secs(work)).await;
+                }).await;
+                results.lock().unwrap().push((domain, outcome.is_some(), start.elapsed()));
+            }
+        }, std::future::pending()).await;
+
+        let results = results.lock().unwrap();
+        for (domain, finished, elapsed) in results.iter() {
+            if domain.starts_with("fast") {
+                assert!(finished);
+                assert!(*elapsed < Duration::from_secs(5), "{domain} took {elapsed:?}");
+            } else {
+                assert!(!finished, "{domain} should have timed out");
+                assert_eq!(*elapsed, Duration::from_secs(10));
+            }
+        }
+        assert_eq!(results.len(), 6);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_concurrent_calls_share_one_run() {
+        let coalesce = Coalesce::default();
+        let scrapes = Arc::new(AtomicUsize::new(0));
+        let scrape = || {
+            let scrapes = scrapes.clone();
+            async move {
+                scrapes.fetch_add(1, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                "News".to_string()
+            }
+        };
+
+        let (a, b) = tokio::join!(coalesce.run("example.com", scrape), coalesce.run("example.com", scrape));
+        assert_eq!(scrapes.load(Ordering::SeqCst), 1);
+        assert_eq!((a.0.as_str(), b.0.as_str()), ("News", "News"));
+        // Only one of them did the work
+        assert!(a.1 != b.1);
+
+        // Once it's finished, the next call runs again
+        coalesce.run("example.com", scrape).await;
+        assert_eq!(scrapes.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_aborted_leader_leaves_no_run_behind() {
+        let coalesce = Coalesce::default();
+        let started = Arc::new(tokio::sync::Notify::new());
+        let leader = tokio::spawn({
+            let coalesce = coalesce.clone();
+            let started = started.clone();
+            async move {
+                coalesce.run("example.com", || async move {
+                    started.notify_one();
+                    std::future::pending::<String>().await
+                }).await
+            }
+        });
+        started.notified().await;
+        leader.abort();
+        assert!(leader.await.unwrap_err().is_cancelled());
+
+        // The next call runs afresh, instead of waiting on the abandoned one
+        let next = tokio::time::timeout(Duration::from_secs(5), coalesce.run("example.com", || async { "News".to_string() }));
+        assert_eq!(next.await.expect("waited on the aborted run"), ("News".to_string(), true));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_www_and_bare_domains_share_one_run() {
+        let coalesce = Coalesce::default();
+        let scrapes = Arc::new(AtomicUsize::new(0));
+        let scrape = || {
+            let scrapes = scrapes.clone();
+            async move {
+                scrapes.fetch_add(1, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                "News".to_string()
+            }
+        };
+
+        let (bare, www, upper) = tokio::join!(
+            coalesce.run("example.com", scrape),
+            coalesce.run("www.example.com", scrape),
+            coalesce.run("Example.COM.", scrape),
+        );
+        assert_eq!(scrapes.load(Ordering::SeqCst), 1);
+        assert_eq!((bare.0.as_str(), www.0.as_str(), upper.0.as_str()), ("News", "News", "News"));
+        // Each spelling is its own row, so each records the result
+        assert!(bare.1 && www.1 && upper.1);
+
+        assert_eq!(domain_key("WWW.Bücher.example."), "xn--bcher-kva.example");
+        assert_eq!(domain_key("www.com"), "www.com");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_shutdown_aborts_in_flight() {
+        let finished = Arc::new(AtomicUsize::new(0));
+        let done = run_bounded(0..10, 4, |_| {
+            let finished = finished.clone();
+            async move {
+                tokio::time::sleep(Duration::from_secs(60)).await;
+                finished.fetch_add(1, Ordering::SeqCst);
+            }
+        }, tokio::time::sleep(Duration::from_secs(1))).await;
+
+        assert!(!done);
+        assert_eq!(finished.load(Ordering::SeqCst), 0);
+    }
+}
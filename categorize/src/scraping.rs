@@ -0,0 +1,1847 @@
+//! Fetching a domain's website, and turning it into a list of keywords.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::net::IpAddr;
+use std::ops::ControlFlow;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime};
+use anyhow::Result;
+use futures::future::join_all;
+use futures::StreamExt;
+use itertools::Itertools;
+use reqwest::header;
+use scraper::Html;
+use crate::backoff::Backoff;
+use crate::governor::Governor;
+use crate::render::Rendering;
+use crate::runner::ascii_domain;
+use crate::tokenize::{Stemmer, Tokenizer, Tokenizers};
+
+fn find_content(selector: &str, document: &Html, tokenizer: &dyn Tokenizer) -> Vec<String> {
+    let selector = scraper::Selector::parse(selector).unwrap();
+    let mut content = Vec::new();
+    for element in document.select(&selector) {
+        // Get all text elements matching the selector
+        let e: String = element.text().collect::<String>();
+
+        // Split into words (by default at whitespace, skipping short ones and
+        // lowercasing the rest)
+        let e: Vec<String> = tokenizer.tokens(&e);
+
+        if !e.is_empty() {
+            content.extend(e);
+        }
+    }
+
+    content
+}
+
+/// How the words are picked out of a page.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Extraction {
+    /// Titles, lists, headings and paragraphs, from anywhere on the page
+    #[default]
+    Selectors,
+    /// Find the block holding the main content (like a browser's reader view)
+    /// and only use that, plus the title. Falls back to selectors.
+    Readability,
+}
+
+/// Settings for fetching websites. The ones that go into the HTTP client
+/// (timeouts, headers, proxy, TLS and connection pooling) can only be set
+/// with [`ScrapeConfig::builder`].
+#[derive(Clone)]
+pub struct ScrapeConfig {
+    /// Read pages from `{fixtures}/{domain}.html` instead of the network.
+    /// This is for tests, so they don't depend on live websites.
+    pub fixtures: Option<PathBuf>,
+    /// Pages with fewer distinct keywords than this aren't worth categorizing
+    pub min_unique_words: usize,
+    /// Other pages to fetch alongside the homepage (e.g. `/about`), whose
+    /// words are merged in before ranking
+    pub extra_paths: Vec<String>,
+    /// At most this many of the `extra_paths` are fetched
+    pub max_extra_pages: usize,
+    /// How long to wait for a page
+    timeout: Duration,
+    /// How long to wait for the connection. Dead hosts fail here, so this can
+    /// be much shorter than `timeout`.
+    connect_timeout: Duration,
+    /// How many of the most common words to keep
+    pub max_words: usize,
+    /// Words that occur fewer times than this are dropped before ranking.
+    /// One-offs are often typos or tracking IDs. 1 keeps everything.
+    pub min_word_count: usize,
+    /// Lowercase phrases that mark a page as parked (e.g. "this domain is for sale")
+    pub parking_phrases: Vec<String>,
+    /// Parking services' hosts. A redirect to one of these (or a subdomain)
+    /// marks the domain as parked, without fetching the parking page.
+    parking_hosts: Vec<String>,
+    /// Extra headers sent with every request, as `(name, value)`
+    headers: Vec<(String, String)>,
+    /// Cookies sent with every request (e.g. to get past a consent gate), as `(name, value)`
+    cookies: Vec<(String, String)>,
+    /// Basic-auth credentials sent to every site, as `(username, password)`.
+    /// Only for runs over sites that all share a login.
+    pub basic_auth: Option<(String, String)>,
+    /// Basic-auth credentials for particular domains, as `(username, password)`.
+    /// These win over `basic_auth`.
+    pub domain_basic_auth: HashMap<String, (String, String)>,
+    /// Only categorize sites that have changed since this time. The homepage
+    /// is fetched with `If-Modified-Since`, and skipped if it answers 304 or
+    /// has an older `Last-Modified`; a sitemap whose newest `<lastmod>` is
+    /// older skips the site without fetching it. Sites that say neither are
+    /// always categorized.
+    pub since: Option<SystemTime>,
+    /// Send requests through this proxy (`http://`, `https://` or `socks5://`,
+    /// optionally with `user:password@`), instead of connecting directly
+    proxy: Option<String>,
+    /// How to find the words on each page
+    pub extraction: Extraction,
+    /// Lowercase names of response headers worth keeping (e.g. `server`).
+    /// For `set-cookie`, only the cookie names are kept.
+    pub signal_headers: Vec<String>,
+    /// Tell the LLM about the kept headers, along with the keywords
+    pub headers_in_prompt: bool,
+    /// Homepages whose `Content-Length` is below this are skipped without
+    /// being downloaded. Zero turns the check off.
+    pub min_content_length: u64,
+    /// Consulted before every request
+    pub governor: Governor,
+    /// Talk HTTP/2 straight away, without negotiating. Only for servers known to support it.
+    http2_prior_knowledge: bool,
+    /// How long an unused connection is kept open for reuse
+    pool_idle_timeout: Duration,
+    /// How many unused connections are kept open to each host
+    pool_max_idle_per_host: usize,
+    /// INSECURE: accept TLS 1.0 and invalid or self-signed certificates, so
+    /// sites with outdated TLS can still be scraped. Anyone on the path can
+    /// then read or change the pages. Off by default.
+    insecure_tls: bool,
+    /// TCP keepalive on every connection. `None` turns it off.
+    tcp_keepalive: Option<Duration>,
+    /// Renders homepages that look like JavaScript shells. Off by default.
+    pub renderer: Rendering,
+    /// Render every homepage, not just the ones that look like shells
+    pub render_all: bool,
+    /// How page text is split into words, by the page's language
+    pub tokenizers: Tokenizers,
+    /// How many more times to try a homepage that couldn't be connected to
+    /// or timed out
+    pub fetch_retries: usize,
+    /// How long to wait between those tries
+    pub backoff: Backoff,
+    /// The client, built on first use and then shared by every request, and
+    /// by clones. The fields it's built from are private, and only set by
+    /// the builder, so it can't go stale.
+    client: OnceLock<reqwest::Client>,
+}
+
+/// Registrar and parking-service boilerplate, used unless other phrases are configured.
+pub const PARKING_PHRASES: &[&str] = &[
+    "this domain is for sale",
+    "this domain may be for sale",
+    "buy this domain",
+    "domain is parked",
+    "parked free",
+    "this domain has been registered",
+    "is available for purchase",
+    "hugedomains",
+    "sedoparking",
+    "parkingcrew",
+];
+
+/// Where registrars send expired and parked domains, used unless other hosts are configured.
+pub const PARKING_HOSTS: &[&str] = &[
+    "sedoparking.com",
+    "bodis.com",
+    "parkingcrew.net",
+    "above.com",
+    "afternic.com",
+    "dan.com",
+    "hugedomains.com",
+    "parklogic.com",
+];
+
+/// Headers that often give away the platform (Shopify, WordPress, ...).
+pub const SIGNAL_HEADERS: &[&str] = &["server", "x-powered-by", "x-generator", "set-cookie"];
+
+/// Ask for English, so the LLM gets words it knows the categories in.
+pub const DEFAULT_ACCEPT_LANGUAGE: &str = "en-US,en;q=0.9";
+
+/// Pages that often describe what a site is about, for `--extra-pages`.
+pub const COMMON_EXTRA_PATHS: &[&str] = &["/about", "/products", "/services"];
+
+impl Default for ScrapeConfig {
+    fn default() -> Self {
+        Self {
+            fixtures: None,
+            min_unique_words: 5,
+            extra_paths: Vec::new(),
+            max_extra_pages: 3,
+            timeout: Duration::from_secs(30),
+            connect_timeout: Duration::from_secs(5),
+            max_words: 100,
+            min_word_count: 1,
+            parking_phrases: PARKING_PHRASES.iter().map(|p| p.to_string()).collect(),
+            parking_hosts: PARKING_HOSTS.iter().map(|h| h.to_string()).collect(),
+            headers: vec![("Accept-Language".to_string(), DEFAULT_ACCEPT_LANGUAGE.to_string())],
+            cookies: Vec::new(),
+            basic_auth: None,
+            domain_basic_auth: HashMap::new(),
+            since: None,
+            proxy: None,
+            extraction: Extraction::Selectors,
+            signal_headers: SIGNAL_HEADERS.iter().map(|h| h.to_string()).collect(),
+            headers_in_prompt: false,
+            min_content_length: 0,
+            governor: Governor::unlimited(),
+            http2_prior_knowledge: false,
+            // Most sites are only visited for a page or four, all at once, so
+            // there's little point keeping much open for long
+            pool_idle_timeout: Duration::from_secs(30),
+            pool_max_idle_per_host: 4,
+            insecure_tls: false,
+            tcp_keepalive: Some(Duration::from_secs(60)),
+            renderer: Rendering::none(),
+            render_all: false,
+            tokenizers: Tokenizers::default(),
+            fetch_retries: 0,
+            backoff: Backoff::default(),
+            client: OnceLock::new(),
+        }
+    }
+}
+
+impl ScrapeConfig {
+    pub fn builder() -> ScrapeConfigBuilder {
+        ScrapeConfigBuilder(Self::default())
+    }
+
+    /// The shared client, so connections (and DNS lookups) are reused across
+    /// requests instead of starting afresh for every domain.
+    fn client(&self) -> Result<reqwest::Client> {
+        if let Some(client) = self.client.get() {
+            return Ok(client.clone());
+        }
+        let client = build_client(self)?;
+        Ok(self.client.get_or_init(|| client).clone())
+    }
+
+    /// How long to wait for a page
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// The basic-auth credentials for `domain`, if there are any.
+    fn basic_auth_for(&self, domain: &str) -> Option<&(String, String)> {
+        self.domain_basic_auth.get(&domain.to_lowercase()).or(self.basic_auth.as_ref())
+    }
+}
+
+/// Builds a [`ScrapeConfig`], starting from the defaults and checking the result.
+pub struct ScrapeConfigBuilder(ScrapeConfig);
+
+impl ScrapeConfigBuilder {
+    pub fn fixtures(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.0.fixtures = Some(dir.into());
+        self
+    }
+
+    pub fn min_unique_words(mut self, min: usize) -> Self {
+        self.0.min_unique_words = min;
+        self
+    }
+
+    pub fn extra_paths<S: ToString>(mut self, paths: impl IntoIterator<Item = S>) -> Self {
+        self.0.extra_paths = paths.into_iter().map(|p| p.to_string()).collect();
+        self
+    }
+
+    pub fn max_extra_pages(mut self, max: usize) -> Self {
+        self.0.max_extra_pages = max;
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.0.timeout = timeout;
+        self
+    }
+
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.0.connect_timeout = timeout;
+        self
+    }
+
+    pub fn max_words(mut self, max: usize) -> Self {
+        self.0.max_words = max;
+        self
+    }
+
+    pub fn renderer(mut self, renderer: Rendering) -> Self {
+        self.0.renderer = renderer;
+        self
+    }
+
+    pub fn fetch_retries(mut self, retries: usize) -> Self {
+        self.0.fetch_retries = retries;
+        self
+    }
+
+    pub fn backoff(mut self, backoff: Backoff) -> Self {
+        self.0.backoff = backoff;
+        self
+    }
+
+    /// Split the text of pages in `language` with `tokenizer`.
+    pub fn tokenizer(mut self, language: &str, tokenizer: impl Tokenizer + 'static) -> Self {
+        self.0.tokenizers.insert(language, tokenizer);
+        self
+    }
+
+    /// Count the keywords of pages in `language` by their stem with `stemmer`.
+    pub fn stemmer(mut self, language: &str, stemmer: impl Stemmer + 'static) -> Self {
+        self.0.tokenizers.insert_stemmer(language, stemmer);
+        self
+    }
+
+    pub fn render_all(mut self, enabled: bool) -> Self {
+        self.0.render_all = enabled;
+        self
+    }
+
+    pub fn min_word_count(mut self, min: usize) -> Self {
+        self.0.min_word_count = min;
+        self
+    }
+
+    pub fn parking_phrases<S: ToString>(mut self, phrases: impl IntoIterator<Item = S>) -> Self {
+        self.0.parking_phrases = phrases.into_iter().map(|p| p.to_string().to_lowercase()).collect();
+        self
+    }
+
+    pub fn parking_hosts<S: ToString>(mut self, hosts: impl IntoIterator<Item = S>) -> Self {
+        self.0.parking_hosts = hosts.into_iter().map(|h| h.to_string().to_lowercase()).collect();
+        self
+    }
+
+    /// Send a header with every request, replacing any default of the same name.
+    pub fn header(mut self, name: impl ToString, value: impl ToString) -> Self {
+        let name = name.to_string();
+        self.0.headers.retain(|(existing, _)| !existing.eq_ignore_ascii_case(&name));
+        self.0.headers.push((name, value.to_string()));
+        self
+    }
+
+    pub fn cookie(mut self, name: impl ToString, value: impl ToString) -> Self {
+        self.0.cookies.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Log in to every site with basic auth.
+    pub fn basic_auth(mut self, username: impl ToString, password: impl ToString) -> Self {
+        self.0.basic_auth = Some((username.to_string(), password.to_string()));
+        self
+    }
+
+    /// Log in to `domain` with basic auth.
+    pub fn domain_basic_auth(mut self, domain: &str, username: impl ToString, password: impl ToString) -> Self {
+        self.0.domain_basic_auth.insert(domain.to_lowercase(), (username.to_string(), password.to_string()));
+        self
+    }
+
+    pub fn since(mut self, since: SystemTime) -> Self {
+        self.0.since = Some(since);
+        self
+    }
+
+    pub fn proxy(mut self, url: impl ToString) -> Self {
+        self.0.proxy = Some(url.to_string());
+        self
+    }
+
+    pub fn extraction(mut self, extraction: Extraction) -> Self {
+        self.0.extraction = extraction;
+        self
+    }
+
+    pub fn signal_headers<S: ToString>(mut self, headers: impl IntoIterator<Item = S>) -> Self {
+        self.0.signal_headers = headers.into_iter().map(|h| h.to_string().to_lowercase()).collect();
+        self
+    }
+
+    pub fn headers_in_prompt(mut self, enabled: bool) -> Self {
+        self.0.headers_in_prompt = enabled;
+        self
+    }
+
+    pub fn min_content_length(mut self, bytes: u64) -> Self {
+        self.0.min_content_length = bytes;
+        self
+    }
+
+    /// INSECURE: see [`ScrapeConfig::insecure_tls`].
+    pub fn insecure_tls(mut self, enabled: bool) -> Self {
+        self.0.insecure_tls = enabled;
+        self
+    }
+
+    pub fn http2_prior_knowledge(mut self, enabled: bool) -> Self {
+        self.0.http2_prior_knowledge = enabled;
+        self
+    }
+
+    pub fn pool_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.0.pool_idle_timeout = timeout;
+        self
+    }
+
+    pub fn pool_max_idle_per_host(mut self, max: usize) -> Self {
+        self.0.pool_max_idle_per_host = max;
+        self
+    }
+
+    pub fn tcp_keepalive(mut self, keepalive: Option<Duration>) -> Self {
+        self.0.tcp_keepalive = keepalive;
+        self
+    }
+
+    /// Share a request limit, e.g. with the LLM.
+    pub fn governor(mut self, governor: Governor) -> Self {
+        self.0.governor = governor;
+        self
+    }
+
+    pub fn build(self) -> Result<ScrapeConfig> {
+        let config = self.0;
+        anyhow::ensure!(config.max_words > 0, "max_words must be at least 1");
+        anyhow::ensure!(
+            config.min_unique_words <= config.max_words,
+            "min_unique_words ({}) can never be reached with max_words of {}",
+            config.min_unique_words, config.max_words
+        );
+        anyhow::ensure!(!config.timeout.is_zero(), "The scrape timeout can't be zero");
+        anyhow::ensure!(!config.connect_timeout.is_zero(), "The connect timeout can't be zero");
+        anyhow::ensure!(
+            config.extra_paths.iter().all(|p| p.starts_with('/')),
+            "Extra paths must start with a /"
+        );
+        // Catch bad headers now, rather than failing every domain
+        request_headers(&config)?;
+        let _ = config.client.set(build_client(&config)?);
+        Ok(config)
+    }
+}
+
+/// The headers sent with every request: the user agent, the configured
+/// headers, and the cookies.
+fn request_headers(config: &ScrapeConfig) -> Result<header::HeaderMap> {
+    // Build a header with a Firefox user agent
+    let mut headers = header::HeaderMap::new();
+    headers.insert(
+        header::USER_AGENT,
+        header::HeaderValue::from_static("Mozilla/5.0 (platform; rv:geckoversion) Gecko/geckotrail Firefox/firefoxversion")
+    );
+    for (name, value) in config.headers.iter() {
+        headers.insert(header::HeaderName::from_bytes(name.as_bytes())?, header::HeaderValue::from_str(value)?);
+    }
+    if !config.cookies.is_empty() {
+        let cookies = config.cookies.iter().map(|(name, value)| format!("{name}={value}")).join("; ");
+        headers.insert(header::COOKIE, header::HeaderValue::from_str(&cookies)?);
+    }
+    Ok(headers)
+}
+
+fn build_client(config: &ScrapeConfig) -> Result<reqwest::Client> {
+    Ok(client_builder(config)?.build()?)
+}
+
+fn client_builder(config: &ScrapeConfig) -> Result<reqwest::ClientBuilder> {
+    let headers = request_headers(config)?;
+
+    // Setup Reqwest with the header
+    let mut client = reqwest::Client::builder()
+        .default_headers(headers)
+        .timeout(config.timeout)
+        .connect_timeout(config.connect_timeout)
+        .pool_idle_timeout(config.pool_idle_timeout)
+        .pool_max_idle_per_host(config.pool_max_idle_per_host)
+        .tcp_keepalive(config.tcp_keepalive);
+    if config.http2_prior_knowledge {
+        client = client.http2_prior_knowledge();
+    }
+    if config.insecure_tls {
+        client = client
+            .min_tls_version(reqwest::tls::Version::TLS_1_0)
+            .danger_accept_invalid_certs(true);
+    }
+    // Stop at redirects to parking services, so their pages aren't fetched
+    let parking_hosts = config.parking_hosts.clone();
+    client = client.redirect(reqwest::redirect::Policy::custom(move |attempt| {
+        let parked = attempt.url().host_str().is_some_and(|host| is_parking_host(host, &parking_hosts));
+        if parked {
+            attempt.stop()
+        } else if let Some(looped) = redirect_loop(attempt.url(), attempt.previous()) {
+            tracing::debug!("{looped}");
+            attempt.error(looped)
+        } else if attempt.previous().len() >= 10 {
+            attempt.error("too many redirects")
+        } else {
+            attempt.follow()
+        }
+    }));
+    if let Some(proxy) = &config.proxy {
+        // Credentials in the URL are used for proxy auth
+        client = client.proxy(reqwest::Proxy::all(proxy)?);
+    }
+    Ok(client)
+}
+
+/// The redirects went round in a circle, often `http://` and `https://`
+/// sending the browser to each other.
+#[derive(Debug)]
+pub struct RedirectLoop {
+    /// From the first URL that came round again, back to it
+    pub urls: Vec<String>,
+}
+
+impl fmt::Display for RedirectLoop {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Redirect loop: {}", self.urls.join(" -> "))
+    }
+}
+
+impl std::error::Error for RedirectLoop {}
+
+/// If redirecting to `next` revisits one of the `previous` URLs, the loop.
+fn redirect_loop(next: &reqwest::Url, previous: &[reqwest::Url]) -> Option<RedirectLoop> {
+    let start = previous.iter().position(|url| url == next)?;
+    let urls = previous[start..].iter().chain([next]).map(reqwest::Url::to_string).collect();
+    Some(RedirectLoop { urls })
+}
+
+/// Did a request fail in the TLS handshake? reqwest only reports a connect
+/// error, so this looks for the TLS library's error underneath it, which
+/// may be wrapped in an `io::Error`.
+pub fn is_tls_error(e: &anyhow::Error) -> bool {
+    let Some(e) = e.chain().find_map(|e| e.downcast_ref::<reqwest::Error>()) else {
+        return false;
+    };
+    let mut source = std::error::Error::source(e);
+    while let Some(inner) = source {
+        // An io::Error's source skips the error it wraps
+        let wrapped = inner.downcast_ref::<std::io::Error>().and_then(|e| e.get_ref());
+        if inner.is::<native_tls::Error>() || wrapped.is_some_and(|e| e.is::<native_tls::Error>()) {
+            return true;
+        }
+        source = inner.source();
+    }
+    false
+}
+
+/// The homepage said (in `Content-Length`) that it's too small to be worth
+/// downloading.
+#[derive(Debug)]
+pub struct TooSmall {
+    pub length: u64,
+}
+
+impl fmt::Display for TooSmall {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "The page is only {} bytes", self.length)
+    }
+}
+
+impl std::error::Error for TooSmall {}
+
+/// The homepage (or the sitemap) says the site hasn't changed since
+/// [`ScrapeConfig::since`].
+#[derive(Debug)]
+pub struct NotModified;
+
+impl fmt::Display for NotModified {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("The site hasn't changed since the cutoff")
+    }
+}
+
+impl std::error::Error for NotModified {}
+
+/// The progress callback stopped the download (say, because it looked binary).
+#[derive(Debug)]
+pub struct DownloadStopped {
+    pub bytes: usize,
+}
+
+impl fmt::Display for DownloadStopped {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Stopped downloading after {} bytes", self.bytes)
+    }
+}
+
+impl std::error::Error for DownloadStopped {}
+
+/// A fetched HTML page, and the HTTP status it came with.
+struct Fetched {
+    /// Where the page ended up, after any redirects
+    url: reqwest::Url,
+    status: u16,
+    /// From the `Last-Modified` header, if there was a valid one
+    last_modified: Option<SystemTime>,
+    /// The `signal_headers` that were present
+    headers: Vec<(String, String)>,
+    body: String,
+    /// Set if the page redirected to a parking service: which one
+    parked: Option<String>,
+}
+
+/// Is `host` one of `parking_hosts`, or a subdomain of one?
+fn is_parking_host(host: &str, parking_hosts: &[String]) -> bool {
+    let host = host.to_lowercase();
+    parking_hosts.iter().any(|parking| host == *parking || host.ends_with(&format!(".{parking}")))
+}
+
+/// The parking host a response redirects to, if it does.
+fn parking_redirect(response: &reqwest::Response, config: &ScrapeConfig) -> Option<String> {
+    if !response.status().is_redirection() {
+        return None;
+    }
+    let location = response.headers().get(header::LOCATION)?.to_str().ok()?;
+    let target = response.url().join(location).ok()?;
+    let host = target.host_str()?;
+    is_parking_host(host, &config.parking_hosts).then(|| host.to_string())
+}
+
+/// Fetch one page. `path` starts with a `/`. `on_chunk` is given each piece
+/// of the body as it arrives, and can stop the download.
+async fn fetch_html(
+    client: &reqwest::Client,
+    domain: &str,
+    path: &str,
+    config: &ScrapeConfig,
+    on_chunk: &(dyn Fn(&[u8]) -> ControlFlow<()> + Sync),
+) -> Result<Fetched> {
+    if let Some(fixtures) = &config.fixtures {
+        // The homepage is `{domain}.html`, other pages are `{domain}/{path}.html`
+        let file = match path {
+            "/" => format!("{domain}.html"),
+            path => format!("{domain}{path}.html"),
+        };
+        let body = tokio::fs::read_to_string(fixtures.join(file)).await?;
+        if on_chunk(body.as_bytes()).is_break() {
+            return Err(DownloadStopped { bytes: body.len() }.into());
+        }
+        let url = reqwest::Url::parse(&format!("http://{}{}", ascii_domain(domain)?, path))?;
+        return Ok(Fetched { url, status: 200, last_modified: None, headers: Vec::new(), body, parked: None });
+    }
+
+    let url = format!("http://{}{}", ascii_domain(domain)?, path);
+
+    // Fetch the website. Redirects are followed, so this is the final status.
+    config.governor.wait().await;
+    let mut request = client.get(&url);
+    if let Some((username, password)) = config.basic_auth_for(domain) {
+        request = request.basic_auth(username, Some(password));
+    }
+    let since = config.since.filter(|_| path == "/");
+    if let Some(since) = since {
+        request = request.header(header::IF_MODIFIED_SINCE, httpdate::fmt_http_date(since));
+    }
+    let mut response = request.send().await?;
+    let status = response.status().as_u16();
+    if status == 304 {
+        return Err(NotModified.into());
+    }
+    if let Some(host) = parking_redirect(&response, config) {
+        let headers = signal_headers(response.headers(), config);
+        let parked = Some(format!("redirects to {host}"));
+        let url = response.url().clone();
+        return Ok(Fetched { url, status, last_modified: None, headers, body: String::new(), parked });
+    }
+    // Redirect stubs and empty pages aren't worth reading. Without the header,
+    // the word count catches them later.
+    if let Some(length) = response.content_length() {
+        if path == "/" && length < config.min_content_length {
+            return Err(TooSmall { length }.into());
+        }
+    }
+    let last_modified = response
+        .headers()
+        .get(header::LAST_MODIFIED)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| httpdate::parse_http_date(value).ok());
+    // Servers that ignore If-Modified-Since still say when the page changed,
+    // before there's a body to read
+    if let (Some(since), Some(modified)) = (since, last_modified) {
+        if modified < since {
+            return Err(NotModified.into());
+        }
+    }
+
+    let content_type = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+
+    // Read the body a piece at a time, so progress can be reported
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        body.extend_from_slice(&chunk);
+        if on_chunk(&chunk).is_break() {
+            return Err(DownloadStopped { bytes: body.len() }.into());
+        }
+    }
+    let body = decode_body(&body, content_type.as_deref());
+
+    let headers = signal_headers(response.headers(), config);
+    let url = response.url().clone();
+    Ok(Fetched { url, status, last_modified, headers, body, parked: None })
+}
+
+/// The allowlisted response headers, as `(name, value)`. Cookie values can
+/// be personal, so for `set-cookie` only the name is kept.
+fn signal_headers(headers: &header::HeaderMap, config: &ScrapeConfig) -> Vec<(String, String)> {
+    let mut kept = Vec::new();
+    for name in config.signal_headers.iter() {
+        for value in headers.get_all(name.as_str()) {
+            let Ok(value) = value.to_str() else { continue };
+            let value = match name.as_str() {
+                "set-cookie" => value.split('=').next().unwrap_or_default(),
+                _ => value,
+            };
+            kept.push((name.clone(), value.trim().to_string()));
+        }
+    }
+    kept
+}
+
+/// The `charset=` part of a `Content-Type` (or a meta tag's `content`).
+fn charset_param(content_type: &str) -> Option<&str> {
+    let (_, rest) = content_type.split_once("charset=")?;
+    rest.split([';', ' ', '"', '\'']).find(|s| !s.is_empty())
+}
+
+/// The charset declared by a `<meta>` tag near the top of the page.
+fn meta_charset(body: &[u8]) -> Option<&'static encoding_rs::Encoding> {
+    // Browsers only look this far in, and it's before any text we'd decode wrongly
+    let head = String::from_utf8_lossy(&body[..body.len().min(1024)]).to_lowercase();
+    let doc = scraper::Html::parse_document(&head);
+    let meta = scraper::Selector::parse("meta").unwrap();
+    doc.select(&meta).find_map(|e| {
+        let e = e.value();
+        let label = e.attr("charset").or_else(|| charset_param(e.attr("content")?))?;
+        encoding_rs::Encoding::for_label(label.trim().as_bytes())
+    })
+}
+
+/// Decode a page body using its declared charset: from the `Content-Type`
+/// header, then a `<meta>` tag, then UTF-8. Anything that doesn't decode
+/// becomes U+FFFD rather than an error.
+fn decode_body(body: &[u8], content_type: Option<&str>) -> String {
+    let encoding = content_type
+        .and_then(charset_param)
+        .and_then(|label| encoding_rs::Encoding::for_label(label.as_bytes()))
+        .or_else(|| meta_charset(body))
+        .unwrap_or(encoding_rs::UTF_8);
+    // A byte order mark overrides the label
+    let (text, _, _) = encoding.decode(body);
+    text.into_owned()
+}
+
+/// A candidate keyword, and the stem it's counted under if its page's
+/// language has a stemmer.
+struct Word {
+    text: String,
+    stem: Option<String>,
+}
+
+impl Word {
+    fn key(&self) -> &str {
+        self.stem.as_deref().unwrap_or(&self.text)
+    }
+}
+
+/// All the candidate keywords on an HTML page, using the configured
+/// extraction and the tokenizer and stemmer for the page's language.
+fn page_words(html: &str, config: &ScrapeConfig) -> Vec<Word> {
+    let language = page_language(html);
+    let tokenizer = config.tokenizers.for_language(language.as_deref());
+    let words = match config.extraction {
+        Extraction::Selectors => selector_words(html, tokenizer),
+        Extraction::Readability => readability_words(html, tokenizer).unwrap_or_else(|| selector_words(html, tokenizer)),
+    };
+    let stemmer = config.tokenizers.stemmer_for(language.as_deref());
+    words.into_iter().map(|text| Word { stem: stemmer.map(|s| s.stem(&text)), text }).collect()
+}
+
+/// Navigation, footers and the like. Their text says little about the site.
+const CHROME_TAGS: &[&str] = &["nav", "header", "footer", "aside", "form"];
+/// Class and id fragments that mark page chrome.
+const CHROME_HINTS: &[&str] = &[
+    "nav", "menu", "footer", "header", "sidebar", "comment", "cookie", "banner", "share", "social", "related", "breadcrumb",
+];
+/// A main content block needs at least this much paragraph text.
+const MIN_CONTENT_CHARS: usize = 140;
+
+/// Is this element, or anything it's inside, page chrome?
+fn is_chrome(element: scraper::ElementRef) -> bool {
+    std::iter::once(element)
+        .chain(element.ancestors().filter_map(scraper::ElementRef::wrap))
+        .any(|e| {
+            let e = e.value();
+            let names = e.attr("class").unwrap_or_default().to_lowercase() + " " + &e.attr("id").unwrap_or_default().to_lowercase();
+            CHROME_TAGS.contains(&e.name()) || CHROME_HINTS.iter().any(|hint| names.contains(hint))
+        })
+}
+
+/// How likely an element is to be the main content: how much paragraph text
+/// it holds, less the share of its text that's links.
+fn content_score(element: scraper::ElementRef) -> f32 {
+    let paragraphs = scraper::Selector::parse("p").unwrap();
+    let links = scraper::Selector::parse("a").unwrap();
+    let length = |e: scraper::ElementRef| e.text().map(str::len).sum::<usize>();
+
+    let paragraph_chars: usize = element.select(&paragraphs).map(length).sum();
+    let total_chars = length(element).max(1);
+    let link_chars: usize = element.select(&links).map(length).sum();
+    paragraph_chars as f32 * (1.0 - link_chars as f32 / total_chars as f32)
+}
+
+/// Words from the page's title and main content block, or `None` if no block
+/// looks like content.
+fn readability_words(html: &str, tokenizer: &dyn Tokenizer) -> Option<Vec<String>> {
+    let doc = scraper::Html::parse_document(html);
+    let candidates = scraper::Selector::parse("article, main, section, div").unwrap();
+    // Ties go to the last candidate, which is the innermost
+    let (score, best) = doc
+        .select(&candidates)
+        .filter(|e| !is_chrome(*e))
+        .map(|e| (content_score(e), e))
+        .max_by(|a, b| a.0.total_cmp(&b.0))?;
+    if score < MIN_CONTENT_CHARS as f32 {
+        return None;
+    }
+
+    let mut content = find_content("title", &doc, tokenizer);
+    content.extend(tokenizer.tokens(&best.text().collect::<Vec<_>>().join(" ")));
+    Some(content)
+}
+
+/// All the candidate keywords on an HTML page, in page order.
+fn selector_words(html: &str, tokenizer: &dyn Tokenizer) -> Vec<String> {
+    // Parse the HTML
+    let doc = scraper::Html::parse_document(html);
+    // Search for parts of the site with text in likely places
+    let mut content = Vec::new();
+    for items in ["title", "meta", "ul,li", "h1", "p"] {
+        content.extend(find_content(items, &doc, tokenizer));
+    }
+    content
+}
+
+/// Rank words by how often they occur, and keep the `max_words` most common
+/// of those that occur at least `min_word_count` times.
+fn rank_keywords(mut content: Vec<Word>, config: &ScrapeConfig) -> String {
+    // We now have a big list of words (hopefully) from the website
+    content.sort_by(|a, b| a.key().cmp(b.key()).then_with(|| a.text.cmp(&b.text))); // Sort alphabetically, by stem
+    content
+        .chunk_by(|a, b| a.key() == b.key()) // Group the forms of each word
+        .map(|forms| (forms.len(), representative(forms))) // Count them, and pick one to show the LLM
+        .filter(|(count, _word)| *count >= config.min_word_count) // Drop the rare ones
+        .sorted_by(|a, b| b.0.cmp(&a.0)) // Sort by count, descending
+        .map(|(_count, word)| word)// Take only the word
+        .take(config.max_words)// Take the top words
+        .join(" ") // Join them into a string
+}
+
+/// The form of a word to put in the prompt: the most common, then the
+/// shortest. `forms` is sorted by text.
+fn representative(forms: &[Word]) -> &str {
+    forms
+        .chunk_by(|a, b| a.text == b.text)
+        .min_by_key(|same| (std::cmp::Reverse(same.len()), same[0].text.len()))
+        .map(|same| same[0].text.as_str())
+        .unwrap_or_default()
+}
+
+/// If a page looks like a parked or placeholder domain, say why.
+pub fn parked_signal(html: &str, config: &ScrapeConfig) -> Option<String> {
+    let doc = scraper::Html::parse_document(html);
+    // All the visible text, lowercase with the whitespace squashed
+    let text = doc.root_element()
+        .text()
+        .flat_map(str::split_whitespace)
+        .map(str::to_lowercase)
+        .join(" ");
+
+    if let Some(phrase) = config.parking_phrases.iter().find(|p| text.contains(p.as_str())) {
+        return Some(phrase.clone());
+    }
+
+    // Registrar placeholders are often little more than "domain" and a logo
+    let distinct = text.split_whitespace().filter(|w| w.len() > 3).unique().count();
+    if distinct < config.min_unique_words && text.contains("domain") {
+        return Some("placeholder".to_string());
+    }
+    None
+}
+
+/// Pages with fewer visible words than this can be script shells.
+const JS_SHELL_MAX_WORDS: usize = 20;
+
+/// Elements that single-page apps mount themselves into.
+const JS_MOUNT_POINTS: &[&str] = &["#root", "#app", "#__next", "#__nuxt", "[ng-app]", "app-root"];
+
+/// Does the page look like a shell for JavaScript to fill in? That's a body
+/// with next to no visible text, and either several scripts or an empty
+/// element for an app to mount into.
+pub fn looks_js_rendered(html: &str) -> bool {
+    let doc = scraper::Html::parse_document(html);
+    let script = scraper::Selector::parse("script").unwrap();
+    let scripts = doc.select(&script).count();
+    if scripts == 0 {
+        return false;
+    }
+    let body = scraper::Selector::parse("body").unwrap();
+    let words = doc
+        .select(&body)
+        .flat_map(|body| body.descendants())
+        .filter_map(|node| node.value().as_text().map(|text| (node, text)))
+        .filter(|(node, _)| !node.ancestors().any(|a| {
+            a.value().as_element().is_some_and(|e| matches!(e.name(), "script" | "style" | "noscript" | "template"))
+        }))
+        .flat_map(|(_, text)| text.split_whitespace())
+        .count();
+    if words >= JS_SHELL_MAX_WORDS {
+        return false;
+    }
+    let mount_point = JS_MOUNT_POINTS
+        .iter()
+        .filter_map(|s| scraper::Selector::parse(s).ok())
+        .any(|selector| doc.select(&selector).any(|e| e.text().all(|t| t.trim().is_empty())));
+    scripts >= 3 || mount_point
+}
+
+/// The page's language, from `<html lang="...">`: just the primary tag, so
+/// `de-DE` is `de`.
+fn page_language(html: &str) -> Option<String> {
+    let doc = scraper::Html::parse_document(html);
+    let lang = doc.root_element().value().attr("lang")?;
+    let primary = lang.split(['-', '_']).next()?.trim().to_lowercase();
+    (!primary.is_empty()).then_some(primary)
+}
+
+/// The page's `og:image` and favicon, resolved against `base` (where the page
+/// was fetched from). Only the URLs: neither is downloaded.
+fn page_images(html: &str, base: &reqwest::Url) -> (Option<String>, Option<String>) {
+    let doc = scraper::Html::parse_document(html);
+    let resolve = |href: &str| base.join(href.trim()).ok().map(String::from);
+    let og_image = scraper::Selector::parse(r#"meta[property="og:image"], meta[name="og:image"]"#).unwrap();
+    let image = doc.select(&og_image).find_map(|e| resolve(e.value().attr("content")?));
+    // `rel="icon"` and `rel="shortcut icon"`, then the Apple one as a fallback
+    let favicon = ["link[rel~=icon]", "link[rel=apple-touch-icon]"]
+        .iter()
+        .map(|s| scraper::Selector::parse(s).unwrap())
+        .find_map(|selector| doc.select(&selector).find_map(|e| resolve(e.value().attr("href")?)));
+    (image, favicon)
+}
+
+/// Extract the most common words from an HTML page, as a space-separated string.
+pub fn extract_keywords(html: &str, config: &ScrapeConfig) -> String {
+    rank_keywords(page_words(html, config), config)
+}
+
+/// Does the keyword list have enough distinct words to be worth categorizing?
+/// A page that's mostly one word repeated gives the LLM nothing to go on.
+pub fn has_enough_content(text: &str, config: &ScrapeConfig) -> bool {
+    text.split_whitespace().unique().count() >= config.min_unique_words
+}
+
+/// What we got from scraping a domain.
+pub struct Page {
+    /// The most common words, space separated
+    pub keywords: String,
+    /// The homepage's HTTP status, after any redirects
+    pub status: u16,
+    /// How long fetching took, including any extra pages
+    pub elapsed: Duration,
+    /// Set if the homepage looks like a parked domain: what gave it away
+    pub parked: Option<String>,
+    /// When the homepage says it last changed
+    pub last_modified: Option<SystemTime>,
+    /// The homepage's declared language (e.g. `de`)
+    pub language: Option<String>,
+    /// The homepage's `signal_headers`
+    pub headers: Vec<(String, String)>,
+    /// The homepage looks like an empty shell that scripts fill in, so
+    /// there's little to be had from its HTML. Only checked for pages
+    /// without enough keywords; the others are `false`.
+    pub js_rendered: bool,
+    /// The homepage's `og:image` URL
+    pub image: Option<String>,
+    /// The homepage's favicon URL
+    pub favicon: Option<String>,
+}
+
+impl Page {
+    /// A hash of what the LLM would be shown. Templated pages (registrar
+    /// defaults, SaaS tenant pages) hash the same.
+    pub fn content_hash(&self) -> u64 {
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        (&self.keywords, &self.language).hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// The headers in a form for the prompt: `server: Shopify, set-cookie: _shopify_y`.
+    pub fn header_summary(&self) -> Option<String> {
+        if self.headers.is_empty() {
+            return None;
+        }
+        Some(self.headers.iter().map(|(name, value)| format!("{name}: {value}")).join(", "))
+    }
+}
+
+pub async fn website_text(domain: &str, config: &ScrapeConfig) -> Result<Page> {
+    website_text_with_progress(domain, config, |_, _| ControlFlow::Continue(())).await
+}
+
+/// Like [`website_text`], but calls `on_chunk` each time more of a page
+/// arrives, with the total number of bytes downloaded so far (across all of
+/// the domain's pages) and the new piece. Returning `Break` stops that
+/// page's download with [`DownloadStopped`].
+pub async fn website_text_with_progress(
+    domain: &str,
+    config: &ScrapeConfig,
+    on_chunk: impl Fn(usize, &[u8]) -> ControlFlow<()> + Sync,
+) -> Result<Page> {
+    let start = Instant::now();
+    let client = config.client()?;
+    let received = AtomicUsize::new(0);
+    let progress = |chunk: &[u8]| on_chunk(received.fetch_add(chunk.len(), Ordering::SeqCst) + chunk.len(), chunk);
+
+    // A sitemap that says nothing has changed saves fetching the site at all
+    if let Some(since) = config.since {
+        if let Some(modified) = sitemap_last_modified(&client, domain, config).await {
+            if modified < since {
+                return Err(NotModified.into());
+            }
+        }
+    }
+
+    // The homepage has to work. Plenty of sites don't have an /about, so
+    // extra pages that fail are skipped.
+    let home = async {
+        let mut home = fetch_html(&client, domain, "/", config, &progress).await;
+        for retry in 1..=config.fetch_retries {
+            match &home {
+                Err(e) if is_retryable_fetch(e) => tracing::debug!(domain, retry, "Fetching failed, trying again: {e}"),
+                _ => break,
+            }
+            config.backoff.wait(retry as u32).await;
+            home = fetch_html(&client, domain, "/", config, &progress).await;
+        }
+        home
+    };
+    let extra_paths = config.extra_paths.iter().take(config.max_extra_pages);
+    let extras = join_all(extra_paths.map(|path| fetch_html(&client, domain, path, config, &progress)));
+
+    // Fetch the homepage and any extra pages at the same time. With `since`,
+    // the homepage goes first, so nothing more is fetched if it hasn't changed.
+    let (home, extras) = match config.since {
+        None => futures::join!(home, extras),
+        Some(_) => {
+            let home = home.await?;
+            (Ok(home), extras.await)
+        }
+    };
+    let mut home = home?;
+    // Parsing the page again to look for a shell is only done when it's needed
+    let mut shell = None;
+    if config.renderer.is_enabled() && home.parked.is_none() && (config.render_all || *shell.insert(looks_js_rendered(&home.body))) {
+        let url = format!("http://{}/", ascii_domain(domain)?);
+        match config.renderer.render(&url).await {
+            Some(Ok(html)) => {
+                home.body = html;
+                shell = None;
+            }
+            Some(Err(e)) => tracing::warn!(domain, "Rendering failed, using the page as fetched: {e}"),
+            None => {}
+        }
+    }
+    let mut words = page_words(&home.body, config);
+    for page in extras.into_iter().flatten() {
+        words.extend(page_words(&page.body, config));
+    }
+    let (image, favicon) = page_images(&home.body, &home.url);
+    let keywords = rank_keywords(words, config);
+    // Only worth knowing when there isn't enough to go on
+    let js_rendered = !has_enough_content(&keywords, config) && shell.unwrap_or_else(|| looks_js_rendered(&home.body));
+    let elapsed = start.elapsed();
+    tracing::debug!(domain, status = home.status, elapsed_ms = elapsed.as_millis() as u64, "Fetched");
+
+    Ok(Page {
+        keywords,
+        status: home.status,
+        elapsed,
+        parked: home.parked.or_else(|| parked_signal(&home.body, config)),
+        last_modified: home.last_modified,
+        language: page_language(&home.body),
+        js_rendered,
+        headers: home.headers,
+        image,
+        favicon,
+    })
+}
+
+/// Could fetching again work? Connections that failed or timed out might;
+/// a bad certificate or a redirect loop won't.
+fn is_retryable_fetch(error: &anyhow::Error) -> bool {
+    let transient = error
+        .chain()
+        .any(|e| e.downcast_ref::<reqwest::Error>().is_some_and(|e| e.is_connect() || e.is_timeout()));
+    transient && !is_tls_error(error)
+}
+
+/// The most child sitemaps read from a sitemap index.
+const MAX_CHILD_SITEMAPS: usize = 10;
+
+/// The `<loc>`s in a sitemap, and whether it's an index of other sitemaps
+/// rather than a list of pages.
+fn sitemap_locs(xml: &str) -> (Vec<String>, bool) {
+    let mut locs = Vec::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find("<loc>") {
+        rest = &rest[start + "<loc>".len()..];
+        let Some(end) = rest.find("</loc>") else { break };
+        let loc = rest[..end].trim();
+        let loc = loc.strip_prefix("<![CDATA[").and_then(|l| l.strip_suffix("]]>")).unwrap_or(loc);
+        let loc = loc
+            .replace("&lt;", "<")
+            .replace("&gt;", ">")
+            .replace("&quot;", "\"")
+            .replace("&apos;", "'")
+            .replace("&amp;", "&");
+        locs.push(loc.trim().to_string());
+        rest = &rest[end..];
+    }
+    (locs, xml.contains("<sitemapindex"))
+}
+
+/// When the site last changed, going by the newest `<lastmod>` in its
+/// `/sitemap.xml`. `None` if there's no sitemap, or it doesn't say.
+async fn sitemap_last_modified(client: &reqwest::Client, domain: &str, config: &ScrapeConfig) -> Option<SystemTime> {
+    let sitemap = fetch_html(client, domain, "/sitemap.xml", config, &|_| ControlFlow::Continue(())).await.ok()?;
+    if sitemap.status >= 400 {
+        return None;
+    }
+    sitemap_lastmods(&sitemap.body).max()
+}
+
+/// The `<lastmod>` dates in a sitemap. They're W3C dates, `2024-01-02` or
+/// `2024-01-02T12:00:00+00:00`; any time zone is ignored, which is close
+/// enough for a cutoff.
+fn sitemap_lastmods(xml: &str) -> impl Iterator<Item = SystemTime> + '_ {
+    xml.split("<lastmod>").skip(1).filter_map(|rest| {
+        let date = rest.split("</lastmod>").next()?.trim();
+        let date = match date.len() {
+            10 => format!("{date} 00:00:00"),
+            _ => date.get(..19)?.to_string(),
+        };
+        humantime::parse_rfc3339_weak(&date).ok()
+    })
+}
+
+/// The path (and query) of `loc`, if it's on `domain` (`www.` or not).
+/// Other hosts are left out.
+fn same_site_path(loc: &str, domain: &str) -> Option<String> {
+    let url = reqwest::Url::parse(loc).ok()?;
+    let bare = |host: &str| host.trim_start_matches("www.").to_lowercase();
+    let host = domain.split(':').next().unwrap_or(domain);
+    if bare(url.host_str()?) != bare(&ascii_domain(host).ok()?) {
+        return None;
+    }
+    Some(match url.query() {
+        Some(query) => format!("{}?{query}", url.path()),
+        None => url.path().to_string(),
+    })
+}
+
+/// Up to `count` of `paths`, spread over the site's sections (the first part
+/// of the path): one from each in turn, in the order they're listed.
+fn sample_paths(paths: Vec<String>, count: usize) -> Vec<String> {
+    let mut sections: Vec<(String, std::collections::VecDeque<String>)> = Vec::new();
+    for path in paths.into_iter().filter(|p| p != "/").unique() {
+        let section = path.trim_start_matches('/').split(['/', '?']).next().unwrap_or_default().to_string();
+        match sections.iter_mut().find(|(s, _)| *s == section) {
+            Some((_, paths)) => paths.push_back(path),
+            None => sections.push((section, std::collections::VecDeque::from([path]))),
+        }
+    }
+    let mut sample = Vec::new();
+    while sample.len() < count && sections.iter().any(|(_, paths)| !paths.is_empty()) {
+        for (_, paths) in sections.iter_mut() {
+            if sample.len() == count {
+                break;
+            }
+            sample.extend(paths.pop_front());
+        }
+    }
+    sample
+}
+
+/// A sample of up to `count` pages from the domain's `/sitemap.xml` (following
+/// a sitemap index one level down), spread over the site's sections. Use them
+/// as `extra_paths` to categorize the site from more than its homepage.
+pub async fn sitemap_paths(domain: &str, config: &ScrapeConfig, count: usize) -> Result<Vec<String>> {
+    let client = &config.client()?;
+    let fetch = |path: String| async move {
+        let sitemap = fetch_html(client, domain, &path, config, &|_| ControlFlow::Continue(())).await?;
+        anyhow::ensure!(sitemap.status < 400, "{path} returned {}", sitemap.status);
+        Ok(sitemap_locs(&sitemap.body))
+    };
+    let (locs, is_index) = fetch("/sitemap.xml".to_string()).await?;
+    let paths = match is_index {
+        false => locs.iter().filter_map(|loc| same_site_path(loc, domain)).collect(),
+        true => {
+            let children = locs.iter().filter_map(|loc| same_site_path(loc, domain)).take(MAX_CHILD_SITEMAPS);
+            let mut paths = Vec::new();
+            for child in join_all(children.map(fetch)).await {
+                // One broken child sitemap doesn't spoil the rest
+                match child {
+                    Ok((locs, _)) => paths.extend(locs.iter().filter_map(|loc| same_site_path(loc, domain))),
+                    Err(e) => tracing::debug!(domain, "Skipping a sitemap: {e}"),
+                }
+            }
+            paths
+        }
+    };
+    anyhow::ensure!(!paths.is_empty(), "The sitemap for {domain} doesn't list any of its pages");
+    Ok(sample_paths(paths, count))
+}
+
+/// Looks up a domain's addresses. Swappable so tests don't need real DNS.
+type Lookup = Arc<dyn Fn(String) -> futures::future::BoxFuture<'static, Vec<IpAddr>> + Send + Sync>;
+
+/// Caches DNS lookups, so that each domain is only resolved once per run.
+/// Failed lookups are cached too (as an empty list). Cloning shares the cache.
+#[derive(Clone)]
+pub struct DnsCache {
+    cache: Arc<Mutex<HashMap<String, Vec<IpAddr>>>>,
+    lookup: Lookup,
+}
+
+impl Default for DnsCache {
+    /// Resolves with the system resolver.
+    fn default() -> Self {
+        Self::with_resolver(|domain| async move {
+            let Ok(domain) = ascii_domain(&domain) else { return Vec::new() };
+            let addrs = tokio::net::lookup_host((domain.as_str(), 80)).await;
+            match addrs {
+                Ok(addrs) => addrs.map(|a| a.ip()).collect(),
+                Err(_) => Vec::new(),
+            }
+        })
+    }
+}
+
+impl DnsCache {
+    /// A cache in front of `lookup`, which returns a domain's A/AAAA addresses
+    /// (or nothing, if it doesn't resolve).
+    pub fn with_resolver<F, Fut>(lookup: F) -> Self
+    where
+        F: Fn(String) -> Fut + Send + Sync + 'static,
+        Fut: std::future::Future<Output = Vec<IpAddr>> + Send + 'static,
+    {
+        let lookup: Lookup = Arc::new(move |domain| Box::pin(lookup(domain)));
+        Self { cache: Arc::default(), lookup }
+    }
+
+    /// Store a resolution, as if it had been looked up.
+    pub fn insert(&self, domain: &str, addrs: Vec<IpAddr>) {
+        self.cache.lock().unwrap().insert(domain.to_string(), addrs);
+    }
+
+    /// Resolve a domain's A/AAAA records. An empty list means it doesn't resolve.
+    pub async fn resolve(&self, domain: &str) -> Vec<IpAddr> {
+        if let Some(addrs) = self.cache.lock().unwrap().get(domain) {
+            return addrs.clone();
+        }
+        let addrs = (self.lookup)(domain.to_string()).await;
+        self.cache.lock().unwrap().insert(domain.to_string(), addrs.clone());
+        addrs
+    }
+
+    /// Resolve many domains ahead of time, `concurrency` at once, so that
+    /// workers find them already cached (and the system resolver warmed up).
+    /// Returns the domains that didn't resolve.
+    pub async fn prefetch(&self, domains: impl IntoIterator<Item = String>, concurrency: usize) -> Vec<String> {
+        futures::stream::iter(domains)
+            .map(|domain| async move {
+                let resolves = !self.resolve(&domain).await.is_empty();
+                (domain, resolves)
+            })
+            .buffer_unordered(concurrency.max(1))
+            .filter_map(|(domain, resolves)| async move { (!resolves).then_some(domain) })
+            .collect()
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{http_response, TestServer};
+
+    fn fixture_config() -> ScrapeConfig {
+        ScrapeConfig {
+            fixtures: Some(PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("fixtures")),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fixture_keywords() {
+        let config = fixture_config();
+        assert_eq!(
+            website_text("bakery.example", &config).await.unwrap().keywords,
+            "bread cakes fresh baked bakery bread, daily. pastries."
+        );
+        assert_eq!(
+            website_text("games.example", &config).await.unwrap().keywords,
+            "games arcade puzzle racing online play"
+        );
+    }
+
+    #[test]
+    fn test_repetitive_page_is_insufficient() {
+        let config = ScrapeConfig::default();
+        let text = extract_keywords("<title>Welcome</title><p>welcome welcome welcome welcome welcome</p>", &config);
+        assert!(!has_enough_content(&text, &config));
+        // The same word with different punctuation still doesn't get over the line
+        assert!(!has_enough_content("welcome welcome. welcome! welcome", &config));
+    }
+
+    #[test]
+    fn test_rare_words_are_dropped() {
+        let html = "<title>Bakery</title><p>bread cakes bread cakes utm_x7f3 bakery recieve</p>";
+        let config = ScrapeConfig::builder().min_word_count(2).build().unwrap();
+        assert_eq!(extract_keywords(html, &config), "bakery bread cakes");
+        // By default everything is kept
+        assert!(extract_keywords(html, &ScrapeConfig::default()).contains("utm_x7f3"));
+    }
+
+    #[test]
+    fn test_js_shell_is_detected() {
+        let shell = r#"<html><head><title>Loading</title><script src="/vendor.js"></script></head>
+            <body><noscript>You need to enable JavaScript to run this app.</noscript><div id="root"></div>
+            <script src="/main.js"></script></body></html>"#;
+        assert!(looks_js_rendered(shell));
+
+        // Scripts on a page with real content are fine
+        let article = "<p>Our bakery has baked bread, cakes and pastries by hand every morning since 1952, \
+            using flour milled down the road and butter from the farm next door.</p>";
+        let scripts = "<script>a()</script><script>b()</script><script>c()</script>";
+        assert!(!looks_js_rendered(&format!("<body><div id=\"root\"></div>{article}{scripts}</body>")));
+        // And so is a small page without any
+        assert!(!looks_js_rendered("<title>Tiny</title><p>Bakery</p>"));
+    }
+
+    #[tokio::test]
+    async fn test_js_shells_are_rendered() {
+        /// Always renders the same page, and keeps the URLs it was asked for
+        struct CannedRenderer(Arc<Mutex<Vec<String>>>);
+        impl crate::render::Renderer for CannedRenderer {
+            async fn render(&self, url: &str) -> Result<String> {
+                self.0.lock().unwrap().push(url.to_string());
+                Ok("<title>Arcade</title><ul>\n<li>Puzzle games</li>\n<li>Racing games</li>\n</ul>".to_string())
+            }
+        }
+
+        let shell = r#"<body><div id="app"></div><script src="/app.js"></script></body>"#;
+        let server = TestServer::start(move |_| http_response(200, &[], shell)).await;
+        let rendered = Arc::new(Mutex::new(Vec::new()));
+        let config = ScrapeConfig::builder()
+            .renderer(Rendering::new(CannedRenderer(rendered.clone())))
+            .build()
+            .unwrap();
+
+        let page = website_text(&server.domain(), &config).await.unwrap();
+        assert_eq!(page.keywords, "games puzzle racing arcade");
+        assert!(!page.js_rendered);
+        assert_eq!(*rendered.lock().unwrap(), vec![format!("http://{}/", server.domain())]);
+
+        // Without a renderer, the shell is what there is
+        let page = website_text(&server.domain(), &ScrapeConfig::default()).await.unwrap();
+        assert!(page.js_rendered);
+    }
+
+    #[test]
+    fn test_tokenizer_follows_page_language() {
+        let html = r#"<html lang="zh-CN"><title>图书馆</title><p>大学图书馆</p></html>"#;
+        assert_eq!(extract_keywords(html, &ScrapeConfig::default()), "图书馆 大学图书馆");
+        let config = ScrapeConfig::builder().tokenizer("zh", crate::tokenize::Cjk).build().unwrap();
+        assert_eq!(extract_keywords(html, &config), "书馆 图书 大学 学图");
+    }
+
+    #[test]
+    fn test_stemming_collapses_word_forms() {
+        let html = r#"<html lang="en"><title>Games</title><p>games gaming gaming online games games</p></html>"#;
+        assert_eq!(extract_keywords(html, &ScrapeConfig::default()), "games gaming online");
+        let config = ScrapeConfig::builder().stemmer("en", crate::tokenize::Porter).build().unwrap();
+        // One entry, counted 6 times, shown as its most common form
+        assert_eq!(extract_keywords(html, &config), "games online");
+        // Pages in other languages aren't stemmed
+        assert_eq!(extract_keywords(&html.replace("\"en\"", "\"fr\""), &config), "games gaming online");
+    }
+
+    #[tokio::test]
+    async fn test_og_image_and_favicon_are_captured() {
+        let html = r#"<html><head><title>Bakery</title>
+            <meta property="og:image" content="https://cdn.example/bakery.jpg">
+            <link rel="shortcut icon" href="/static/favicon.ico"></head>
+            <body><p>Fresh bread and cakes</p></body></html>"#;
+        let server = TestServer::start(move |_| http_response(200, &[], html)).await;
+        let page = website_text(&server.domain(), &ScrapeConfig::default()).await.unwrap();
+        assert_eq!(page.image.as_deref(), Some("https://cdn.example/bakery.jpg"));
+        // Relative links are resolved against the page
+        assert_eq!(page.favicon, Some(format!("http://{}/static/favicon.ico", server.domain())));
+        // Nothing but the page itself was fetched
+        assert_eq!(server.requests.lock().unwrap().len(), 1);
+
+        let page = website_text("bakery.example", &fixture_config()).await.unwrap();
+        assert_eq!((page.image, page.favicon), (None, None));
+    }
+
+    #[tokio::test]
+    async fn test_rich_page_is_sufficient() {
+        let config = fixture_config();
+        let page = website_text("bakery.example", &config).await.unwrap();
+        assert!(has_enough_content(&page.keywords, &config));
+    }
+
+    #[tokio::test]
+    async fn test_extra_pages_are_merged() {
+        let config = ScrapeConfig {
+            extra_paths: COMMON_EXTRA_PATHS.iter().map(|p| p.to_string()).collect(),
+            ..fixture_config()
+        };
+        // Only /about exists for this fixture - the others are skipped
+        let text = website_text("bakery.example", &config).await.unwrap().keywords;
+        assert!(text.contains("sourdough"));
+        assert!(text.contains("family"));
+        assert!(text.starts_with("bread "));
+    }
+
+    #[tokio::test]
+    async fn test_sitemap_pages_are_sampled_and_scraped() {
+        let server = TestServer::start(|path| {
+            let urlset = |pages: &[&str]| {
+                let urls: String = pages.iter().map(|p| format!("<url><loc>http://127.0.0.1{p}</loc></url>")).collect();
+                format!(r#"<?xml version="1.0"?><urlset xmlns="http://www.sitemaps.org/schemas/sitemap/0.9">{urls}</urlset>"#)
+            };
+            match path {
+                "/sitemap.xml" => http_response(200, &[], concat!(
+                    "<sitemapindex><sitemap><loc>http://127.0.0.1/sitemap-shop.xml</loc></sitemap>",
+                    "<sitemap><loc>http://127.0.0.1/sitemap-blog.xml</loc></sitemap>",
+                    "<sitemap><loc>https://cdn.example/other.xml</loc></sitemap></sitemapindex>",
+                )),
+                "/sitemap-shop.xml" => http_response(200, &[], urlset(&["/", "/shop/bread", "/shop/cakes", "/shop/flour"])),
+                "/sitemap-blog.xml" => http_response(200, &[], urlset(&["/blog/sourdough?page=1", "/blog/rye"])),
+                "/" => http_response(200, &[], "<title>Village Bakery</title>"),
+                path => {
+                    // Each page is titled with its last path segment
+                    let page = path.split('?').next().unwrap_or_default().rsplit('/').next().unwrap_or_default();
+                    http_response(200, &[], format!("<title>{page}</title>"))
+                }
+            }
+        }).await;
+        let domain = server.domain();
+        let config = ScrapeConfig::default();
+
+        // Taken from each section in turn, not just the first sitemap's pages
+        let paths = sitemap_paths(&domain, &config, 3).await.unwrap();
+        assert_eq!(paths, vec!["/shop/bread", "/blog/sourdough?page=1", "/shop/cakes"]);
+
+        let config = ScrapeConfig { max_extra_pages: paths.len(), extra_paths: paths, ..config };
+        let keywords = website_text(&domain, &config).await.unwrap().keywords;
+        for word in ["village", "bread", "sourdough", "cakes"] {
+            assert!(keywords.contains(word), "{keywords}");
+        }
+        assert!(!keywords.contains("flour"));
+    }
+
+    #[tokio::test]
+    async fn test_readability_skips_page_chrome() {
+        let config = fixture_config();
+        let selectors = website_text("chrome.example", &config).await.unwrap().keywords;
+        let config = ScrapeConfig { extraction: Extraction::Readability, ..fixture_config() };
+        let readability = website_text("chrome.example", &config).await.unwrap().keywords;
+
+        // The selectors pick up every menu
+        assert!(selectors.contains("basket"));
+        assert!(selectors.contains("newsletter"));
+        // Readability only has the article, with its subject at the top
+        assert!(readability.starts_with("starter sourdough "), "{readability}");
+        for chrome in ["basket", "newsletter", "privacy", "login"] {
+            assert!(!readability.contains(chrome), "{chrome} in {readability}");
+        }
+    }
+
+    #[test]
+    fn test_readability_falls_back_to_selectors() {
+        let config = ScrapeConfig { extraction: Extraction::Readability, ..Default::default() };
+        let html = "<title>Tiny</title><ul><li>Bakery</li><li>Cakes</li></ul>";
+        assert_eq!(extract_keywords(html, &config), extract_keywords(html, &ScrapeConfig::default()));
+    }
+
+    #[test]
+    fn test_default_build() {
+        let config = ScrapeConfig::builder().build().unwrap();
+        assert_eq!(config.timeout, Duration::from_secs(30));
+        assert_eq!(config.max_words, 100);
+        assert_eq!(config.min_unique_words, 5);
+        assert!(config.fixtures.is_none());
+        assert!(config.extra_paths.is_empty());
+    }
+
+    #[test]
+    fn test_customized_build() {
+        let config = ScrapeConfig::builder()
+            .timeout(Duration::from_secs(5))
+            .max_words(20)
+            .min_unique_words(3)
+            .extra_paths(["/about"])
+            .max_extra_pages(1)
+            .fixtures("fixtures")
+            .build()
+            .unwrap();
+        assert_eq!(config.timeout, Duration::from_secs(5));
+        assert_eq!(config.max_words, 20);
+        assert_eq!(config.min_unique_words, 3);
+        assert_eq!(config.extra_paths, vec!["/about".to_string()]);
+        assert_eq!(config.max_extra_pages, 1);
+        assert_eq!(config.fixtures, Some(PathBuf::from("fixtures")));
+    }
+
+    #[test]
+    fn test_nonsense_builds_are_rejected() {
+        assert!(ScrapeConfig::builder().max_words(0).build().is_err());
+        assert!(ScrapeConfig::builder().max_words(3).min_unique_words(5).build().is_err());
+        assert!(ScrapeConfig::builder().timeout(Duration::ZERO).build().is_err());
+        assert!(ScrapeConfig::builder().extra_paths(["about"]).build().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_status_and_duration_are_captured() {
+        let server = TestServer::start(|_| {
+            http_response(200, &[("Content-Type", "text/html")], "<title>Parked domain for sale</title>")
+        }).await;
+        let page = website_text(&server.domain(), &ScrapeConfig::default()).await.unwrap();
+        assert_eq!(page.status, 200);
+        assert!(page.elapsed > Duration::ZERO);
+        assert_eq!(page.keywords, "domain parked sale");
+        assert!(server.requests.lock().unwrap()[0].starts_with("GET / HTTP/1.1"));
+    }
+
+    #[tokio::test]
+    async fn test_progress_is_cumulative() {
+        let body = format!("<title>Big page</title><p>{}</p>", "words ".repeat(20_000));
+        let length = body.len();
+        let server = TestServer::start(move |_| http_response(200, &[], &body)).await;
+
+        let seen = Mutex::new(Vec::new());
+        website_text_with_progress(&server.domain(), &ScrapeConfig::default(), |bytes, _| {
+            seen.lock().unwrap().push(bytes);
+            ControlFlow::Continue(())
+        }).await.unwrap();
+
+        let seen = seen.into_inner().unwrap();
+        assert!(!seen.is_empty());
+        assert!(seen.windows(2).all(|w| w[0] < w[1]), "{seen:?}");
+        assert_eq!(*seen.last().unwrap(), length);
+    }
+
+    #[tokio::test]
+    async fn test_progress_can_stop_a_binary_download() {
+        let mut body = b"\x89PNG\r\n\x1a\n\0\0".to_vec();
+        body.extend(std::iter::repeat_n(0u8, 1_000_000));
+        let server = TestServer::start(move |_| http_response(200, &[], &body)).await;
+
+        let calls = AtomicUsize::new(0);
+        let err = website_text_with_progress(&server.domain(), &ScrapeConfig::default(), |_, chunk| {
+            calls.fetch_add(1, Ordering::SeqCst);
+            match chunk.contains(&0) {
+                true => ControlFlow::Break(()),
+                false => ControlFlow::Continue(()),
+            }
+        }).await.err().unwrap();
+        let stopped = err.downcast_ref::<DownloadStopped>().unwrap();
+        // It stopped at the first piece, without reading the rest
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert!(stopped.bytes < 1_000_000, "{stopped}");
+    }
+
+    /// How long fetching `domain` takes to fail, with `timeout` and `connect_timeout`.
+    async fn time_to_fail(domain: &str, timeout: Duration, connect_timeout: Duration) -> Duration {
+        let config = ScrapeConfig::builder()
+            .timeout(timeout)
+            .connect_timeout(connect_timeout)
+            .fetch_retries(0)
+            .build()
+            .unwrap();
+        let started = Instant::now();
+        let error = tokio::time::timeout(Duration::from_secs(10), website_text(domain, &config))
+            .await
+            .expect("neither timeout stopped the fetch")
+            .err()
+            .expect("the fetch should fail");
+        assert!(
+            error.chain().any(|e| e.downcast_ref::<reqwest::Error>().is_some_and(reqwest::Error::is_timeout)),
+            "{error:?}"
+        );
+        started.elapsed()
+    }
+
+    #[tokio::test]
+    async fn test_both_timeouts_are_applied() {
+        // Accepts the connection, then never answers: only the overall timeout stops it
+        let stalled = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stalled_domain = stalled.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let mut open = Vec::new();
+            while let Ok((socket, _)) = stalled.accept().await {
+                open.push(socket);
+            }
+        });
+        let took = time_to_fail(&stalled_domain, Duration::from_millis(300), Duration::from_secs(10)).await;
+        assert!(took < Duration::from_secs(5), "{took:?}");
+
+        // Never accepts, and its queue is already full, so connecting hangs
+        // until the connect timeout
+        let socket = tokio::net::TcpSocket::new_v4().unwrap();
+        socket.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let full = socket.listen(0).unwrap();
+        let full_addr = full.local_addr().unwrap();
+        let mut queued = Vec::new();
+        while let Ok(stream) = std::net::TcpStream::connect_timeout(&full_addr, Duration::from_millis(200)) {
+            queued.push(stream);
+        }
+        let took = time_to_fail(&full_addr.to_string(), Duration::from_secs(30), Duration::from_millis(300)).await;
+        assert!(took < Duration::from_secs(5), "{took:?}");
+        drop((full, queued));
+
+        assert!(ScrapeConfig::builder().connect_timeout(Duration::ZERO).build().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_redirect_to_parking_host_is_parked() {
+        let server = TestServer::start(|_| {
+            http_response(302, &[("Location", "http://ww1.sedoparking.com/?domain=example")], "")
+        }).await;
+        let page = website_text(&server.domain(), &ScrapeConfig::default()).await.unwrap();
+        assert_eq!(page.parked.as_deref(), Some("redirects to ww1.sedoparking.com"));
+        assert_eq!(page.status, 302);
+
+        // Other redirects are followed as usual
+        let server = TestServer::start(|path| match path {
+            "/" => http_response(301, &[("Location", "/home")], ""),
+            _ => http_response(200, &[], "<title>Village Bakery</title>"),
+        }).await;
+        let page = website_text(&server.domain(), &ScrapeConfig::default()).await.unwrap();
+        assert!(page.parked.is_none());
+        assert_eq!(page.keywords, "bakery village");
+    }
+
+    #[test]
+    fn test_scheme_ping_pong_is_a_loop() {
+        let url = |s: &str| reqwest::Url::parse(s).unwrap();
+        let previous = [url("http://shop.example/"), url("https://shop.example/")];
+        let looped = redirect_loop(&url("http://shop.example/"), &previous).unwrap();
+        assert_eq!(looped.to_string(), "Redirect loop: http://shop.example/ -> https://shop.example/ -> http://shop.example/");
+        // Going somewhere new is fine
+        assert!(redirect_loop(&url("https://shop.example/home"), &previous).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_pool_settings_are_applied() {
+        let handler = |_: &str| {
+            let body = "<title>Welcome</title>";
+            format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{body}", body.len()).into_bytes()
+        };
+
+        // The client is shared, so one connection does for every fetch
+        let server = TestServer::start_keep_alive(handler).await;
+        let config = ScrapeConfig::default();
+        for _ in 0..3 {
+            website_text(&server.domain(), &config).await.unwrap();
+        }
+        assert_eq!(server.connections.load(Ordering::SeqCst), 1);
+
+        // Keeping no idle connections means a new one every time
+        let server = TestServer::start_keep_alive(handler).await;
+        let config = ScrapeConfig::builder().pool_max_idle_per_host(0).build().unwrap();
+        for _ in 0..3 {
+            website_text(&server.domain(), &config).await.unwrap();
+        }
+        assert_eq!(server.connections.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_configured_headers_are_sent() {
+        let server = TestServer::start(|_| http_response(200, &[], "<title>Welcome</title>")).await;
+        let config = ScrapeConfig::builder()
+            .header("X-Test", "yes")
+            .cookie("consent", "accepted")
+            .cookie("region", "uk")
+            .build()
+            .unwrap();
+        website_text(&server.domain(), &config).await.unwrap();
+
+        let request = server.requests.lock().unwrap()[0].to_lowercase();
+        assert!(request.contains("accept-language: en-us,en;q=0.9"), "{request}");
+        assert!(request.contains("x-test: yes"));
+        assert!(request.contains("cookie: consent=accepted; region=uk"));
+    }
+
+    #[tokio::test]
+    async fn test_basic_auth_is_sent() {
+        let server = TestServer::start(|_| http_response(200, &[], "<title>Intranet</title>")).await;
+        let config = ScrapeConfig::builder()
+            .domain_basic_auth(&server.domain(), "user", "secret")
+            .build()
+            .unwrap();
+        website_text(&server.domain(), &config).await.unwrap();
+        // Nothing is sent without a credential
+        website_text(&server.domain(), &ScrapeConfig::default()).await.unwrap();
+
+        let requests = server.requests.lock().unwrap();
+        assert!(requests[0].to_lowercase().contains("authorization: basic dxnlcjpzzwnyzxq="), "{}", requests[0]);
+        assert!(!requests[1].to_lowercase().contains("authorization"));
+    }
+
+    #[test]
+    fn test_invalid_header_is_rejected() {
+        assert!(ScrapeConfig::builder().header("Not a header", "x").build().is_err());
+        assert!(ScrapeConfig::builder().header("X-Test", "line\nbreak").build().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_requests_go_through_the_proxy() {
+        let proxy = TestServer::start(|_| http_response(200, &[], "<title>Via the proxy</title>")).await;
+        let config = ScrapeConfig::builder()
+            .proxy(format!("http://user:secret@{}", proxy.domain()))
+            .build()
+            .unwrap();
+        let page = website_text("example.invalid", &config).await.unwrap();
+        assert_eq!(page.keywords, "proxy");
+
+        // A proxy gets the whole URL, and the credentials from the proxy URL
+        let request = proxy.requests.lock().unwrap()[0].to_lowercase();
+        assert!(request.starts_with("get http://example.invalid/ http/1.1"), "{request}");
+        assert!(request.contains("proxy-authorization: basic dxnlcjpzzwnyzxq="));
+    }
+
+    #[tokio::test]
+    async fn test_unicode_domains_are_sent_as_punycode() {
+        let proxy = TestServer::start(|_| http_response(200, &[], "<title>Bücher</title>")).await;
+        let config = ScrapeConfig::builder().proxy(format!("http://{}", proxy.domain())).build().unwrap();
+        website_text("bücher.example", &config).await.unwrap();
+        let request = proxy.requests.lock().unwrap()[0].clone();
+        assert!(request.starts_with("GET http://xn--bcher-kva.example/ HTTP/1.1"), "{request}");
+
+        assert_eq!(ascii_domain("b%C3%BCcher.example").unwrap(), "xn--bcher-kva.example");
+        assert_eq!(ascii_domain("xn--bcher-kva.example").unwrap(), "xn--bcher-kva.example");
+        assert_eq!(ascii_domain("example.com").unwrap(), "example.com");
+    }
+
+    #[test]
+    fn test_invalid_proxy_is_rejected() {
+        assert!(ScrapeConfig::builder().proxy("not a url").build().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_latin1_body_is_decoded() {
+        let server = TestServer::start(|_| {
+            http_response(200, &[("Content-Type", "text/html; charset=ISO-8859-1")], b"<title>Caf\xe9 cr\xe8me br\xfbl\xe9e</title>")
+        }).await;
+        let page = website_text(&server.domain(), &ScrapeConfig::default()).await.unwrap();
+        assert_eq!(page.keywords, "brûlée café crème");
+
+        // The same, labeled with a meta tag instead of the header
+        let body = b"<html><head><meta charset=\"latin1\"><title>Caf\xe9 cr\xe8me</title></head></html>";
+        assert_eq!(decode_body(body, Some("text/html")), "<html><head><meta charset=\"latin1\"><title>Café crème</title></head></html>");
+        // Unlabeled junk is replaced, not an error
+        assert_eq!(decode_body(b"caf\xe9", None), "caf\u{FFFD}");
+    }
+
+    #[tokio::test]
+    async fn test_signal_headers_are_captured() {
+        let server = TestServer::start(|_| {
+            let headers = [
+                ("Server", "nginx"),
+                ("X-Powered-By", "Shopify"),
+                ("Set-Cookie", "_shopify_y=abc123; Path=/"),
+                ("X-Request-Id", "42"),
+            ];
+            http_response(200, &headers, "<title>Shop</title>")
+        }).await;
+        let page = website_text(&server.domain(), &ScrapeConfig::default()).await.unwrap();
+        assert_eq!(page.header_summary().unwrap(), "server: nginx, x-powered-by: Shopify, set-cookie: _shopify_y");
+
+        let config = ScrapeConfig::builder().signal_headers(["X-Request-Id"]).build().unwrap();
+        let page = website_text(&server.domain(), &config).await.unwrap();
+        assert_eq!(page.header_summary().unwrap(), "x-request-id: 42");
+    }
+
+    #[tokio::test]
+    async fn test_parking_page_is_flagged() {
+        let page = website_text("parked.example", &fixture_config()).await.unwrap();
+        assert_eq!(page.parked.as_deref(), Some("this domain may be for sale"));
+
+        let page = website_text("bakery.example", &fixture_config()).await.unwrap();
+        assert_eq!(page.parked, None);
+    }
+
+    #[test]
+    fn test_parking_phrases_are_configurable() {
+        let config = ScrapeConfig::builder().parking_phrases(["Coming Soon"]).build().unwrap();
+        let html = "<h1>Coming soon</h1><p>Our new website is under construction, please visit again later</p>";
+        assert_eq!(parked_signal(html, &config).as_deref(), Some("coming soon"));
+        assert_eq!(parked_signal(html, &ScrapeConfig::default()), None);
+    }
+
+    #[tokio::test]
+    async fn test_missing_fixture_is_an_error() {
+        assert!(website_text("missing.example", &fixture_config()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_prefetch_fills_the_cache() {
+        let lookups = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let dns = DnsCache::with_resolver({
+            let lookups = lookups.clone();
+            move |domain| {
+                lookups.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    match domain.ends_with(".invalid") {
+                        true => Vec::new(),
+                        false => vec!["192.0.2.1".parse().unwrap()],
+                    }
+                }
+            }
+        });
+        let domains = ["a.example", "gone.invalid", "b.example"].map(String::from);
+        assert_eq!(dns.prefetch(domains.clone(), 2).await, vec!["gone.invalid".to_string()]);
+        assert_eq!(lookups.load(Ordering::SeqCst), 3);
+
+        // Workers get the answers without another lookup
+        assert_eq!(dns.resolve("a.example").await.len(), 1);
+        assert!(dns.resolve("gone.invalid").await.is_empty());
+        assert_eq!(lookups.load(Ordering::SeqCst), 3);
+    }
+}
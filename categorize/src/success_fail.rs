@@ -0,0 +1,1059 @@
+//! Result sinks. Successes and failures are sent over channels to tasks that
+//! append them to files, so the workers never wait on file I/O.
+//!
+//! Each sink takes a channel capacity. A bigger channel soaks up bursts of
+//! results while the writer catches up, at the cost of holding more of them in
+//! memory (and losing more if the process dies). Once a channel is full,
+//! workers wait to send - which slows scraping down to the writer's pace.
+//!
+//! Every sink also returns the writer task's handle. Drop the sender and await
+//! the handle (see [`close`]) to be sure everything sent has been written.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::net::IpAddr;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use anyhow::Result;
+use serde::Serialize;
+use tokio::sync::mpsc::Sender;
+use tokio::task::JoinHandle;
+use load_data::split_incomplete_line;
+
+/// Used when no capacity is configured.
+pub const DEFAULT_CHANNEL_CAPACITY: usize = 32;
+
+pub(crate) async fn append_to_file(filename: impl AsRef<std::path::Path>, line: &str) -> Result<()> {
+    append_line(filename, line, false).await
+}
+
+/// Append a line. With `sync`, it's on the disk (not just handed to the OS)
+/// before this returns.
+async fn append_line(filename: impl AsRef<std::path::Path>, line: &str, sync: bool) -> Result<()> {
+    let mut file = tokio::fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(filename.as_ref())
+        .await?;
+    tokio::io::AsyncWriteExt::write_all(&mut file, format!("{}\n", line).as_bytes()).await?;
+    // Tokio finishes the write in the background unless we wait for it
+    tokio::io::AsyncWriteExt::flush(&mut file).await?;
+    if sync {
+        file.sync_data().await?;
+        #[cfg(test)]
+        tests::count_sync(filename.as_ref());
+    }
+    Ok(())
+}
+
+/// Stop a sink: nothing more can be sent, and this returns once the writer
+/// has written everything that was.
+pub async fn close<T>(tx: Sender<T>, writer: JoinHandle<()>) {
+    drop(tx);
+    if let Err(e) = writer.await {
+        tracing::error!("Result writer stopped early: {}", e);
+    }
+}
+
+/// Append `line` to a CSV file, writing `header` first if the file is new or empty.
+async fn append_csv_line(filename: impl AsRef<std::path::Path>, header: &str, line: &str) -> Result<()> {
+    let filename = filename.as_ref();
+    let is_empty = tokio::fs::metadata(filename).await.map(|m| m.len() == 0).unwrap_or(true);
+    if is_empty {
+        append_to_file(filename, header).await?;
+    }
+    append_to_file(filename, line).await
+}
+
+/// Why a domain couldn't be categorized.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailReason {
+    /// The domain doesn't resolve, so we never tried to fetch it
+    Nxdomain,
+    /// Fetching the website failed
+    Scrape,
+    /// The page didn't have enough distinct words to categorize
+    InsufficientContent,
+    /// The LLM didn't give us a category
+    Categorize,
+    /// The domain took longer than its overall time limit
+    Timeout,
+    /// The LLM refused to categorize it, so a person should look
+    NeedsReview,
+    /// The homepage's `Content-Length` was below the minimum
+    TooSmall,
+    /// Something went wrong in our code (a panic). The run carries on without it.
+    Internal,
+    /// Too little content, because the page is a shell that JavaScript fills in
+    JsRendered,
+    /// The TLS handshake failed: an old protocol version, or a bad certificate
+    Tls,
+    /// The homepage redirects round in a circle
+    RedirectLoop,
+    /// The LLM couldn't be connected to
+    LlmUnreachable,
+}
+
+impl FailReason {
+    /// Could trying the domain again work? A network blip or a busy LLM may
+    /// not happen next time; a domain that doesn't resolve will fail the same way.
+    pub fn is_transient(&self) -> bool {
+        matches!(self, Self::Scrape | Self::Categorize | Self::Timeout | Self::LlmUnreachable)
+    }
+}
+
+impl fmt::Display for FailReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Nxdomain => "nxdomain",
+            Self::Scrape => "scrape",
+            Self::InsufficientContent => "insufficient-content",
+            Self::Categorize => "categorize",
+            Self::Timeout => "timeout",
+            Self::NeedsReview => "needs-review",
+            Self::TooSmall => "too-small",
+            Self::Internal => "internal",
+            Self::JsRendered => "js-rendered",
+            Self::Tls => "tls",
+            Self::RedirectLoop => "redirect-loop",
+            Self::LlmUnreachable => "llm-unreachable",
+        };
+        f.write_str(name)
+    }
+}
+
+pub struct Failure {
+    pub domain: String,
+    pub reason: FailReason,
+}
+
+pub async fn failures(capacity: usize) -> (Sender<Failure>, JoinHandle<()>) {
+    failures_to(PathBuf::from("failures.txt"), capacity).await
+}
+
+/// Like [`failures`], but writing to `filename` instead of `failures.txt`.
+pub async fn failures_to(filename: PathBuf, capacity: usize) -> (Sender<Failure>, JoinHandle<()>) {
+    let (tx, mut rx) = tokio::sync::mpsc::channel::<Failure>(capacity.max(1));
+    let writer = tokio::spawn(async move {
+        if let Err(e) = repair_truncated(&filename).await {
+            tracing::error!("Failed to repair {}: {e}", filename.display());
+        }
+        while let Some(failure) = rx.recv().await {
+            tracing::warn!(domain = %failure.domain, reason = %failure.reason, "Failed to categorize");
+            let line = format!("{},{}", failure.domain, failure.reason);
+            if let Err(e) = append_csv_line(&filename, "domain,reason", &line).await {
+                tracing::error!("Failed to write to file: {}", e);
+            }
+        }
+    });
+    (tx, writer)
+}
+
+/// How many times each domain has failed, from a `failures.txt` that earlier
+/// runs have been appending to.
+pub fn failure_counts(text: &str) -> HashMap<String, usize> {
+    let mut counts = HashMap::new();
+    for line in text.lines().filter(|l| !l.trim().is_empty() && *l != "domain,reason") {
+        let domain = line.split(',').next().unwrap_or_default();
+        *counts.entry(domain.to_string()).or_insert(0) += 1;
+    }
+    counts
+}
+
+/// `text` without a last line that has no newline. Every row is written with
+/// one, so that's a row a crash cut short; it's logged and left out.
+pub fn complete_lines(text: &str) -> &str {
+    let (complete, incomplete) = split_incomplete_line(text);
+    if let Some(line) = incomplete {
+        tracing::warn!("Ignoring an incomplete last line: {line:?}");
+    }
+    complete
+}
+
+/// If `filename` ends with an incomplete line, cut it off. Otherwise the next
+/// row appended would be joined onto it.
+async fn repair_truncated(filename: &std::path::Path) -> Result<()> {
+    let Ok(text) = tokio::fs::read_to_string(filename).await else {
+        return Ok(());
+    };
+    let complete = complete_lines(&text);
+    if complete.len() != text.len() {
+        write_atomically(filename, complete, true).await?;
+    }
+    Ok(())
+}
+
+/// The domains in a results file (`categories.csv`, `failures.txt`, ...):
+/// the first column of every row, without any header.
+pub fn result_domains(text: &str) -> HashSet<String> {
+    complete_lines(text)
+        .lines()
+        .filter_map(|line| line.split(',').next())
+        .map(str::trim)
+        .filter(|domain| !domain.is_empty() && *domain != "domain")
+        .map(str::to_string)
+        .collect()
+}
+
+/// Put the domains that have failed before at the back, fewest failures
+/// first, so fresh domains get done first. The order is otherwise kept.
+pub fn failures_last<T>(items: &mut [T], domain: impl Fn(&T) -> &str, counts: &HashMap<String, usize>) {
+    items.sort_by_key(|item| counts.get(domain(item)).copied().unwrap_or(0));
+}
+
+/// Which IP versions a domain can be reached over.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AddressFamily {
+    Ipv4Only,
+    Ipv6Only,
+    DualStack,
+    /// The domain didn't resolve to anything
+    #[default]
+    Unknown,
+}
+
+impl AddressFamily {
+    /// Classify a set of resolved addresses (the domain's A and AAAA records).
+    pub fn from_addrs(addrs: impl IntoIterator<Item = IpAddr>) -> Self {
+        let (mut v4, mut v6) = (false, false);
+        for addr in addrs {
+            match addr {
+                IpAddr::V4(_) => v4 = true,
+                IpAddr::V6(_) => v6 = true,
+            }
+        }
+        match (v4, v6) {
+            (true, true) => Self::DualStack,
+            (true, false) => Self::Ipv4Only,
+            (false, true) => Self::Ipv6Only,
+            (false, false) => Self::Unknown,
+        }
+    }
+}
+
+impl fmt::Display for AddressFamily {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Ipv4Only => "ipv4",
+            Self::Ipv6Only => "ipv6",
+            Self::DualStack => "dual-stack",
+            Self::Unknown => "unknown",
+        };
+        f.write_str(name)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Domain {
+    pub domain: String,
+    pub category: String,
+    pub address_family: AddressFamily,
+    /// The homepage's HTTP status
+    pub http_status: Option<u16>,
+    /// How long the website took to fetch
+    pub fetch_time: Option<Duration>,
+    /// The keywords the category was chosen from
+    pub keywords: Option<String>,
+    /// Set if the page was the same as this earlier domain's, whose category was reused
+    pub duplicate_of: Option<String>,
+    /// The homepage's declared language (e.g. `de`)
+    pub language: Option<String>,
+    /// The homepage's `og:image` URL
+    pub image: Option<String>,
+    /// The homepage's favicon URL
+    pub favicon: Option<String>,
+    /// With an ensemble, how many of the valid answers (the second number)
+    /// were this category
+    pub agreement: Option<(usize, usize)>,
+    /// Set if the site couldn't be scraped, and the category came from the
+    /// domain name alone
+    pub from_name: bool,
+}
+
+/// Which optional columns the success sink writes.
+#[derive(Clone, Copy, Default)]
+pub struct SuccessOptions {
+    /// Add `http_status` and `fetch_ms` columns
+    pub record_fetch: bool,
+    /// Keep one row per domain, replacing its existing row. The old rows stay
+    /// until the file is compacted, every so often and at the end.
+    pub upsert: bool,
+    /// Add a `keywords` column with the top few keywords
+    pub store_keywords: bool,
+    /// Add the page's language, as `lang=de` (`lang=` if it didn't say).
+    /// It goes before the keywords, which are always last.
+    pub store_language: bool,
+    /// Add the homepage's `og:image` and favicon URLs, as `image=..` and
+    /// `favicon=..` (empty if it had none), after the language
+    pub store_images: bool,
+    /// Make sure the file is on disk after every this many results, so a
+    /// power cut can't lose them. Slower; zero leaves it to the OS.
+    pub fsync_every: usize,
+}
+
+impl SuccessOptions {
+    /// Should the `count`th result (counting from 1) be synced?
+    fn sync_after(&self, count: usize) -> bool {
+        self.fsync_every > 0 && count.is_multiple_of(self.fsync_every)
+    }
+}
+
+/// How many keywords `store_keywords` keeps.
+pub const STORED_KEYWORDS: usize = 20;
+
+/// Quote a CSV field if it needs it.
+pub(crate) fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// The `categories.csv` line for a domain.
+fn success_line(domain: &Domain, options: SuccessOptions) -> String {
+    let mut line = format!("{},{},{}", domain.domain, csv_field(&domain.category), domain.address_family);
+    if options.record_fetch {
+        let status = domain.http_status.map(|s| s.to_string()).unwrap_or_default();
+        let fetch_ms = domain.fetch_time.map(|t| t.as_millis().to_string()).unwrap_or_default();
+        line.push_str(&format!(",{status},{fetch_ms}"));
+    }
+    if options.store_language {
+        line.push_str(&format!(",lang={}", domain.language.as_deref().unwrap_or_default()));
+    }
+    if options.store_images {
+        let image = format!("image={}", domain.image.as_deref().unwrap_or_default());
+        let favicon = format!("favicon={}", domain.favicon.as_deref().unwrap_or_default());
+        line.push_str(&format!(",{},{}", csv_field(&image), csv_field(&favicon)));
+    }
+    // Always written, since it's easy to mistake for a category from the content
+    if domain.from_name {
+        line.push_str(",from=name");
+    }
+    // The category was this earlier domain's, for the same page
+    if let Some(original) = &domain.duplicate_of {
+        line.push_str(&format!(",dup={original}"));
+    }
+    // How sure the ensemble was, so it's there without --events
+    if let Some((votes, of)) = domain.agreement {
+        line.push_str(&format!(",agreement={votes}/{of}"));
+    }
+    if options.store_keywords {
+        let keywords = domain.keywords.as_deref().unwrap_or_default();
+        let top = keywords.split_whitespace().take(STORED_KEYWORDS).collect::<Vec<_>>().join(" ");
+        line.push_str(&format!(",{}", csv_field(&top)));
+    }
+    line
+}
+
+/// The lines of a results file, keyed by domain (the first column). Adding a
+/// domain that's already there replaces its line, keeping its place.
+#[derive(Default)]
+struct Upsert {
+    lines: Vec<String>,
+    index: HashMap<String, usize>,
+}
+
+impl Upsert {
+    fn parse(text: &str) -> Self {
+        let mut rows = Self::default();
+        for line in complete_lines(text).lines().filter(|l| !l.trim().is_empty()) {
+            let domain = line.split(',').next().unwrap_or_default();
+            rows.insert(domain, line.to_string());
+        }
+        rows
+    }
+
+    fn insert(&mut self, domain: &str, line: String) {
+        match self.index.get(domain) {
+            Some(&i) => self.lines[i] = line,
+            None => {
+                self.index.insert(domain.to_string(), self.lines.len());
+                self.lines.push(line);
+            }
+        }
+    }
+
+    fn contents(&self) -> String {
+        self.lines.iter().map(|line| format!("{line}\n")).collect()
+    }
+}
+
+/// The domains in a `categories.csv` that currently have `category` (case is
+/// ignored), each once.
+pub fn domains_in_category(text: &str, category: &str) -> Vec<String> {
+    Upsert::parse(text)
+        .lines
+        .iter()
+        .filter_map(|line| {
+            // The category may be quoted
+            let record = csv::ReaderBuilder::new()
+                .has_headers(false)
+                .flexible(true)
+                .from_reader(line.as_bytes())
+                .into_records()
+                .next()?
+                .ok()?;
+            let current = record.get(1)?;
+            current.eq_ignore_ascii_case(category).then(|| record.get(0).unwrap_or_default().to_string())
+        })
+        .collect()
+}
+
+/// Replace a file in one step, via a temporary file, so a crash can't leave
+/// half of it. With `sync`, the new contents are on disk before the swap,
+/// and the swap itself (the directory entry) is on disk afterwards.
+async fn write_atomically(filename: &std::path::Path, contents: &str, sync: bool) -> Result<()> {
+    let tmp = filename.with_extension("tmp");
+    let mut file = tokio::fs::File::create(&tmp).await?;
+    tokio::io::AsyncWriteExt::write_all(&mut file, contents.as_bytes()).await?;
+    tokio::io::AsyncWriteExt::flush(&mut file).await?;
+    if sync {
+        file.sync_data().await?;
+    }
+    tokio::fs::rename(&tmp, filename).await?;
+    // Windows can't open a directory to sync it
+    if sync && cfg!(unix) {
+        let dir = match filename.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => std::path::Path::new("."),
+        };
+        tokio::fs::File::open(dir).await?.sync_all().await?;
+    }
+    Ok(())
+}
+
+/// How many replaced rows an upserted file can build up before it's rewritten.
+const COMPACT_AFTER: usize = 1000;
+
+/// Keep "categories.csv" to one row per domain. Every result is appended, so
+/// a replaced domain has its old row until the file is rewritten without
+/// them, after [`COMPACT_AFTER`] replacements and when the writer finishes.
+/// Readers already take the last row for a domain in the meantime.
+async fn upsert_successes(filename: PathBuf, mut rx: tokio::sync::mpsc::Receiver<Domain>, options: SuccessOptions) {
+    let existing = tokio::fs::read_to_string(&filename).await.unwrap_or_default();
+    let mut rows = Upsert::parse(&existing);
+    // A crash left part of a row at the end, so write the file out clean
+    if complete_lines(&existing).len() != existing.len() {
+        if let Err(e) = write_atomically(&filename, &rows.contents(), true).await {
+            tracing::error!("Failed to repair {}: {e}", filename.display());
+        }
+    }
+    let mut count = 0;
+    let mut stale = 0;
+    while let Some(domain) = rx.recv().await {
+        tracing::info!(domain = %domain.domain, category = %domain.category, address_family = %domain.address_family, "Categorized");
+        count += 1;
+        let line = success_line(&domain, options);
+        if rows.index.contains_key(&domain.domain) {
+            stale += 1;
+        }
+        rows.insert(&domain.domain, line.clone());
+        if let Err(e) = append_line(&filename, &line, options.sync_after(count)).await {
+            tracing::error!("Failed to write to file: {}", e);
+        }
+        if stale >= COMPACT_AFTER {
+            compact(&filename, &rows, options).await;
+            stale = 0;
+        }
+    }
+    if stale > 0 {
+        compact(&filename, &rows, options).await;
+    }
+}
+
+/// Rewrite an upserted file with one row per domain.
+async fn compact(filename: &std::path::Path, rows: &Upsert, options: SuccessOptions) {
+    if let Err(e) = write_atomically(filename, &rows.contents(), options.fsync_every > 0).await {
+        tracing::error!("Failed to rewrite {}: {e}", filename.display());
+    }
+}
+
+pub async fn success(options: SuccessOptions, capacity: usize) -> (Sender<Domain>, JoinHandle<()>) {
+    success_to(PathBuf::from("categories.csv"), options, capacity).await
+}
+
+/// Like [`success`], but writing to `filename` instead of `categories.csv`.
+pub async fn success_to(filename: PathBuf, options: SuccessOptions, capacity: usize) -> (Sender<Domain>, JoinHandle<()>) {
+    let (tx, mut rx) = tokio::sync::mpsc::channel::<Domain>(capacity.max(1));
+    if options.upsert {
+        let writer = tokio::spawn(upsert_successes(filename, rx, options));
+        return (tx, writer);
+    }
+    let writer = tokio::spawn(async move {
+        if let Err(e) = repair_truncated(&filename).await {
+            tracing::error!("Failed to repair {}: {e}", filename.display());
+        }
+        let mut count = 0;
+        while let Some(domain) = rx.recv().await {
+            tracing::info!(domain = %domain.domain, category = %domain.category, address_family = %domain.address_family, "Categorized");
+            count += 1;
+            let line = success_line(&domain, options);
+            if let Err(e) = append_line(&filename, &line, options.sync_after(count)).await {
+                tracing::error!("Failed to write to file: {}", e);
+            }
+        }
+    });
+    (tx, writer)
+}
+
+/// A domain that looks parked, and what gave it away.
+pub struct Parked {
+    pub domain: String,
+    pub signal: String,
+}
+
+pub async fn parked(capacity: usize) -> (Sender<Parked>, JoinHandle<()>) {
+    parked_to(PathBuf::from("parked.csv"), capacity).await
+}
+
+/// Like [`parked`], but writing to `filename` instead of `parked.csv`.
+pub async fn parked_to(filename: PathBuf, capacity: usize) -> (Sender<Parked>, JoinHandle<()>) {
+    let (tx, mut rx) = tokio::sync::mpsc::channel::<Parked>(capacity.max(1));
+    let writer = tokio::spawn(async move {
+        while let Some(parked) = rx.recv().await {
+            tracing::info!(domain = %parked.domain, signal = %parked.signal, "Parked");
+            let line = format!("{},{}", parked.domain, parked.signal);
+            if let Err(e) = append_to_file(&filename, &line).await {
+                tracing::error!("Failed to write to file: {}", e);
+            }
+        }
+    });
+    (tx, writer)
+}
+
+/// Domains skipped because their site hasn't changed, one per line.
+pub async fn unchanged(capacity: usize) -> (Sender<String>, JoinHandle<()>) {
+    unchanged_to(PathBuf::from("unchanged.txt"), capacity).await
+}
+
+/// Like [`unchanged`], but writing to `filename` instead of `unchanged.txt`.
+pub async fn unchanged_to(filename: PathBuf, capacity: usize) -> (Sender<String>, JoinHandle<()>) {
+    let (tx, mut rx) = tokio::sync::mpsc::channel::<String>(capacity.max(1));
+    let writer = tokio::spawn(async move {
+        while let Some(domain) = rx.recv().await {
+            tracing::info!(domain = %domain, "Unchanged");
+            if let Err(e) = append_to_file(&filename, &domain).await {
+                tracing::error!("Failed to write to file: {}", e);
+            }
+        }
+    });
+    (tx, writer)
+}
+
+/// Somewhere to put results. [`FileSink`] writes the usual files; anything
+/// else (a database, stdout, your own code) can implement this instead.
+pub trait ResultSink: Send + Sync {
+    fn record_success(&self, domain: &Domain) -> impl Future<Output = ()> + Send;
+    fn record_failure(&self, domain: &str, reason: FailReason) -> impl Future<Output = ()> + Send;
+    fn record_parked(&self, domain: &str, signal: &str) -> impl Future<Output = ()> + Send;
+    fn record_unchanged(&self, domain: &str) -> impl Future<Output = ()> + Send;
+}
+
+/// The standard sink: categories.csv, failures.txt, parked.csv and unchanged.txt.
+pub struct FileSink {
+    success: Sender<Domain>,
+    failures: Sender<Failure>,
+    parked: Sender<Parked>,
+    unchanged: Sender<String>,
+    writers: Vec<JoinHandle<()>>,
+}
+
+impl FileSink {
+    pub async fn new(options: SuccessOptions, capacity: usize) -> Self {
+        Self::in_dir(Path::new("."), options, capacity).await
+    }
+
+    /// Write the usual files, but in `dir`.
+    pub async fn in_dir(dir: &Path, options: SuccessOptions, capacity: usize) -> Self {
+        let (success, success_writer) = success_to(dir.join("categories.csv"), options, capacity).await;
+        let (failures, failures_writer) = failures_to(dir.join("failures.txt"), capacity).await;
+        let (parked, parked_writer) = parked_to(dir.join("parked.csv"), capacity).await;
+        let (unchanged, unchanged_writer) = unchanged_to(dir.join("unchanged.txt"), capacity).await;
+        Self {
+            success,
+            failures,
+            parked,
+            unchanged,
+            writers: vec![success_writer, failures_writer, parked_writer, unchanged_writer],
+        }
+    }
+
+    /// Stop accepting results, and wait until every file is written.
+    pub async fn close(self) {
+        drop((self.success, self.failures, self.parked, self.unchanged));
+        for writer in self.writers {
+            if let Err(e) = writer.await {
+                tracing::error!("Result writer stopped early: {}", e);
+            }
+        }
+    }
+}
+
+impl ResultSink for FileSink {
+    async fn record_success(&self, domain: &Domain) {
+        let _ = self.success.send(domain.clone()).await;
+    }
+
+    async fn record_failure(&self, domain: &str, reason: FailReason) {
+        let _ = self.failures.send(Failure { domain: domain.to_string(), reason }).await;
+    }
+
+    async fn record_parked(&self, domain: &str, signal: &str) {
+        let _ = self.parked.send(Parked { domain: domain.to_string(), signal: signal.to_string() }).await;
+    }
+
+    async fn record_unchanged(&self, domain: &str) {
+        let _ = self.unchanged.send(domain.to_string()).await;
+    }
+}
+
+/// Two sinks at once (e.g. the files plus an [`EventSink`]).
+impl<A: ResultSink, B: ResultSink> ResultSink for (A, B) {
+    async fn record_success(&self, domain: &Domain) {
+        self.0.record_success(domain).await;
+        self.1.record_success(domain).await;
+    }
+
+    async fn record_failure(&self, domain: &str, reason: FailReason) {
+        self.0.record_failure(domain, reason).await;
+        self.1.record_failure(domain, reason).await;
+    }
+
+    async fn record_parked(&self, domain: &str, signal: &str) {
+        self.0.record_parked(domain, signal).await;
+        self.1.record_parked(domain, signal).await;
+    }
+
+    async fn record_unchanged(&self, domain: &str) {
+        self.0.record_unchanged(domain).await;
+        self.1.record_unchanged(domain).await;
+    }
+}
+
+/// A sink that may not be there.
+impl<S: ResultSink> ResultSink for Option<S> {
+    async fn record_success(&self, domain: &Domain) {
+        if let Some(sink) = self {
+            sink.record_success(domain).await;
+        }
+    }
+
+    async fn record_failure(&self, domain: &str, reason: FailReason) {
+        if let Some(sink) = self {
+            sink.record_failure(domain, reason).await;
+        }
+    }
+
+    async fn record_parked(&self, domain: &str, signal: &str) {
+        if let Some(sink) = self {
+            sink.record_parked(domain, signal).await;
+        }
+    }
+
+    async fn record_unchanged(&self, domain: &str) {
+        if let Some(sink) = self {
+            sink.record_unchanged(domain).await;
+        }
+    }
+}
+
+/// Why a domain was skipped rather than categorized.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SkipReason {
+    /// It looks parked: what gave it away
+    Parked(String),
+    /// The site hasn't changed since the last run
+    Unchanged,
+}
+
+/// One domain's result, for code that wants results as they happen instead
+/// of reading the files.
+#[derive(Debug, Clone)]
+pub enum RunEvent {
+    DomainSucceeded(Domain),
+    DomainFailed { domain: String, reason: FailReason },
+    DomainSkipped { domain: String, reason: SkipReason },
+}
+
+impl RunEvent {
+    /// The event as a JSON object, e.g. `{"event":"failed","domain":"a.com","reason":"nxdomain"}`.
+    pub fn to_json(&self) -> serde_json::Value {
+        match self {
+            Self::DomainSucceeded(domain) => serde_json::json!({
+                "event": "succeeded",
+                "domain": domain.domain,
+                "category": domain.category,
+                "address_family": domain.address_family.to_string(),
+                "image": domain.image,
+                "favicon": domain.favicon,
+                "agreement": domain.agreement.map(|(votes, of)| serde_json::json!({ "votes": votes, "of": of })),
+            }),
+            Self::DomainFailed { domain, reason } => serde_json::json!({
+                "event": "failed",
+                "domain": domain,
+                "reason": reason.to_string(),
+            }),
+            Self::DomainSkipped { domain, reason: SkipReason::Parked(signal) } => serde_json::json!({
+                "event": "skipped",
+                "domain": domain,
+                "parked": signal,
+            }),
+            Self::DomainSkipped { domain, reason: SkipReason::Unchanged } => serde_json::json!({
+                "event": "skipped",
+                "domain": domain,
+                "unchanged": true,
+            }),
+        }
+    }
+}
+
+/// Sends a [`RunEvent`] for every result.
+pub struct EventSink(pub Sender<RunEvent>);
+
+impl ResultSink for EventSink {
+    async fn record_success(&self, domain: &Domain) {
+        let _ = self.0.send(RunEvent::DomainSucceeded(domain.clone())).await;
+    }
+
+    async fn record_failure(&self, domain: &str, reason: FailReason) {
+        let _ = self.0.send(RunEvent::DomainFailed { domain: domain.to_string(), reason }).await;
+    }
+
+    async fn record_parked(&self, domain: &str, signal: &str) {
+        let reason = SkipReason::Parked(signal.to_string());
+        let _ = self.0.send(RunEvent::DomainSkipped { domain: domain.to_string(), reason }).await;
+    }
+
+    async fn record_unchanged(&self, domain: &str) {
+        let reason = SkipReason::Unchanged;
+        let _ = self.0.send(RunEvent::DomainSkipped { domain: domain.to_string(), reason }).await;
+    }
+}
+
+/// One LLM interaction, for the audit log.
+#[derive(Serialize)]
+pub struct AuditRecord {
+    pub domain: String,
+    pub prompt: String,
+    /// The raw LLM output
+    pub response: String,
+    /// The category we took from the response, if any
+    pub category: Option<String>,
+    /// Whether the category was used
+    pub accepted: bool,
+}
+
+/// Write audit records to `filename`, one JSON object per line.
+pub async fn audit(filename: PathBuf, capacity: usize) -> (Sender<AuditRecord>, JoinHandle<()>) {
+    let (tx, mut rx) = tokio::sync::mpsc::channel::<AuditRecord>(capacity.max(1));
+    let writer = tokio::spawn(async move {
+        while let Some(record) = rx.recv().await {
+            let line = match serde_json::to_string(&record) {
+                Ok(line) => line,
+                Err(e) => {
+                    tracing::error!("Failed to serialize audit record: {}", e);
+                    continue;
+                }
+            };
+            if let Err(e) = append_to_file(&filename, &line).await {
+                tracing::error!("Failed to write to file: {}", e);
+            }
+        }
+    });
+    (tx, writer)
+}
+
+/// Write run events to `filename` as JSON lines.
+pub async fn events(filename: PathBuf, capacity: usize) -> (Sender<RunEvent>, JoinHandle<()>) {
+    let (tx, mut rx) = tokio::sync::mpsc::channel::<RunEvent>(capacity.max(1));
+    let writer = tokio::spawn(async move {
+        while let Some(event) = rx.recv().await {
+            if let Err(e) = append_to_file(&filename, &event.to_json().to_string()).await {
+                tracing::error!("Failed to write to file: {}", e);
+            }
+        }
+    });
+    (tx, writer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// How many times each file has been synced after an append.
+    static SYNCS: std::sync::Mutex<Option<HashMap<PathBuf, usize>>> = std::sync::Mutex::new(None);
+
+    pub(super) fn count_sync(path: &std::path::Path) {
+        *SYNCS.lock().unwrap().get_or_insert_with(HashMap::new).entry(path.to_path_buf()).or_default() += 1;
+    }
+
+    fn synced(path: &std::path::Path) -> usize {
+        SYNCS.lock().unwrap().as_ref().and_then(|syncs| syncs.get(path).copied()).unwrap_or_default()
+    }
+
+    #[tokio::test]
+    async fn test_channel_capacity_is_configurable() {
+        assert_eq!(failures(8).await.0.max_capacity(), 8);
+        assert_eq!(parked(DEFAULT_CHANNEL_CAPACITY).await.0.max_capacity(), 32);
+        assert_eq!(success(SuccessOptions::default(), 1000).await.0.max_capacity(), 1000);
+        // Tokio can't make a channel with no room at all
+        assert_eq!(unchanged(0).await.0.max_capacity(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_close_waits_for_everything_to_be_written() {
+        let path = std::env::temp_dir().join(format!("audit-close-test-{}.jsonl", std::process::id()));
+        let (tx, writer) = audit(path.clone(), 4).await;
+        for n in 0..50 {
+            let record = AuditRecord {
+                domain: format!("{n}.example"),
+                prompt: "prompt".to_string(),
+                response: "News".to_string(),
+                category: Some("News".to_string()),
+                accepted: true,
+            };
+            tx.send(record).await.unwrap();
+        }
+        close(tx, writer).await;
+
+        let text = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(text.lines().count(), 50);
+        assert!(text.ends_with("\"accepted\":true}\n"));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_sync_intervals() {
+        // Off by default: the OS decides when
+        let options = SuccessOptions::default();
+        assert!((1..=10).all(|count| !options.sync_after(count)));
+        let every = |n| SuccessOptions { fsync_every: n, ..Default::default() };
+        assert!((1..=10).all(|count| every(1).sync_after(count)));
+        assert_eq!((1..=10).filter(|&count| every(3).sync_after(count)).collect::<Vec<_>>(), vec![3, 6, 9]);
+    }
+
+    #[tokio::test]
+    async fn test_results_are_synced_every_n() {
+        for upsert in [false, true] {
+            for (fsync_every, expected) in [(0, 0), (1, 5), (2, 2)] {
+                let path = std::env::temp_dir().join(format!("fsync-test-{upsert}-{fsync_every}-{}.csv", std::process::id()));
+                let _ = std::fs::remove_file(&path);
+                let options = SuccessOptions { upsert, fsync_every, ..Default::default() };
+                let (tx, writer) = success_to(path.clone(), options, 4).await;
+                for n in 0..5 {
+                    let domain = Domain { domain: format!("{n}.example"), category: "News".to_string(), ..Default::default() };
+                    tx.send(domain).await.unwrap();
+                }
+                close(tx, writer).await;
+
+                assert_eq!(synced(&path), expected, "upsert {upsert}, every {fsync_every}");
+                assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 5);
+                std::fs::remove_file(path).unwrap();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_truncated_last_line_is_recovered() {
+        let truncated = "a.com,News,ipv4\nb.com,Gaming,ipv4\nc.com,Ret";
+        assert_eq!(result_domains(truncated), HashSet::from(["a.com".to_string(), "b.com".to_string()]));
+
+        for upsert in [false, true] {
+            let path = std::env::temp_dir().join(format!("truncated-test-{upsert}-{}.csv", std::process::id()));
+            std::fs::write(&path, truncated).unwrap();
+            let (tx, writer) = success_to(path.clone(), SuccessOptions { upsert, ..Default::default() }, 4).await;
+            let domain = Domain {
+                domain: "d.com".to_string(),
+                category: "News".to_string(),
+                address_family: AddressFamily::Ipv4Only,
+                ..Default::default()
+            };
+            tx.send(domain).await.unwrap();
+            close(tx, writer).await;
+
+            let text = std::fs::read_to_string(&path).unwrap();
+            assert_eq!(text, "a.com,News,ipv4\nb.com,Gaming,ipv4\nd.com,News,ipv4\n", "upsert {upsert}");
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_upsert_sink_replaces_rows() {
+        let path = std::env::temp_dir().join(format!("upsert-test-{}.csv", std::process::id()));
+        std::fs::write(&path, "a.com,News,ipv4\nb.com,Gaming,ipv4\n").unwrap();
+        let (tx, writer) = success_to(path.clone(), SuccessOptions { upsert: true, ..Default::default() }, 4).await;
+        let domain = |domain: &str, category: &str| Domain {
+            domain: domain.to_string(),
+            category: category.to_string(),
+            address_family: AddressFamily::Ipv4Only,
+            ..Default::default()
+        };
+        tx.send(domain("a.com", "Technology")).await.unwrap();
+        tx.send(domain("c.com", "Retail")).await.unwrap();
+
+        // Until it's closed, the new rows are appended, and the latest one counts
+        let appended = "a.com,News,ipv4\nb.com,Gaming,ipv4\na.com,Technology,ipv4\nc.com,Retail,ipv4\n";
+        for _ in 0..500 {
+            if std::fs::read_to_string(&path).unwrap() == appended {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), appended);
+        assert_eq!(domains_in_category(appended, "Technology"), vec!["a.com"]);
+
+        close(tx, writer).await;
+        let text = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(text, "a.com,Technology,ipv4\nb.com,Gaming,ipv4\nc.com,Retail,ipv4\n");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_upsert_replaces_rows() {
+        let mut rows = Upsert::parse("a.com,News,ipv4\nb.com,Gaming,ipv4\n");
+        rows.insert("a.com", "a.com,Technology,ipv4".to_string());
+        rows.insert("c.com", "c.com,Retail,ipv6".to_string());
+        assert_eq!(rows.contents(), "a.com,Technology,ipv4\nb.com,Gaming,ipv4\nc.com,Retail,ipv6\n");
+
+        // Duplicates from earlier append-only runs collapse to the latest
+        let rows = Upsert::parse("a.com,News\na.com,Technology\n");
+        assert_eq!(rows.contents(), "a.com,Technology\n");
+    }
+
+    #[test]
+    fn test_select_domains_by_category() {
+        let csv = "a.com,Other,ipv4\nb.com,Gaming,ipv4\nc.com,other,dual-stack,200,15\nb.com,Other,ipv4\na.com,News,ipv4\n";
+        // The latest row for each domain is the one that counts
+        assert_eq!(domains_in_category(csv, "Other"), vec!["b.com", "c.com"]);
+        assert_eq!(domains_in_category(csv, "News"), vec!["a.com"]);
+        assert!(domains_in_category(csv, "Retail").is_empty());
+
+        let csv = "a.com,\"Arts, Culture\",ipv4\nb.com,Arts,ipv4\n";
+        assert_eq!(domains_in_category(csv, "Arts, Culture"), vec!["a.com"]);
+    }
+
+    #[test]
+    fn test_failed_domains_go_last() {
+        let counts = failure_counts("domain,reason\nflaky.com,timeout\nbroken.com,scrape\nflaky.com,timeout\n");
+        assert_eq!(counts["flaky.com"], 2);
+        let mut domains = vec!["flaky.com", "a.com", "broken.com", "b.com"];
+        failures_last(&mut domains, |d| d, &counts);
+        assert_eq!(domains, vec!["a.com", "b.com", "broken.com", "flaky.com"]);
+    }
+
+    #[tokio::test]
+    async fn test_header_is_written_once() {
+        let path = std::env::temp_dir().join(format!("failures-test-{}.txt", std::process::id()));
+        append_csv_line(&path, "domain,reason", "a.com,nxdomain").await.unwrap();
+        append_csv_line(&path, "domain,reason", "b.com,timeout").await.unwrap();
+        let text = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(text, "domain,reason\na.com,nxdomain\nb.com,timeout\n");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_fetch_columns_are_optional() {
+        let domain = Domain {
+            domain: "example.com".to_string(),
+            category: "Technology".to_string(),
+            address_family: AddressFamily::DualStack,
+            http_status: Some(200),
+            fetch_time: Some(Duration::from_millis(1234)),
+            keywords: Some("software cloud, apps".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(success_line(&domain, SuccessOptions::default()), "example.com,Technology,dual-stack");
+        assert_eq!(
+            success_line(&domain, SuccessOptions { record_fetch: true, ..Default::default() }),
+            "example.com,Technology,dual-stack,200,1234"
+        );
+    }
+
+    #[test]
+    fn test_keywords_column_is_optional() {
+        let keywords = (1..=30).map(|n| format!("word{n}")).collect::<Vec<_>>().join(" ");
+        let domain = Domain {
+            domain: "example.com".to_string(),
+            category: "Technology".to_string(),
+            address_family: AddressFamily::Ipv4Only,
+            http_status: Some(200),
+            fetch_time: None,
+            keywords: Some(format!("cloud, {keywords}")),
+            ..Default::default()
+        };
+        assert_eq!(success_line(&domain, SuccessOptions::default()), "example.com,Technology,ipv4");
+        // A category with a comma is still one column
+        let arts = Domain { category: "Arts, Culture".to_string(), ..domain.clone() };
+        assert_eq!(success_line(&arts, SuccessOptions::default()), "example.com,\"Arts, Culture\",ipv4");
+
+        let line = success_line(&domain, SuccessOptions { store_keywords: true, ..Default::default() });
+        assert!(line.starts_with("example.com,Technology,ipv4,\"cloud, word1 word2 "), "{line}");
+        // Only the top few are kept
+        assert!(line.ends_with(" word19\""), "{line}");
+
+        let options = SuccessOptions { store_keywords: true, store_language: true, ..Default::default() };
+        let domain = Domain { language: Some("de".to_string()), ..domain };
+        assert!(success_line(&domain, options).starts_with("example.com,Technology,ipv4,lang=de,\"cloud, "));
+    }
+
+    #[test]
+    fn test_ensemble_agreement_is_written_to_the_row() {
+        let domain = Domain {
+            domain: "games.example".to_string(),
+            category: "Gaming".to_string(),
+            agreement: Some((2, 3)),
+            keywords: Some("games consoles".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(success_line(&domain, SuccessOptions::default()), "games.example,Gaming,unknown,agreement=2/3");
+        // Before the keywords, which are always last
+        let options = SuccessOptions { store_keywords: true, ..Default::default() };
+        assert_eq!(success_line(&domain, options), "games.example,Gaming,unknown,agreement=2/3,games consoles");
+        // No ensemble, no agreement
+        assert_eq!(success_line(&Domain { agreement: None, ..domain }, SuccessOptions::default()), "games.example,Gaming,unknown");
+    }
+
+    #[tokio::test]
+    async fn test_images_are_written_to_the_row() {
+        let path = std::env::temp_dir().join(format!("success-images-test-{}.csv", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let options = SuccessOptions { store_images: true, ..Default::default() };
+        let (tx, writer) = success_to(path.clone(), options, 4).await;
+        let domain = |name: &str, image: Option<&str>, favicon: Option<&str>| Domain {
+            domain: name.to_string(),
+            category: "Gaming".to_string(),
+            image: image.map(str::to_string),
+            favicon: favicon.map(str::to_string),
+            ..Default::default()
+        };
+        tx.send(domain("games.example", Some("https://games.example/og.png?w=1,h=1"), Some("https://games.example/favicon.ico"))).await.unwrap();
+        tx.send(domain("plain.example", None, None)).await.unwrap();
+        close(tx, writer).await;
+
+        let text = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(text, concat!(
+            "games.example,Gaming,unknown,\"image=https://games.example/og.png?w=1,h=1\",favicon=https://games.example/favicon.ico\n",
+            "plain.example,Gaming,unknown,image=,favicon=\n",
+        ));
+        // Not unless asked for
+        assert_eq!(success_line(&domain("games.example", Some("https://games.example/og.png"), None), SuccessOptions::default()), "games.example,Gaming,unknown");
+    }
+
+    #[test]
+    fn test_aaaa_only_is_ipv6_only() {
+        let addrs: Vec<IpAddr> = vec!["2001:db8::1".parse().unwrap(), "2001:db8::2".parse().unwrap()];
+        assert_eq!(AddressFamily::from_addrs(addrs), AddressFamily::Ipv6Only);
+    }
+
+    #[test]
+    fn test_address_family_classification() {
+        let v4: IpAddr = "192.0.2.1".parse().unwrap();
+        let v6: IpAddr = "2001:db8::1".parse().unwrap();
+        assert_eq!(AddressFamily::from_addrs([v4]), AddressFamily::Ipv4Only);
+        assert_eq!(AddressFamily::from_addrs([v4, v6]), AddressFamily::DualStack);
+        assert_eq!(AddressFamily::from_addrs([]), AddressFamily::Unknown);
+    }
+}
@@ -0,0 +1,231 @@
+//! Helpers shared by the tests. Each is built with the features its tests
+//! need: the mock LLMs with `llm`, the HTTP server with either network
+//! feature, and the memory sink with both.
+
+use std::sync::{Arc, Mutex};
+#[cfg(feature = "llm")]
+use {
+    std::collections::VecDeque,
+    std::future::Future,
+    anyhow::Result,
+    crate::llm::Completion,
+};
+#[cfg(any(feature = "scrape", feature = "llm"))]
+use {
+    std::sync::atomic::{AtomicUsize, Ordering},
+    tokio::io::{AsyncReadExt, AsyncWriteExt},
+};
+#[cfg(all(feature = "scrape", feature = "llm"))]
+use crate::success_fail::{Domain, FailReason, ResultSink};
+
+/// An LLM that replies with canned responses, in order. Once they run out,
+/// the last one is repeated. Every prompt it's given is kept.
+///
+/// Its embeddings are bags of words: texts sharing words are similar.
+#[cfg(feature = "llm")]
+pub struct MockLlm {
+    responses: Mutex<VecDeque<String>>,
+    pub prompts: Mutex<Vec<String>>,
+    /// Every text it's been asked to embed
+    pub embedded: Mutex<Vec<String>>,
+}
+
+#[cfg(feature = "llm")]
+impl MockLlm {
+    pub fn new<S: ToString>(responses: impl IntoIterator<Item = S>) -> Self {
+        Self {
+            responses: Mutex::new(responses.into_iter().map(|r| r.to_string()).collect()),
+            prompts: Mutex::new(Vec::new()),
+            embedded: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+#[cfg(feature = "llm")]
+impl Completion for MockLlm {
+    fn complete(&self, prompt: &str) -> impl Future<Output = Result<String>> + Send {
+        self.prompts.lock().unwrap().push(prompt.to_string());
+        let mut responses = self.responses.lock().unwrap();
+        let response = if responses.len() > 1 {
+            responses.pop_front()
+        } else {
+            responses.front().cloned()
+        };
+        async move { response.ok_or_else(|| anyhow::anyhow!("No canned response")) }
+    }
+
+    fn embed(&self, text: &str) -> impl Future<Output = Result<Vec<f32>>> + Send {
+        use std::hash::{Hash, Hasher};
+        self.embedded.lock().unwrap().push(text.to_string());
+        let mut vector = vec![0.0; 256];
+        for word in text.split(|c: char| !c.is_alphanumeric()).filter(|w| !w.is_empty()) {
+            let mut hasher = std::collections::hash_map::DefaultHasher::new();
+            word.to_lowercase().hash(&mut hasher);
+            vector[hasher.finish() as usize % 256] += 1.0;
+        }
+        async move { Ok(vector) }
+    }
+}
+
+/// An LLM that replays answers recorded from a real one: `responses.csv`
+/// of `domain,response`, picked by the domain named in the prompt.
+#[cfg(all(feature = "scrape", feature = "llm"))]
+pub struct RecordedLlm {
+    responses: Vec<(String, String)>,
+}
+
+#[cfg(all(feature = "scrape", feature = "llm"))]
+impl RecordedLlm {
+    pub fn load(path: &std::path::Path) -> Result<Self> {
+        let responses = csv::Reader::from_path(path)?
+            .deserialize::<(String, String)>()
+            .collect::<Result<_, _>>()?;
+        Ok(Self { responses })
+    }
+}
+
+#[cfg(all(feature = "scrape", feature = "llm"))]
+impl Completion for RecordedLlm {
+    fn complete(&self, prompt: &str) -> impl Future<Output = Result<String>> + Send {
+        let response = self.responses
+            .iter()
+            .find(|(domain, _)| prompt.contains(&format!("The domain is: {domain}.")))
+            .map(|(_, response)| response.clone());
+        async move { response.ok_or_else(|| anyhow::anyhow!("No recorded response")) }
+    }
+}
+
+/// A tiny HTTP server for tests. Each connection gets one response, built
+/// by `handler` from the request path. The raw request heads are kept.
+#[cfg(any(feature = "scrape", feature = "llm"))]
+// The LLM tests only need some of it
+#[cfg_attr(not(feature = "scrape"), allow(dead_code))]
+pub struct TestServer {
+    pub addr: std::net::SocketAddr,
+    pub requests: Arc<Mutex<Vec<String>>>,
+    /// How many connections have been accepted
+    pub connections: Arc<AtomicUsize>,
+}
+
+#[cfg(any(feature = "scrape", feature = "llm"))]
+#[cfg_attr(not(feature = "scrape"), allow(dead_code))]
+impl TestServer {
+    pub async fn start(handler: impl Fn(&str) -> Vec<u8> + Send + Sync + 'static) -> Self {
+        Self::serve(handler, false).await
+    }
+
+    /// Like [`TestServer::start`], but connections are kept open for more
+    /// requests. `handler` mustn't send `Connection: close`.
+    pub async fn start_keep_alive(handler: impl Fn(&str) -> Vec<u8> + Send + Sync + 'static) -> Self {
+        Self::serve(handler, true).await
+    }
+
+    async fn serve(handler: impl Fn(&str) -> Vec<u8> + Send + Sync + 'static, keep_alive: bool) -> Self {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let connections = Arc::new(AtomicUsize::new(0));
+        let handler = Arc::new(handler);
+        let log = requests.clone();
+        let accepted = connections.clone();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                accepted.fetch_add(1, Ordering::SeqCst);
+                let handler = handler.clone();
+                let log = log.clone();
+                tokio::spawn(async move {
+                    while let Some(head) = read_head(&mut socket).await {
+                        let path = head.split_whitespace().nth(1).unwrap_or("/").to_string();
+                        log.lock().unwrap().push(head);
+                        let _ = socket.write_all(&handler(&path)).await;
+                        if !keep_alive {
+                            break;
+                        }
+                    }
+                    let _ = socket.shutdown().await;
+                });
+            }
+        });
+        Self { addr, requests, connections }
+    }
+
+    /// The server's address, for use in place of a domain name.
+    pub fn domain(&self) -> String {
+        self.addr.to_string()
+    }
+}
+
+#[cfg(any(feature = "scrape", feature = "llm"))]
+async fn read_head(socket: &mut tokio::net::TcpStream) -> Option<String> {
+    let mut head = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = socket.read(&mut buf).await.ok()?;
+        if n == 0 {
+            return None;
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+    Some(String::from_utf8_lossy(&head).to_string())
+}
+
+/// Collects log output in memory.
+#[derive(Clone, Default)]
+pub struct LogBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl LogBuffer {
+    pub fn contents(&self) -> String {
+        String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+    }
+}
+
+impl std::io::Write for LogBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A raw HTTP response.
+#[cfg(any(feature = "scrape", feature = "llm"))]
+pub fn http_response(status: u16, headers: &[(&str, &str)], body: impl AsRef<[u8]>) -> Vec<u8> {
+    let body = body.as_ref();
+    let mut response = format!("HTTP/1.1 {status} Test\r\nContent-Length: {}\r\nConnection: close\r\n", body.len());
+    for (name, value) in headers {
+        response.push_str(&format!("{name}: {value}\r\n"));
+    }
+    response.push_str("\r\n");
+    let mut response = response.into_bytes();
+    response.extend_from_slice(body);
+    response
+}
+
+/// Keeps results in memory, as one line per record.
+#[cfg(all(feature = "scrape", feature = "llm"))]
+#[derive(Default)]
+pub struct MemorySink {
+    pub records: Mutex<Vec<String>>,
+}
+
+#[cfg(all(feature = "scrape", feature = "llm"))]
+impl ResultSink for MemorySink {
+    async fn record_success(&self, domain: &Domain) {
+        self.records.lock().unwrap().push(format!("success {} {}", domain.domain, domain.category));
+    }
+
+    async fn record_failure(&self, domain: &str, reason: FailReason) {
+        self.records.lock().unwrap().push(format!("failure {domain} {reason}"));
+    }
+
+    async fn record_parked(&self, domain: &str, signal: &str) {
+        self.records.lock().unwrap().push(format!("parked {domain} {signal}"));
+    }
+
+    async fn record_unchanged(&self, domain: &str) {
+        self.records.lock().unwrap().push(format!("unchanged {domain}"));
+    }
+}
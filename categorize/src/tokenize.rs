@@ -0,0 +1,217 @@
+//! Splitting page text into candidate keywords. The default splits on
+//! whitespace, which doesn't work for languages written without spaces, so
+//! another [`Tokenizer`] can be used for pages in a given language. A
+//! [`Stemmer`] can also be set per language, so forms of a word are counted
+//! as one keyword.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use itertools::Itertools;
+
+/// Turns a piece of page text into keywords.
+pub trait Tokenizer: Send + Sync {
+    fn tokens(&self, text: &str) -> Vec<String>;
+}
+
+/// Lowercase words split at whitespace, skipping anything of 3 bytes or fewer.
+pub struct Whitespace;
+
+impl Tokenizer for Whitespace {
+    fn tokens(&self, text: &str) -> Vec<String> {
+        text.split_whitespace()
+            .filter(|s| s.len() > 3)
+            .map(|s| s.trim().to_lowercase())
+            .collect()
+    }
+}
+
+/// For Chinese and Japanese: runs of CJK characters become overlapping
+/// pairs ("图书馆" is "图书 书馆"), which catches most two-character words
+/// without needing a dictionary. Everything else is split at whitespace.
+pub struct Cjk;
+
+/// Han, kana and hangul.
+fn is_cjk(c: char) -> bool {
+    matches!(c,
+        '\u{3040}'..='\u{30ff}' // Hiragana and katakana
+        | '\u{3400}'..='\u{4dbf}' // Han extension A
+        | '\u{4e00}'..='\u{9fff}' // Han
+        | '\u{ac00}'..='\u{d7af}' // Hangul syllables
+        | '\u{f900}'..='\u{faff}' // Han compatibility
+    )
+}
+
+impl Tokenizer for Cjk {
+    fn tokens(&self, text: &str) -> Vec<String> {
+        let mut tokens = Vec::new();
+        for word in text.split_whitespace() {
+            let chars: Vec<char> = word.chars().collect();
+            for (is_run, group) in &chars.iter().chunk_by(|c| is_cjk(**c)) {
+                let group: Vec<char> = group.copied().collect();
+                if !is_run {
+                    tokens.extend(Whitespace.tokens(&group.iter().collect::<String>()));
+                } else if group.len() == 1 {
+                    tokens.push(group[0].to_string());
+                } else {
+                    tokens.extend(group.windows(2).map(|pair| pair.iter().collect::<String>()));
+                }
+            }
+        }
+        tokens
+    }
+}
+
+/// Reduces a keyword to its stem, so forms of the same word ("games",
+/// "gaming") are counted together.
+pub trait Stemmer: Send + Sync {
+    fn stem(&self, word: &str) -> String;
+}
+
+/// The first step of the Porter stemmer, for English: plurals, `-ed` and
+/// `-ing` ("games" and "gaming" are both "game"). The later steps, for
+/// suffixes like `-ational`, merge words that mean different things about as
+/// often as they help. Words that aren't all ASCII letters are left alone.
+pub struct Porter;
+
+/// Is `word[i]` a consonant? `y` is, unless it follows a consonant.
+fn is_consonant(word: &[u8], i: usize) -> bool {
+    match word[i] {
+        b'a' | b'e' | b'i' | b'o' | b'u' => false,
+        b'y' => i == 0 || !is_consonant(word, i - 1),
+        _ => true,
+    }
+}
+
+/// Porter's measure: how many vowel-then-consonant runs `word` has.
+fn measure(word: &[u8]) -> usize {
+    (1..word.len()).filter(|&i| is_consonant(word, i) && !is_consonant(word, i - 1)).count()
+}
+
+fn has_vowel(word: &[u8]) -> bool {
+    (0..word.len()).any(|i| !is_consonant(word, i))
+}
+
+/// Does `word` end consonant, vowel, consonant, the last not `w`, `x` or `y`
+/// ("hop", but not "few")?
+fn ends_cvc(word: &[u8]) -> bool {
+    let n = word.len();
+    n >= 3
+        && is_consonant(word, n - 3)
+        && !is_consonant(word, n - 2)
+        && is_consonant(word, n - 1)
+        && !matches!(word[n - 1], b'w' | b'x' | b'y')
+}
+
+impl Stemmer for Porter {
+    fn stem(&self, word: &str) -> String {
+        if word.len() <= 2 || !word.bytes().all(|b| b.is_ascii_lowercase()) {
+            return word.to_string();
+        }
+        let mut stem = word.as_bytes().to_vec();
+
+        // Plurals: "caresses" is "caress", "ponies" is "poni", "cats" is "cat"
+        if stem.ends_with(b"sses") || stem.ends_with(b"ies") {
+            stem.truncate(stem.len() - 2);
+        } else if stem.ends_with(b"s") && !stem.ends_with(b"ss") {
+            stem.pop();
+        }
+
+        // "agreed" is "agree"; "hopping" is "hop", but "hoping" is "hope"
+        if stem.ends_with(b"eed") {
+            if measure(&stem[..stem.len() - 3]) > 0 {
+                stem.pop();
+            }
+        } else if let Some(suffix) = [&b"ed"[..], b"ing"]
+            .into_iter()
+            .find(|suffix| stem.ends_with(suffix) && has_vowel(&stem[..stem.len() - suffix.len()]))
+        {
+            stem.truncate(stem.len() - suffix.len());
+            let n = stem.len();
+            if stem.ends_with(b"at") || stem.ends_with(b"bl") || stem.ends_with(b"iz") {
+                stem.push(b'e');
+            } else if n >= 2 && stem[n - 1] == stem[n - 2] && is_consonant(&stem, n - 1) && !matches!(stem[n - 1], b'l' | b's' | b'z') {
+                stem.pop();
+            } else if measure(&stem) == 1 && ends_cvc(&stem) {
+                stem.push(b'e');
+            }
+        }
+
+        // "happy" is "happi", to match "happiness" and "happies"
+        if stem.ends_with(b"y") && has_vowel(&stem[..stem.len() - 1]) {
+            *stem.last_mut().unwrap() = b'i';
+        }
+        String::from_utf8(stem).unwrap()
+    }
+}
+
+/// Which tokenizer (and stemmer, if any) to use for a page, by its declared
+/// language. Clones share them.
+#[derive(Clone)]
+pub struct Tokenizers {
+    default: Arc<dyn Tokenizer>,
+    by_language: HashMap<String, Arc<dyn Tokenizer>>,
+    stemmers: HashMap<String, Arc<dyn Stemmer>>,
+}
+
+impl Default for Tokenizers {
+    fn default() -> Self {
+        Self { default: Arc::new(Whitespace), by_language: HashMap::new(), stemmers: HashMap::new() }
+    }
+}
+
+impl Tokenizers {
+    /// Use `tokenizer` for pages in `language` (e.g. `zh`).
+    pub fn insert(&mut self, language: &str, tokenizer: impl Tokenizer + 'static) {
+        self.by_language.insert(language.to_lowercase(), Arc::new(tokenizer));
+    }
+
+    /// The tokenizer for a page in `language`, or the default.
+    pub fn for_language(&self, language: Option<&str>) -> &dyn Tokenizer {
+        language
+            .and_then(|language| self.by_language.get(&language.to_lowercase()))
+            .unwrap_or(&self.default)
+            .as_ref()
+    }
+
+    /// Stem the keywords of pages in `language` with `stemmer`.
+    pub fn insert_stemmer(&mut self, language: &str, stemmer: impl Stemmer + 'static) {
+        self.stemmers.insert(language.to_lowercase(), Arc::new(stemmer));
+    }
+
+    /// The stemmer for a page in `language`. Without one, keywords aren't stemmed.
+    pub fn stemmer_for(&self, language: Option<&str>) -> Option<&dyn Stemmer> {
+        Some(self.stemmers.get(&language?.to_lowercase())?.as_ref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cjk_text_is_split() {
+        let text = "北京大学图书馆";
+        // Without spaces, it's all one "word"
+        assert_eq!(Whitespace.tokens(text).len(), 1);
+        let tokens = Cjk.tokens(text);
+        assert_eq!(tokens, vec!["北京", "京大", "大学", "学图", "图书", "书馆"]);
+
+        // Latin words in among it are still split as usual
+        assert_eq!(Cjk.tokens("Rust编程 Guide"), vec!["rust", "编程", "guide"]);
+
+        let mut tokenizers = Tokenizers::default();
+        tokenizers.insert("zh", Cjk);
+        assert_eq!(tokenizers.for_language(Some("ZH")).tokens(text).len(), 6);
+        assert_eq!(tokenizers.for_language(Some("en")).tokens(text).len(), 1);
+        assert_eq!(tokenizers.for_language(None).tokens(text).len(), 1);
+    }
+
+    #[test]
+    fn test_porter_stems_word_forms_together() {
+        let stems = |words: &[&str]| words.iter().map(|w| Porter.stem(w)).collect::<Vec<_>>();
+        assert_eq!(stems(&["game", "games", "gaming", "gamed"]), vec!["game"; 4]);
+        assert_eq!(stems(&["hopping", "hoping", "caresses", "ponies", "agreed", "happy"]), vec!["hop", "hope", "caress", "poni", "agree", "happi"]);
+        // Short words, and anything that isn't plain ASCII, are left alone
+        assert_eq!(stems(&["is", "cafés", "mp3s"]), vec!["is", "cafés", "mp3s"]);
+    }
+}
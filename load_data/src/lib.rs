@@ -1,6 +1,16 @@
 //! Reads the ASN data from an IPInfo CSV file, and returns a de-duplicated
-//! list of domains.
+//! list of domains (and the organization behind each one).
+//!
+//! This crate has nothing to do with scraping or the LLM, and is kept that
+//! way: it only needs `csv`, `serde`, `anyhow` and `itertools`, so it can be
+//! used on its own (`cargo build -p load_data`) without pulling in tokio,
+//! reqwest or scraper. CI checks its dependency tree stays that way.
 
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
 use serde::Deserialize;
 use anyhow::Result;
 use itertools::Itertools;
@@ -15,16 +25,23 @@ struct AsnRow {
     domain: String,
 }
 
-/// Load the ASN data from a CSV file, and return a list of domains.
-pub fn load_asn_domains() -> Result<Vec<String>> {
-    let data = include_str!("../../data/asn.csv");
-    let mut reader = csv::Reader::from_reader(data.as_bytes());
-    let rows: Vec<_> = reader
-        .deserialize::<AsnRow>() // Deserialize - returns a result
-        .into_iter() // Consume the iterator
+/// Every domain in the CSV, normalized, in file order (so with duplicates).
+fn asn_domains(data: impl std::io::Read) -> impl Iterator<Item = String> {
+    domains_from(csv::Reader::from_reader(data))
+}
+
+fn domains_from<R: std::io::Read>(reader: csv::Reader<R>) -> impl Iterator<Item = String> {
+    reader
+        .into_deserialize::<AsnRow>() // Deserialize - returns a result
         .flatten()// Keep only Ok records
         .map(|r| r.domain.to_lowercase().trim().to_string()) // Extract just the domain
         .filter(|d| !d.is_empty()) // Remove empty domains
+}
+
+/// Load the ASN data from a CSV file, and return a list of domains.
+pub fn load_asn_domains() -> Result<Vec<String>> {
+    let data = include_str!("../../data/asn.csv");
+    let rows: Vec<_> = asn_domains(data.as_bytes())
         .sorted() // Sort the results
         .dedup() // Remove duplicates
         .collect(); // Move the results into a vector
@@ -34,12 +51,328 @@ pub fn load_asn_domains() -> Result<Vec<String>> {
     Ok(rows)
 }
 
+/// A file left out of a merge, and why.
+pub type Skipped = (PathBuf, anyhow::Error);
+
+/// Load and merge several ASN CSVs (e.g. one per region), de-duplicated and
+/// sorted across all of them. A file that can't be read, or doesn't have a
+/// `domain` column, is skipped (and returned, to be reported), so one bad file
+/// doesn't stop the rest. It's an error if every file is skipped.
+pub fn load_asn_domains_from_paths(paths: &[PathBuf]) -> Result<(Vec<String>, Vec<Skipped>)> {
+    let mut domains = Vec::new();
+    let mut skipped = Vec::new();
+    for path in paths {
+        match asn_domains_from_path(path) {
+            Ok(found) => domains.extend(found),
+            Err(e) => skipped.push((path.clone(), e)),
+        }
+    }
+    ensure_any_loaded(paths, &skipped)?;
+    Ok((domains.into_iter().sorted().dedup().collect(), skipped))
+}
+
+fn ensure_any_loaded(paths: &[PathBuf], skipped: &[Skipped]) -> Result<()> {
+    if !paths.is_empty() && skipped.len() == paths.len() {
+        let reasons = skipped.iter().map(|(path, e)| format!("{}: {e}", path.display())).join("; ");
+        anyhow::bail!("None of the ASN files could be loaded ({reasons})");
+    }
+    Ok(())
+}
+
+fn asn_domains_from_path(path: &Path) -> Result<Vec<String>> {
+    Ok(stream_asn_domains(path)?.collect())
+}
+
+/// The domains in an ASN CSV, read a row at a time.
+fn stream_asn_domains(path: &Path) -> Result<impl Iterator<Item = String>> {
+    let mut reader = csv::Reader::from_reader(BufReader::new(File::open(path)?));
+    anyhow::ensure!(reader.headers()?.iter().any(|h| h == "domain"), "it has no domain column");
+    Ok(domains_from(reader))
+}
+
+/// Like [`load_asn_domains_from_paths`], but for inputs with more rows than
+/// fit in memory: the rows are de-duplicated with [`external_dedup`] (in runs
+/// of `run_size`, spilled to `spill_dir`), so only the unique domains are
+/// ever held.
+pub fn load_asn_domains_external(paths: &[PathBuf], run_size: usize, spill_dir: &Path) -> Result<(Vec<String>, Vec<Skipped>)> {
+    let mut rows: Vec<Box<dyn Iterator<Item = String>>> = Vec::new();
+    let mut skipped = Vec::new();
+    for path in paths {
+        match stream_asn_domains(path) {
+            Ok(found) => rows.push(Box::new(found)),
+            Err(e) => skipped.push((path.clone(), e)),
+        }
+    }
+    ensure_any_loaded(paths, &skipped)?;
+    std::fs::create_dir_all(spill_dir)?;
+    let merged = spill_dir.join("domains.txt");
+    external_dedup(rows.into_iter().flatten(), run_size, spill_dir, BufWriter::new(File::create(&merged)?))?;
+    let domains = BufReader::new(File::open(&merged)?).lines().collect::<std::io::Result<Vec<_>>>()?;
+    std::fs::remove_file(merged)?;
+    Ok((domains, skipped))
+}
+
+/// Read a list of domains, one per line (e.g. piped in), normalized and
+/// de-duplicated the same way as the ASN domains. Blank lines are skipped.
+pub fn read_domain_list(reader: impl BufRead) -> Result<Vec<String>> {
+    let mut domains = Vec::new();
+    for line in reader.lines() {
+        let domain = line?.trim().to_lowercase();
+        if !domain.is_empty() {
+            domains.push(domain);
+        }
+    }
+    Ok(domains.into_iter().sorted().dedup().collect())
+}
+
+/// Split off a last line that has no newline, if there is one. Results files
+/// are written a row at a time with a newline after each, so that's a row a
+/// crash cut short. Returns the complete lines and the incomplete one.
+pub fn split_incomplete_line(text: &str) -> (&str, Option<&str>) {
+    if text.is_empty() || text.ends_with('\n') {
+        return (text, None);
+    }
+    let end = text.rfind('\n').map_or(0, |i| i + 1);
+    (&text[..end], Some(&text[end..]))
+}
+
+/// The organization (the ASN's name) behind each domain, e.g. `cloudflare.com`
+/// is "Cloudflare, Inc.". A domain with several ranges keeps the first name.
+fn asn_names(data: impl std::io::Read) -> HashMap<String, String> {
+    let mut names = HashMap::new();
+    for row in csv::Reader::from_reader(data).into_deserialize::<AsnRow>().flatten() {
+        let domain = row.domain.to_lowercase().trim().to_string();
+        if !domain.is_empty() {
+            names.entry(domain).or_insert(row.name);
+        }
+    }
+    names
+}
+
+/// Load the organization name for every domain in the ASN data.
+pub fn load_asn_names() -> Result<HashMap<String, String>> {
+    let data = include_str!("../../data/asn.csv");
+    Ok(asn_names(data.as_bytes()))
+}
+
+/// An ASN, the organization it belongs to, and its domains.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Asn {
+    pub asn: String,
+    pub name: String,
+    /// Normalized, sorted and without duplicates
+    pub domains: Vec<String>,
+}
+
+/// The domains in the CSV grouped by ASN, in ASN order. The name is the first
+/// one given for the ASN.
+fn asn_groups(data: impl std::io::Read) -> Vec<Asn> {
+    let mut groups: HashMap<String, Asn> = HashMap::new();
+    for row in csv::Reader::from_reader(data).into_deserialize::<AsnRow>().flatten() {
+        let domain = row.domain.to_lowercase().trim().to_string();
+        if domain.is_empty() {
+            continue;
+        }
+        let group = groups.entry(row.asn.clone()).or_insert_with(|| Asn { asn: row.asn, name: row.name, domains: Vec::new() });
+        group.domains.push(domain);
+    }
+    groups
+        .into_values()
+        .map(|mut group| {
+            group.domains = group.domains.into_iter().sorted().dedup().collect();
+            group
+        })
+        .sorted_by(|a, b| a.asn.cmp(&b.asn))
+        .collect()
+}
+
+/// Load the ASN data grouped by ASN, for categorizing whole organizations.
+pub fn load_asn_groups() -> Result<Vec<Asn>> {
+    let data = include_str!("../../data/asn.csv");
+    Ok(asn_groups(data.as_bytes()))
+}
+
+/// The top-level domain: the part after the last dot.
+fn tld(domain: &str) -> &str {
+    domain.rsplit('.').next().unwrap_or(domain)
+}
+
+/// Keep at most `cap` domains from each TLD, so that one (usually `.com`)
+/// doesn't crowd out the rest. The first ones in `domains` are kept, in order.
+pub fn cap_per_tld(domains: Vec<String>, cap: usize) -> Vec<String> {
+    let mut seen: HashMap<String, usize> = HashMap::new();
+    domains
+        .into_iter()
+        .filter(|domain| {
+            let count = seen.entry(tld(domain).to_string()).or_default();
+            *count += 1;
+            *count <= cap
+        })
+        .collect()
+}
+
+/// For datasets too big to sort in memory: read the ASN CSV at `csv_path`, and
+/// write its de-duplicated domains to `out`, one per line. See [`external_dedup`].
+pub fn write_asn_domains_external(csv_path: &Path, run_size: usize, spill_dir: &Path, out: impl Write) -> Result<usize> {
+    external_dedup(asn_domains(BufReader::new(File::open(csv_path)?)), run_size, spill_dir, out)
+}
+
+/// Sort and de-duplicate `items` without holding them all in memory. Sorted
+/// runs of up to `run_size` items are spilled to files in `spill_dir`, then
+/// merged into `out`, one item per line. Returns how many were written.
+///
+/// The output is the same as `.sorted().dedup()`. Items can't contain newlines.
+pub fn external_dedup(items: impl IntoIterator<Item = String>, run_size: usize, spill_dir: &Path, mut out: impl Write) -> Result<usize> {
+    std::fs::create_dir_all(spill_dir)?;
+    let mut runs = Vec::new();
+    for chunk in items.into_iter().chunks(run_size.max(1)).into_iter() {
+        let path = spill_dir.join(format!("run-{}.txt", runs.len()));
+        let mut file = BufWriter::new(File::create(&path)?);
+        for item in chunk.sorted().dedup() {
+            writeln!(file, "{item}")?;
+        }
+        file.flush()?;
+        runs.push(path);
+    }
+
+    let written = merge_runs(&runs, &mut out);
+    for run in runs.iter() {
+        let _ = std::fs::remove_file(run);
+    }
+    out.flush()?;
+    written
+}
+
+/// Merge sorted run files into `out`, dropping duplicates between runs.
+fn merge_runs(runs: &[PathBuf], out: &mut impl Write) -> Result<usize> {
+    let mut readers = runs
+        .iter()
+        .map(|path| Ok(BufReader::new(File::open(path)?).lines()))
+        .collect::<Result<Vec<_>>>()?;
+
+    // The smallest line from each run, and which run it came from
+    let mut heap = BinaryHeap::new();
+    for (run, reader) in readers.iter_mut().enumerate() {
+        if let Some(line) = reader.next() {
+            heap.push(Reverse((line?, run)));
+        }
+    }
+
+    let mut last: Option<String> = None;
+    let mut written = 0;
+    while let Some(Reverse((line, run))) = heap.pop() {
+        if let Some(next) = readers[run].next() {
+            heap.push(Reverse((next?, run)));
+        }
+        if last.as_ref() != Some(&line) {
+            writeln!(out, "{line}")?;
+            written += 1;
+            last = Some(line);
+        }
+    }
+    Ok(written)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_domain_list_is_normalized() {
+        let list = "News.Example\n\n  games.example  \nnews.example\n";
+        assert_eq!(read_domain_list(list.as_bytes()).unwrap(), vec!["games.example", "news.example"]);
+    }
+
+    #[test]
+    fn test_incomplete_last_line_is_split_off() {
+        assert_eq!(split_incomplete_line("a\nb\n"), ("a\nb\n", None));
+        assert_eq!(split_incomplete_line("a\nb"), ("a\n", Some("b")));
+        assert_eq!(split_incomplete_line("a"), ("", Some("a")));
+        assert_eq!(split_incomplete_line(""), ("", None));
+    }
+
+    #[test]
+    fn test_csvs_are_merged() {
+        let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures");
+        let paths = ["asn-east.csv", "asn-west.csv", "not-asn.csv", "missing.csv"].map(|name| fixtures.join(name));
+        let (domains, skipped) = load_asn_domains_from_paths(&paths).unwrap();
+        assert_eq!(domains, vec!["cloudflare.com", "google.com", "gtelecom.com.au", "one.one.one.one", "quad9.net"]);
+        let skipped: Vec<_> = skipped.iter().map(|(path, _)| path.clone()).collect();
+        assert_eq!(skipped, vec![fixtures.join("not-asn.csv"), fixtures.join("missing.csv")]);
+    }
+
+    #[test]
+    fn test_no_loadable_csv_is_an_error() {
+        let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures");
+        let paths = ["not-asn.csv", "missing.csv"].map(|name| fixtures.join(name));
+        let e = load_asn_domains_from_paths(&paths).unwrap_err().to_string();
+        assert!(e.contains("not-asn.csv") && e.contains("missing.csv"), "{e}");
+        let spill_dir = std::env::temp_dir().join(format!("load-data-none-{}", std::process::id()));
+        assert!(load_asn_domains_external(&paths, 2, &spill_dir).is_err());
+    }
+
     #[test]
     fn test_load_asn_domains() {
         load_asn_domains().unwrap();
     }
+
+    #[test]
+    fn test_domains_are_grouped_by_asn() {
+        let csv = "start_ip,end_ip,asn,name,domain\n\
+            1.0.0.0,1.0.0.255,AS13335,\"Cloudflare, Inc.\",Cloudflare.com\n\
+            1.0.4.0,1.0.7.255,AS38803,Wirefreebroadband Pty Ltd,gtelecom.com.au\n\
+            1.1.1.0,1.1.1.255,AS13335,Cloudflare,one.one.one.one\n\
+            1.1.2.0,1.1.2.255,AS13335,Cloudflare,cloudflare.com\n";
+        let groups = asn_groups(csv.as_bytes());
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].asn, "AS13335");
+        assert_eq!(groups[0].name, "Cloudflare, Inc.");
+        assert_eq!(groups[0].domains, vec!["cloudflare.com", "one.one.one.one"]);
+        assert_eq!(groups[1].domains, vec!["gtelecom.com.au"]);
+    }
+
+    #[test]
+    fn test_asn_names() {
+        let csv = "start_ip,end_ip,asn,name,domain\n\
+            1.0.0.0,1.0.0.255,AS13335,\"Cloudflare, Inc.\",Cloudflare.com\n\
+            1.1.1.0,1.1.1.255,AS13335,Cloudflare,cloudflare.com\n";
+        let names = asn_names(csv.as_bytes());
+        assert_eq!(names["cloudflare.com"], "Cloudflare, Inc.");
+        assert_eq!(names.len(), 1);
+    }
+
+    #[test]
+    fn test_cap_per_tld() {
+        let domains = ["a.com", "b.com", "c.net", "d.com", "e.co.uk", "f.com", "g.net"];
+        let capped = cap_per_tld(domains.iter().map(|d| d.to_string()).collect(), 2);
+        assert_eq!(capped, vec!["a.com", "b.com", "c.net", "e.co.uk", "g.net"]);
+    }
+
+    #[test]
+    fn test_external_dedup_matches_in_memory() {
+        let spill_dir = std::env::temp_dir().join(format!("load-data-test-{}", std::process::id()));
+        let csv_path = concat!(env!("CARGO_MANIFEST_DIR"), "/../data/asn.csv");
+        let mut out = Vec::new();
+        let written = write_asn_domains_external(Path::new(csv_path), 10_000, &spill_dir, &mut out).unwrap();
+
+        let expected = load_asn_domains().unwrap();
+        let external: Vec<_> = String::from_utf8(out).unwrap().lines().map(str::to_string).collect();
+        assert_eq!(written, expected.len());
+        assert_eq!(external, expected);
+        std::fs::remove_dir_all(spill_dir).unwrap();
+    }
+
+    #[test]
+    fn test_external_load_matches_in_memory() {
+        let spill_dir = std::env::temp_dir().join(format!("load-data-external-{}", std::process::id()));
+        let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures");
+        let paths = ["asn-east.csv", "asn-west.csv", "not-asn.csv"].map(|name| fixtures.join(name));
+        // Runs of two, so the duplicate cloudflare.com is in different runs
+        let (external, skipped) = load_asn_domains_external(&paths, 2, &spill_dir).unwrap();
+        assert_eq!(external, load_asn_domains_from_paths(&paths).unwrap().0);
+        assert_eq!(skipped.len(), 1);
+        // Nothing is left behind
+        assert_eq!(std::fs::read_dir(&spill_dir).unwrap().count(), 0);
+        std::fs::remove_dir_all(spill_dir).unwrap();
+    }
 }